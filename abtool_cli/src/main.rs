@@ -1,11 +1,23 @@
+use std::path::PathBuf;
+use std::process::ExitCode;
 use std::time::{Duration, Instant};
 use chrono::Local;
-use clap::Parser;
-use tracing::{debug, error, Level};
+use clap::{Args, Parser, Subcommand};
+use tracing::{debug, error, info, Level};
 use tracing_subscriber::EnvFilter;
-use anyhow::Result;
 
-fn main() -> Result<()> {
+/// 执行失败(外部工具或文件操作出错).
+const EXIT_FAILURE: u8 = 1;
+/// 配置文件无法读取或解析.
+const EXIT_CONFIG: u8 = 3;
+/// 找不到外部工具.
+const EXIT_TOOL_NOT_FOUND: u8 = 4;
+/// 外部工具返回非零退出码.
+const EXIT_TOOL_FAILED: u8 = 5;
+/// keystore 无法读取或签名失败.
+const EXIT_SIGNING: u8 = 6;
+
+fn main() -> ExitCode {
     let filter = EnvFilter::from_default_env().add_directive("abtool_cli=trace".parse().unwrap())
         .add_directive("shell=trace".parse().unwrap());
     let collector = tracing_subscriber::fmt().with_max_level(Level::TRACE).with_env_filter(filter).finish();
@@ -17,57 +29,145 @@ fn main() -> Result<()> {
 
     debug!("starting from {}", formatted.as_str());
 
-    let args = Args::parse();
-    debug!("action: {}", args.action);
+    let cli = Cli::parse();
+    debug!("command: {:?}", cli.command);
 
-    let config = match args.config {
-        None => {
-            panic!("config is None");
-        }
-        Some(config) => {
-            debug!("config file: {}", config);
+    let start_time = Instant::now();
 
-            config
+    let code = match run(cli.command, formatted.as_str()) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(CliError::Config(e)) => {
+            error!("read config failed: {:#}", e);
+            ExitCode::from(EXIT_CONFIG)
+        }
+        Err(CliError::Failed(e)) => {
+            error!("execution failed: {:#}", e);
+            ExitCode::from(exit_code(&e))
         }
     };
 
-    let start_time = Instant::now();
+    let end_time = Instant::now();
+    let duration = time_diff(start_time, end_time);
+    debug!("execution completed, taking: {}ms", duration.as_millis());
+    code
+}
 
-    match args.action.as_str() {
-        "apk" => {
-            debug!("build apk");
-            let _apk_path = match shell::build_apk(config, formatted.as_str()) {
-                Ok(path) => {
-                    debug!("build success, apk path: {}", path);
-
-                }
-                Err(e) => {
-                    error!("build failed: {}", e);
-                    panic!("build failed: {}", e);
-                }
-            };
-        }
+enum CliError {
+    Config(anyhow::Error),
+    Failed(anyhow::Error),
+}
+
+impl From<anyhow::Error> for CliError {
+    fn from(e: anyhow::Error) -> Self {
+        CliError::Failed(e)
+    }
+}
 
-        _ => {
+fn run(command: Command, time: &str) -> std::result::Result<(), CliError> {
+    match command {
+        Command::Aab(args) => {
             debug!("build aab");
-            let _aab_path = match shell::build_aab(config, formatted.as_str()) {
-                Ok(path) => {
-                    debug!("build success, aab path: {}", path);
-
-                }
-                Err(e) => {
-                    error!("build failed: {}", e);
-                    panic!("build failed: {}", e);
-                }
+            let config = args.load()?;
+            let output = shell::BuildOptions::aab(time).range(args.build.range()).build(&config)?;
+            log_output(&output);
+        }
+        Command::Apk(args) => {
+            debug!("build apk");
+            let config = args.load()?;
+            let output = shell::BuildOptions::apk(time).range(args.range()).build(&config)?;
+            log_output(&output);
+        }
+        Command::Decode(args) => {
+            let config = args.config.load(false, false)?;
+            shell::decode(&config, args.force)?;
+        }
+        Command::Sign(args) => {
+            let config = args.config.load(false, false)?;
+            let path = shell::sign(&config, args.file.as_path(), args.output.as_deref())?;
+            debug!("sign success, path: {}", path.to_string_lossy());
+        }
+        Command::Install(args) => {
+            let config = args.config.load(false, false)?;
+            shell::install(&config, args.file.as_path())?;
+        }
+        Command::Launch(args) => {
+            let config = args.config.load(false, false)?;
+            shell::launch(&config, args.activity.as_deref())?;
+        }
+        Command::Verify(args) => {
+            if args.align {
+                shell::check_alignment(args.file.as_path(), args.page_size)?;
+            } else {
+                shell::verify(args.file.as_path())?;
+            }
+        }
+        Command::Check(args) => {
+            let config = args.load(false, false)?;
+            shell::validate(&config)?;
+            info!("config is valid");
+        }
+        Command::Keystore(args) => {
+            let config = args.load(false, false)?;
+            let keystore = shell::list_keystore(&config)?;
+            println!("Keystore type: {}", keystore.format);
+            for (alias, kind) in &keystore.entries {
+                println!("{}, {}", alias, kind);
+            }
+        }
+        Command::Info(args) => {
+            let config = match args.config {
+                Some(config) => Some(load_config(config.as_str())?),
+                None => None,
             };
+            shell::info(config.as_ref(), args.file.as_path())?;
+        }
+        Command::Aab2apk(args) => {
+            let config = args.config.load(false, false)?;
+            let path = shell::aab_to_apk(&config, args.file.as_path(), args.output.as_deref())?;
+            info!("apk path: {}", path.to_string_lossy());
+        }
+        Command::Extract(args) => {
+            let apks = shell::extract_apks(args.file.as_path(), args.device_spec.as_deref(), args.modules.as_slice(), args.output.as_path())?;
+            info!("extracted {} apk(s) to {}", apks.len(), args.output.to_string_lossy());
         }
     }
-    let end_time = Instant::now();
-    let duration = time_diff(start_time, end_time);
-    debug!("execution completed, taking: {}ms", duration.as_millis());
     Ok(())
 }
 
+fn log_output(output: &shell::BuildOutput) {
+    for stage in &output.stages {
+        if !stage.skipped {
+            debug!("{} took {}ms", stage.name, stage.duration.as_millis());
+        }
+    }
+    if let Some(signing) = &output.signing {
+        debug!("signed with `{}` ({}, {}), SHA-256 {}", signing.alias, signing.key_algorithm, signing.schemes.join("+"), signing.certificate_sha256);
+    }
+    if let Some(apks) = &output.apks {
+        debug!("apks path: {}", apks.to_string_lossy());
+    }
+    match &output.artifact {
+        Some(artifact) => debug!("build success, artifact: {}", artifact.to_string_lossy()),
+        None => debug!("build success"),
+    }
+}
+
+/// 按错误链中的 `shell::Error` 区分退出码.
+fn exit_code(e: &anyhow::Error) -> u8 {
+    match e.downcast_ref::<shell::Error>() {
+        Some(shell::Error::ToolNotFound { .. }) => EXIT_TOOL_NOT_FOUND,
+        Some(shell::Error::ToolFailed { .. }) => EXIT_TOOL_FAILED,
+        Some(shell::Error::SigningFailed(_)) => EXIT_SIGNING,
+        Some(shell::Error::ConfigInvalid(_)) => EXIT_CONFIG,
+        _ => EXIT_FAILURE,
+    }
+}
+
+fn load_config(path: &str) -> std::result::Result<shell::Config, CliError> {
+    debug!("config file: {}", path);
+    shell::read_config(path).map_err(CliError::Config)
+}
+
 fn time_diff(start_time: Instant, end_time: Instant) -> Duration {
     end_time.duration_since(start_time)
 }
@@ -77,11 +177,249 @@ fn time_diff(start_time: Instant, end_time: Instant) -> Duration {
 #[command(name = "abtool_cli")]
 #[command(author = "song")]
 #[command(version = "0.1.0")]
-#[command(about = "abtool_cli about")]
-struct Args {
-    #[arg(short, long, default_value = "aab")]
-    action: String,
-    #[arg(short, long, default_value = None)]
+#[command(about = "Convert apk to aab, rebuild smali projects, sign and install them")]
+#[command(after_help = "Exit codes: 0 success, 1 execution failed, 2 invalid arguments, 3 invalid config, 4 tool not found, 5 tool failed, 6 signing failed")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Convert the configured apk to a signed aab
+    Aab(AabArgs),
+    /// Build the smali project into a signed apk
+    Apk(BuildArgs),
+    /// Decode the configured apk into `apk_outdir`
+    Decode(DecodeArgs),
+    /// Sign an aab in place, or an apk into a new file
+    Sign(SignArgs),
+    /// Install an apk, apks or aab to the connected device
+    Install(FileArgs),
+    /// Launch the app on the connected device
+    Launch(LaunchArgs),
+    /// Verify the signature of an apk or aab, or only the alignment of an apk
+    Verify(VerifyArgs),
+    /// Print package information of an apk or aab
+    Info(InfoArgs),
+    /// Convert the base module of an aab into a signed universal apk without bundletool
+    Aab2apk(Aab2ApkArgs),
+    /// Extract the apks a device would receive, or the universal apk, from an apk set
+    Extract(ExtractArgs),
+    /// List the entries of the configured keystore and check its passwords
+    Keystore(ConfigArgs),
+    /// Check paths, version numbers, main_activity and keystore passwords in the config
+    Check(ConfigArgs),
+}
+
+#[derive(Args, Debug)]
+struct ConfigArgs {
+    /// Path of the config.toml file
+    #[arg(short, long)]
+    config: String,
+}
+
+impl ConfigArgs {
+    fn load(&self, no_install: bool, no_launch: bool) -> std::result::Result<shell::Config, CliError> {
+        let mut config = load_config(self.config.as_str())?;
+        if no_install {
+            config.set_install(false);
+        }
+        if no_launch {
+            config.set_launch(false);
+        }
+        Ok(config)
+    }
+}
+
+#[derive(Args, Debug)]
+struct BuildArgs {
+    #[command(flatten)]
+    config: ConfigArgs,
+    /// Do not install the result even if `install = true` in config
+    #[arg(long)]
+    no_install: bool,
+    /// Do not launch the app even if `launch = true` in config
+    #[arg(long)]
+    no_launch: bool,
+    /// Start from this step, e.g. `link_resources` or a `[[pipeline.stage]]` name
+    #[arg(long, value_name = "STEP")]
+    from: Option<String>,
+    /// Stop after this step, e.g. `sign_app_bundle`
+    #[arg(long, value_name = "STEP")]
+    to: Option<String>,
+    /// Run only this step
+    #[arg(long, value_name = "STEP", conflicts_with_all = ["from", "to"])]
+    only: Option<String>,
+    /// Ignore the build cache and run every step
+    #[arg(long)]
+    no_cache: bool,
+}
+
+impl BuildArgs {
+    fn load(&self) -> std::result::Result<shell::Config, CliError> {
+        let mut config = self.config.load(self.no_install, self.no_launch)?;
+        if self.no_cache {
+            config.set_incremental(false);
+        }
+        Ok(config)
+    }
+
+    fn range(&self) -> shell::StepRange {
+        shell::StepRange::new(self.from.clone(), self.to.clone(), self.only.clone())
+    }
+}
+
+#[derive(Args, Debug)]
+struct AabArgs {
+    #[command(flatten)]
+    build: BuildArgs,
+    /// `build-apks --mode`, e.g. `universal` for a single apk that can be sideloaded
+    #[arg(long)]
+    mode: Option<shell::ApksMode>,
+    /// Only generate the apks the connected device needs
+    #[arg(long, conflicts_with = "device_spec")]
+    connected_device: bool,
+    /// Only generate the apks matching this device spec json
+    #[arg(long, value_name = "FILE")]
+    device_spec: Option<String>,
+    /// Build apks for local testing of Play Feature Delivery
+    #[arg(long)]
+    local_testing: bool,
+    /// Device tier used for device tier targeting
+    #[arg(long, value_name = "TIER")]
+    device_tier: Option<u32>,
+}
+
+impl AabArgs {
+    fn load(&self) -> std::result::Result<shell::Config, CliError> {
+        let mut config = self.build.load()?;
+        if let Some(mode) = self.mode {
+            config.set_apks_mode(mode);
+        }
+        if self.connected_device {
+            config.set_connected_device(true);
+        }
+        if let Some(device_spec) = &self.device_spec {
+            config.set_device_spec(device_spec.as_str());
+        }
+        if self.local_testing {
+            config.set_local_testing(true);
+        }
+        if let Some(device_tier) = self.device_tier {
+            config.set_device_tier(device_tier);
+        }
+        Ok(config)
+    }
+}
+
+#[derive(Args, Debug)]
+struct DecodeArgs {
+    #[command(flatten)]
+    config: ConfigArgs,
+    /// Remove an existing `apk_outdir` before decoding
+    #[arg(short, long)]
+    force: bool,
+}
+
+#[derive(Args, Debug)]
+struct SignArgs {
+    #[command(flatten)]
+    config: ConfigArgs,
+    /// The .aab or .apk to sign
+    file: PathBuf,
+    /// Write the signed file here instead of the default location
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+struct Aab2ApkArgs {
+    #[command(flatten)]
+    config: ConfigArgs,
+    /// The .aab to convert
+    file: PathBuf,
+    /// Write the signed apk here instead of `<name>.apk` next to the aab
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+struct FileArgs {
+    #[command(flatten)]
+    config: ConfigArgs,
+    /// The .apk, .apks or .aab to install
+    file: PathBuf,
+}
+
+#[derive(Args, Debug)]
+struct LaunchArgs {
+    #[command(flatten)]
+    config: ConfigArgs,
+    /// Activity to start as `package/activity`, defaults to `main_activity`
+    #[arg(short, long)]
+    activity: Option<String>,
+}
+
+#[derive(Args, Debug)]
+struct VerifyArgs {
+    /// Only check the zip alignment of an apk, like `zipalign -c`
+    #[arg(long)]
+    align: bool,
+    /// Page size in KiB that uncompressed `.so` files must be aligned to (4 or 16)
+    #[arg(long, value_name = "KIB", default_value_t = shell::DEFAULT_PAGE_SIZE, requires = "align")]
+    page_size: u32,
+    /// The .apk or .aab to verify
+    file: PathBuf,
+}
+
+#[derive(Args, Debug)]
+struct InfoArgs {
+    /// Path of the config.toml file, required for .aab
+    #[arg(short, long)]
     config: Option<String>,
+    /// The .apk or .aab to inspect
+    file: PathBuf,
+}
+
+#[derive(Args, Debug)]
+struct ExtractArgs {
+    /// Device spec json as written by `bundletool get-device-spec`, extracts the universal apk if omitted
+    #[arg(long, value_name = "FILE")]
+    device_spec: Option<PathBuf>,
+    /// Also extract this on-demand module, can be repeated
+    #[arg(long = "module", value_name = "NAME")]
+    modules: Vec<String>,
+    /// Directory to extract the apks into
+    #[arg(short, long)]
+    output: PathBuf,
+    /// The .apks to extract from
+    file: PathBuf,
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::{anyhow, Context};
 
-}
\ No newline at end of file
+    use super::*;
+
+    #[test]
+    fn exit_codes() {
+        let tool_failed = shell::Error::ToolFailed { stage: "link".to_string(), program: "aapt2".to_string(), args: Vec::new(), status: Some(1), stderr_tail: Vec::new() };
+        let cases = [
+            (anyhow::Error::from(shell::Error::ToolNotFound { program: "java".to_string() }), EXIT_TOOL_NOT_FOUND),
+            (anyhow::Error::from(tool_failed), EXIT_TOOL_FAILED),
+            (anyhow::Error::from(shell::Error::SigningFailed("bad password".to_string())), EXIT_SIGNING),
+            (anyhow::Error::from(shell::Error::ConfigInvalid("bad config".to_string())), EXIT_CONFIG),
+            (anyhow::Error::from(shell::Error::Io(std::io::Error::other("disk full"))), EXIT_FAILURE),
+            (anyhow!("something else"), EXIT_FAILURE),
+        ];
+        for (e, code) in cases {
+            assert_eq!(exit_code(&e), code, "{:#}", e);
+        }
+
+        // 外层 context 不影响退出码
+        let e = Err::<(), _>(shell::Error::ToolNotFound { program: "adb".to_string() }).context("install apks").unwrap_err();
+        assert_eq!(exit_code(&e), EXIT_TOOL_NOT_FOUND);
+    }
+}
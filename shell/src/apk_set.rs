@@ -0,0 +1,550 @@
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+use tracing::{debug, info, warn};
+use zip::ZipArchive;
+
+use crate::protobuf::{self, Value};
+
+/// `bundletool get-device-spec` 输出的设备描述.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub(crate) struct DeviceSpec {
+    /// 按设备偏好排序, 如 `["arm64-v8a", "armeabi-v7a"]`.
+    pub(crate) supported_abis: Vec<String>,
+    /// 如 `["en-US", "zh-CN"]`.
+    pub(crate) supported_locales: Vec<String>,
+    pub(crate) screen_density: u32,
+    pub(crate) sdk_version: u32,
+}
+
+impl DeviceSpec {
+    pub(crate) fn read(path: &Path) -> Result<Self> {
+        let content = fs::read_to_string(path).with_context(|| format!("read device spec {}", path.to_string_lossy()))?;
+        serde_json::from_str(content.as_str()).with_context(|| format!("parse device spec {}", path.to_string_lossy()))
+    }
+
+    fn languages(&self) -> Vec<String> {
+        self.supported_locales.iter().map(|locale| language(locale)).collect()
+    }
+}
+
+/// 语言标签的语言部分, `zh-CN` 和 `zh_CN` 都返回 `zh`.
+fn language(locale: &str) -> String {
+    locale.split(['-', '_']).next().unwrap_or_default().to_ascii_lowercase()
+}
+
+/// `toc.pb` 中的 targeting, variant 和 apk 的 targeting 字段编号不同, 解码到同一结构.
+/// 每个维度的 `alternatives` 是同级其他 apk 的取值, 用于在多个候选中选出最适合设备的一个.
+#[derive(Debug, Default)]
+struct Targeting {
+    sdk: Dimension<i32>,
+    abi: Dimension<String>,
+    multi_abi: Dimension<Vec<String>>,
+    density: Dimension<u32>,
+    language: Dimension<String>,
+    /// texture, device tier 等不支持的维度设置了取值.
+    unsupported: bool,
+}
+
+#[derive(Debug)]
+struct Dimension<T> {
+    value: Vec<T>,
+    alternatives: Vec<T>,
+}
+
+impl<T> Default for Dimension<T> {
+    fn default() -> Self {
+        Dimension { value: Vec::new(), alternatives: Vec::new() }
+    }
+}
+
+impl<T: PartialEq> Dimension<T> {
+    /// 候选值中最适合设备的值是否为本 apk 的取值, 没有取值时总是匹配.
+    fn matches(&self, best: impl Fn(&[&T]) -> Option<usize>) -> bool {
+        if self.value.is_empty() {
+            return true;
+        }
+        let candidates: Vec<&T> = self.value.iter().chain(&self.alternatives).collect();
+        match best(&candidates) {
+            Some(i) => self.value.contains(candidates[i]),
+            None => false,
+        }
+    }
+}
+
+/// 字段编号: (sdk, abi, multi_abi, density, language, 不支持的维度).
+const VARIANT_FIELDS: (u32, u32, u32, u32, u32, &[u32]) = (1, 2, 4, 3, 0, &[5]);
+const APK_FIELDS: (u32, u32, u32, u32, u32, &[u32]) = (5, 1, 7, 4, 3, &[2, 6, 8, 9, 10]);
+
+const ABI_ALIASES: [&str; 9] = ["", "armeabi", "armeabi-v7a", "arm64-v8a", "x86", "x86_64", "mips", "mips64", "riscv64"];
+/// DensityAlias 对应的 dpi, NODPI 视为任意设备都匹配的最大值.
+const DENSITY_ALIASES: [u32; 9] = [0, u32::MAX, 120, 160, 213, 240, 320, 480, 640];
+
+impl Targeting {
+    fn decode(data: &[u8], fields: (u32, u32, u32, u32, u32, &[u32])) -> Result<Self> {
+        let (sdk, abi, multi_abi, density, language, unsupported) = fields;
+        let mut targeting = Targeting::default();
+        for field in protobuf::fields(data) {
+            let (number, value) = field?;
+            let bytes = || value.as_bytes();
+            if number == sdk {
+                decode_dimension(bytes()?, &mut targeting.sdk, |data| {
+                    // SdkVersion { Int32Value min = 1 }
+                    let mut min = 0;
+                    for field in protobuf::fields(data) {
+                        if let (1, wrapper) = field? {
+                            for field in protobuf::fields(wrapper.as_bytes()?) {
+                                if let (1, value) = field? {
+                                    min = value.as_i32()?;
+                                }
+                            }
+                        }
+                    }
+                    Ok(Some(min))
+                })?;
+            } else if number == abi {
+                decode_dimension(bytes()?, &mut targeting.abi, decode_abi)?;
+            } else if number == multi_abi {
+                decode_dimension(bytes()?, &mut targeting.multi_abi, |data| {
+                    let mut abis = Vec::new();
+                    for field in protobuf::fields(data) {
+                        if let (1, abi) = field? {
+                            abis.extend(decode_abi(abi.as_bytes()?)?);
+                        }
+                    }
+                    Ok(Some(abis))
+                })?;
+            } else if number == density {
+                decode_dimension(bytes()?, &mut targeting.density, |data| {
+                    let mut dpi = None;
+                    for field in protobuf::fields(data) {
+                        match field? {
+                            (1, alias) => dpi = DENSITY_ALIASES.get(alias.as_u64()? as usize).copied(),
+                            (2, value) => dpi = Some(value.as_u64()? as u32),
+                            _ => {}
+                        }
+                    }
+                    Ok(dpi)
+                })?;
+            } else if number == language && language != 0 {
+                decode_dimension(bytes()?, &mut targeting.language, |data| Ok(Some(language_of(data))))?;
+            } else if unsupported.contains(&number) {
+                let has_value = protobuf::fields(bytes()?).any(|field| matches!(field, Ok((1, _))));
+                targeting.unsupported |= has_value;
+            }
+        }
+        Ok(targeting)
+    }
+
+    fn matches(&self, device: &DeviceSpec) -> bool {
+        let sdk = i32::try_from(device.sdk_version).unwrap_or(i32::MAX);
+        let languages = device.languages();
+        let abi_rank = |abi: &String| device.supported_abis.iter().position(|supported| supported == abi);
+
+        !self.unsupported
+            // 不超过设备版本的最高 SDK
+            && self.sdk.matches(|candidates| {
+                candidates.iter().enumerate()
+                    .filter(|(_, min)| ***min <= sdk)
+                    .max_by_key(|(_, min)| ***min)
+                    .map(|(i, _)| i)
+            })
+            // 设备最偏好的 ABI
+            && self.abi.matches(|candidates| {
+                candidates.iter().enumerate()
+                    .filter_map(|(i, abi)| abi_rank(abi).map(|rank| (i, rank)))
+                    .min_by_key(|(_, rank)| *rank)
+                    .map(|(i, _)| i)
+            })
+            // 设备全部支持且包含 ABI 最多的组合
+            && self.multi_abi.matches(|candidates| {
+                candidates.iter().enumerate()
+                    .filter(|(_, abis)| abis.iter().all(|abi| abi_rank(abi).is_some()))
+                    .max_by_key(|(_, abis)| abis.len())
+                    .map(|(i, _)| i)
+            })
+            // 不低于设备 dpi 的最小密度, 都低于时取最大的
+            && self.density.matches(|candidates| {
+                let dpi = device.screen_density;
+                candidates.iter().enumerate().filter(|(_, d)| ***d >= dpi).min_by_key(|(_, d)| ***d)
+                    .or_else(|| candidates.iter().enumerate().max_by_key(|(_, d)| ***d))
+                    .map(|(i, _)| i)
+            })
+            && self.matches_language(languages.as_slice())
+    }
+
+    /// 语言 split 按设备语言选择; 只有 alternatives 的是其他语言的兜底 split.
+    fn matches_language(&self, languages: &[String]) -> bool {
+        if self.language.value.is_empty() {
+            return self.language.alternatives.iter().all(|other| !languages.contains(other));
+        }
+        self.language.value.iter().any(|value| languages.contains(value))
+    }
+}
+
+fn decode_dimension<T>(data: &[u8], dimension: &mut Dimension<T>, decode: impl Fn(&[u8]) -> Result<Option<T>>) -> Result<()> {
+    for field in protobuf::fields(data) {
+        let (number, value) = field?;
+        let target = match number {
+            1 => &mut dimension.value,
+            2 => &mut dimension.alternatives,
+            _ => continue,
+        };
+        let decoded = match value {
+            Value::Bytes(bytes) => decode(bytes)?,
+            _ => None,
+        };
+        target.extend(decoded);
+    }
+    Ok(())
+}
+
+fn decode_abi(data: &[u8]) -> Result<Option<String>> {
+    for field in protobuf::fields(data) {
+        if let (1, alias) = field? {
+            return Ok(ABI_ALIASES.get(alias.as_u64()? as usize).filter(|abi| !abi.is_empty()).map(|abi| abi.to_string()));
+        }
+    }
+    Ok(None)
+}
+
+/// LanguageTargeting 中的语言直接是字符串, 解码时 `decode_dimension` 传入的就是字符串内容.
+fn language_of(data: &[u8]) -> String {
+    language(String::from_utf8_lossy(data).as_ref())
+}
+
+/// `toc.pb` 中的一个 apk.
+#[derive(Debug)]
+struct ApkDescription {
+    path: String,
+    targeting: Targeting,
+    /// 是否为 standalone apk(低版本设备或 universal 模式).
+    standalone: bool,
+}
+
+#[derive(Debug)]
+struct ApkSet {
+    module: String,
+    /// 1 安装时, 2 按需, 3 快速跟进. 旧版 toc 没有该字段, 解码时按 `on_demand` 推断.
+    delivery_type: u64,
+    apks: Vec<ApkDescription>,
+}
+
+#[derive(Debug)]
+struct Variant {
+    targeting: Targeting,
+    apk_sets: Vec<ApkSet>,
+}
+
+/// 解码后的 BuildApksResult.
+#[derive(Debug, Default)]
+struct Toc {
+    variants: Vec<Variant>,
+    /// 资源包(asset slice set)的名称, 资源包不会被解压.
+    asset_packs: Vec<String>,
+}
+
+/// 解码 BuildApksResult, 只保留选择 apk 需要的字段.
+fn decode_toc(data: &[u8]) -> Result<Toc> {
+    let mut toc = Toc::default();
+    for field in protobuf::fields(data) {
+        match field? {
+            (1, variant) => toc.variants.push(decode_variant(variant.as_bytes()?)?),
+            (3, asset_slice_set) => {
+                // AssetSliceSet 与 ApkSet 的模块名字段编号相同
+                toc.asset_packs.push(decode_apk_set(asset_slice_set.as_bytes()?)?.module);
+            }
+            _ => {}
+        }
+    }
+    Ok(toc)
+}
+
+fn decode_variant(data: &[u8]) -> Result<Variant> {
+    let mut variant = Variant { targeting: Targeting::default(), apk_sets: Vec::new() };
+    for field in protobuf::fields(data) {
+        match field? {
+            (1, targeting) => variant.targeting = Targeting::decode(targeting.as_bytes()?, VARIANT_FIELDS)?,
+            (2, apk_set) => variant.apk_sets.push(decode_apk_set(apk_set.as_bytes()?)?),
+            _ => {}
+        }
+    }
+    Ok(variant)
+}
+
+fn decode_apk_set(data: &[u8]) -> Result<ApkSet> {
+    let mut set = ApkSet { module: String::new(), delivery_type: 0, apks: Vec::new() };
+    for field in protobuf::fields(data) {
+        match field? {
+            (1, metadata) => {
+                let mut on_demand = false;
+                for field in protobuf::fields(metadata.as_bytes()?) {
+                    match field? {
+                        (1, name) => set.module = name.as_str()?,
+                        // 已废弃的 on_demand_deprecated
+                        (2, value) => on_demand = value.as_u64()? != 0,
+                        (6, delivery_type) => set.delivery_type = delivery_type.as_u64()?,
+                        _ => {}
+                    }
+                }
+                if set.delivery_type == 0 {
+                    set.delivery_type = if on_demand { 2 } else { 1 };
+                }
+            }
+            (2, apk) => {
+                let mut description = ApkDescription { path: String::new(), targeting: Targeting::default(), standalone: false };
+                for field in protobuf::fields(apk.as_bytes()?) {
+                    match field? {
+                        (1, targeting) => description.targeting = Targeting::decode(targeting.as_bytes()?, APK_FIELDS)?,
+                        (2, path) => description.path = path.as_str()?,
+                        (4, _) => description.standalone = true,
+                        _ => {}
+                    }
+                }
+                set.apks.push(description);
+            }
+            _ => {}
+        }
+    }
+    Ok(set)
+}
+
+/// 从 `.apks` 中选出设备需要的 apk 并解压到 `output`, 返回解压出的文件.
+///
+/// 没有 `device` 时解压 universal 模式生成的 standalone apk. 默认只包含 base 和安装时下发的模块,
+/// `modules` 可以追加其他模块.
+pub(crate) fn extract(apks: &Path, device: Option<&DeviceSpec>, modules: &[String], output: &Path) -> Result<Vec<PathBuf>> {
+    let mut archive = ZipArchive::new(File::open(apks).with_context(|| format!("open {}", apks.to_string_lossy()))?)?;
+    let mut toc = Vec::new();
+    io::copy(&mut archive.by_name("toc.pb").map_err(|_| anyhow!("{} has no toc.pb, is it an apk set?", apks.to_string_lossy()))?, &mut toc)?;
+    let Toc { variants, asset_packs } = decode_toc(toc.as_slice())?;
+    debug!("{} variants in {}", variants.len(), apks.to_string_lossy());
+    if !asset_packs.is_empty() {
+        warn!("asset packs are not extracted: {}", asset_packs.join(", "));
+    }
+
+    let paths = match device {
+        Some(device) => select(&variants, device, modules)?,
+        None => variants.iter()
+            .flat_map(|variant| &variant.apk_sets)
+            .flat_map(|set| &set.apks)
+            .find(|apk| apk.standalone && apk.targeting.sdk.value.is_empty() && apk.targeting.abi.value.is_empty() && apk.targeting.density.value.is_empty())
+            .map(|apk| vec![apk.path.clone()])
+            .ok_or_else(|| anyhow!("{} has no universal apk, build it with `--mode universal` or pass a device spec", apks.to_string_lossy()))?,
+    };
+
+    fs::create_dir_all(output)?;
+    let mut extracted = Vec::with_capacity(paths.len());
+    for path in paths {
+        let name = Path::new(path.as_str()).file_name().ok_or_else(|| anyhow!("invalid apk path `{}` in toc.pb", path))?;
+        let target = output.join(name);
+        info!("extract {} -> {}", path, target.to_string_lossy());
+        io::copy(&mut archive.by_name(path.as_str())?, &mut File::create(target.as_path())?)?;
+        extracted.push(target);
+    }
+    Ok(extracted)
+}
+
+fn select(variants: &[Variant], device: &DeviceSpec, modules: &[String]) -> Result<Vec<String>> {
+    let variant = variants.iter()
+        .find(|variant| variant.targeting.matches(device))
+        .ok_or_else(|| anyhow!("no variant matches the device (sdk {}, abis {})", device.sdk_version, device.supported_abis.join(", ")))?;
+    let paths: Vec<String> = variant.apk_sets.iter()
+        .filter(|set| set.module == "base" || set.delivery_type == 1 || modules.contains(&set.module))
+        .flat_map(|set| &set.apks)
+        .filter(|apk| apk.targeting.matches(device))
+        .map(|apk| apk.path.clone())
+        .collect();
+    if paths.is_empty() {
+        return Err(anyhow!("no apk matches the device"));
+    }
+    Ok(paths)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use zip::write::FileOptions;
+    use zip::ZipWriter;
+
+    use super::*;
+    use crate::protobuf::tests::{message, number};
+    use crate::test_util::TempDir;
+
+    fn concat(parts: &[Vec<u8>]) -> Vec<u8> {
+        parts.concat()
+    }
+
+    /// `Targeting { value: [values], alternatives: [alternatives] }`.
+    fn dimension(values: &[Vec<u8>], alternatives: &[Vec<u8>]) -> Vec<u8> {
+        let values = values.iter().map(|value| message(1, value));
+        let alternatives = alternatives.iter().map(|value| message(2, value));
+        values.chain(alternatives).collect::<Vec<_>>().concat()
+    }
+
+    fn sdk(min: u64) -> Vec<u8> {
+        message(1, number(1, min).as_slice())
+    }
+
+    fn abi(alias: u64) -> Vec<u8> {
+        number(1, alias)
+    }
+
+    fn density(alias: u64) -> Vec<u8> {
+        number(1, alias)
+    }
+
+    fn apk(targeting: Vec<u8>, path: &str) -> Vec<u8> {
+        message(2, concat(&[message(1, targeting.as_slice()), message(2, path.as_bytes()), message(3, &[])]).as_slice())
+    }
+
+    fn apk_set(module: &str, delivery_type: u64, apks: &[Vec<u8>]) -> Vec<u8> {
+        let metadata = concat(&[message(1, module.as_bytes()), number(6, delivery_type)]);
+        let mut set = message(1, metadata.as_slice());
+        set.extend(apks.concat());
+        message(2, set.as_slice())
+    }
+
+    /// base 按 ABI, 密度和语言拆分, 另有一个按需下发的 feature 模块, 一个 universal 变体和一个资源包.
+    fn toc() -> Vec<u8> {
+        let arm64 = message(1, dimension(&[abi(3)], &[abi(2)]).as_slice());
+        let armv7 = message(1, dimension(&[abi(2)], &[abi(3)]).as_slice());
+        let xhdpi = message(4, dimension(&[density(6)], &[density(7)]).as_slice());
+        let xxhdpi = message(4, dimension(&[density(7)], &[density(6)]).as_slice());
+        let zh = message(3, dimension(&[b"zh".to_vec()], &[b"fr".to_vec()]).as_slice());
+        let fr = message(3, dimension(&[b"fr".to_vec()], &[b"zh".to_vec()]).as_slice());
+        let other = message(3, dimension(&[], &[b"fr".to_vec(), b"zh".to_vec()]).as_slice());
+        let base = apk_set("base", 0, &[
+            apk(Vec::new(), "splits/base-master.apk"),
+            apk(arm64, "splits/base-arm64_v8a.apk"),
+            apk(armv7, "splits/base-armeabi_v7a.apk"),
+            apk(xhdpi, "splits/base-xhdpi.apk"),
+            apk(xxhdpi, "splits/base-xxhdpi.apk"),
+            apk(zh, "splits/base-zh.apk"),
+            apk(fr, "splits/base-fr.apk"),
+            apk(other, "splits/base-other_lang.apk"),
+        ]);
+        let camera = apk_set("camera", 2, &[apk(Vec::new(), "splits/camera-master.apk")]);
+        let split_variant = concat(&[message(1, message(1, dimension(&[sdk(21)], &[]).as_slice()).as_slice()), base, camera]);
+
+        // standalone apk 的元数据为字段 4
+        let universal = message(2, concat(&[message(1, &[]), message(2, b"universal.apk"), message(4, &[])]).as_slice());
+        let universal_variant = apk_set("base", 0, &[universal]);
+
+        let asset_pack = message(3, message(1, message(1, b"textures").as_slice()).as_slice());
+        concat(&[message(1, split_variant.as_slice()), message(1, universal_variant.as_slice()), asset_pack])
+    }
+
+    fn device(sdk_version: u32, abis: &[&str], locales: &[&str], screen_density: u32) -> DeviceSpec {
+        DeviceSpec {
+            supported_abis: abis.iter().map(|abi| abi.to_string()).collect(),
+            supported_locales: locales.iter().map(|locale| locale.to_string()).collect(),
+            screen_density,
+            sdk_version,
+        }
+    }
+
+    #[test]
+    fn decode_build_apks_result() {
+        let toc = decode_toc(toc().as_slice()).unwrap();
+        assert_eq!(toc.variants.len(), 2);
+        assert_eq!(toc.asset_packs, vec!["textures"]);
+        let variant = &toc.variants[0];
+        assert_eq!(variant.targeting.sdk.value, vec![21]);
+        assert_eq!(variant.apk_sets.iter().map(|set| (set.module.as_str(), set.delivery_type)).collect::<Vec<_>>(), vec![("base", 1), ("camera", 2)]);
+        let base = &variant.apk_sets[0].apks;
+        assert_eq!(base.len(), 8);
+        assert!(!base[0].standalone);
+        assert_eq!(base[1].targeting.abi.value, vec!["arm64-v8a"]);
+        assert_eq!(base[1].targeting.abi.alternatives, vec!["armeabi-v7a"]);
+        assert_eq!(base[4].targeting.density.value, vec![480]);
+        assert_eq!(base[5].targeting.language.value, vec!["zh"]);
+        let universal = &toc.variants[1].apk_sets[0].apks[0];
+        assert!(universal.standalone);
+        assert_eq!(universal.path, "universal.apk");
+    }
+
+    #[test]
+    fn select_splits_for_device() {
+        let toc = decode_toc(toc().as_slice()).unwrap();
+        let phone = device(33, &["arm64-v8a", "armeabi-v7a"], &["zh-CN"], 420);
+        assert_eq!(select(&toc.variants, &phone, &[]).unwrap(), vec![
+            "splits/base-master.apk", "splits/base-arm64_v8a.apk", "splits/base-xxhdpi.apk", "splits/base-zh.apk",
+        ]);
+
+        // 只支持 v7a, 低密度, 没有对应语言时使用兜底 split, 并追加按需模块
+        let old = device(21, &["armeabi-v7a"], &["de-DE"], 160);
+        assert_eq!(select(&toc.variants, &old, &["camera".to_string()]).unwrap(), vec![
+            "splits/base-master.apk", "splits/base-armeabi_v7a.apk", "splits/base-xhdpi.apk", "splits/base-other_lang.apk",
+            "splits/camera-master.apk",
+        ]);
+    }
+
+    #[test]
+    fn select_modules_by_delivery() {
+        let legacy = |module: &str, on_demand: bool| {
+            let metadata = concat(&[message(1, module.as_bytes()), number(2, u64::from(on_demand))]);
+            message(2, concat(&[message(1, metadata.as_slice()), apk(Vec::new(), format!("splits/{}-master.apk", module).as_str())]).as_slice())
+        };
+        let master = |module: &str| vec![apk(Vec::new(), format!("splits/{}-master.apk", module).as_str())];
+        let variant = concat(&[
+            apk_set("base", 0, &master("base")),
+            apk_set("install", 1, &master("install")),
+            apk_set("ondemand", 2, &master("ondemand")),
+            apk_set("fastfollow", 3, &master("fastfollow")),
+            legacy("old_install", false),
+            legacy("old_ondemand", true),
+        ]);
+        let toc = decode_toc(message(1, variant.as_slice()).as_slice()).unwrap();
+        let delivery: Vec<_> = toc.variants[0].apk_sets.iter().map(|set| (set.module.as_str(), set.delivery_type)).collect();
+        assert_eq!(delivery, [("base", 1), ("install", 1), ("ondemand", 2), ("fastfollow", 3), ("old_install", 1), ("old_ondemand", 2)]);
+
+        let phone = device(33, &["arm64-v8a"], &["en-US"], 420);
+        assert_eq!(select(&toc.variants, &phone, &[]).unwrap(), [
+            "splits/base-master.apk", "splits/install-master.apk", "splits/old_install-master.apk",
+        ]);
+        assert_eq!(select(&toc.variants, &phone, &["old_ondemand".to_string(), "fastfollow".to_string()]).unwrap(), [
+            "splits/base-master.apk", "splits/install-master.apk", "splits/fastfollow-master.apk",
+            "splits/old_install-master.apk", "splits/old_ondemand-master.apk",
+        ]);
+    }
+
+    #[test]
+    fn no_variant_for_old_device() {
+        let toc = decode_toc(toc().as_slice()).unwrap();
+        // universal 变体没有 SDK 限制, 低版本设备会选中它
+        let legacy = device(19, &["armeabi-v7a"], &["en-US"], 240);
+        assert_eq!(select(&toc.variants, &legacy, &[]).unwrap(), vec!["universal.apk"]);
+        let error = select(&toc.variants[..1], &legacy, &[]).unwrap_err();
+        assert_eq!(error.to_string(), "no variant matches the device (sdk 19, abis armeabi-v7a)");
+    }
+
+    #[test]
+    fn extract_from_apk_set() {
+        let dir = TempDir::new("apk-set");
+        let apks = dir.join("app.apks");
+        let mut writer = ZipWriter::new(File::create(apks.as_path()).unwrap());
+        writer.start_file("toc.pb", FileOptions::default()).unwrap();
+        writer.write_all(toc().as_slice()).unwrap();
+        for name in ["splits/base-master.apk", "splits/base-arm64_v8a.apk", "splits/base-xxhdpi.apk", "splits/base-zh.apk", "universal.apk"] {
+            writer.start_file(name, FileOptions::default()).unwrap();
+            writer.write_all(name.as_bytes()).unwrap();
+        }
+        writer.finish().unwrap();
+
+        let output = dir.join("device");
+        let phone = device(33, &["arm64-v8a"], &["zh-CN"], 480);
+        let extracted = extract(apks.as_path(), Some(&phone), &[], output.as_path()).unwrap();
+        let names: Vec<String> = extracted.iter().map(|path| path.file_name().unwrap().to_string_lossy().to_string()).collect();
+        assert_eq!(names, vec!["base-master.apk", "base-arm64_v8a.apk", "base-xxhdpi.apk", "base-zh.apk"]);
+        assert_eq!(fs::read_to_string(output.join("base-zh.apk")).unwrap(), "splits/base-zh.apk");
+
+        let universal = extract(apks.as_path(), None, &[], dir.join("universal").as_path()).unwrap();
+        assert_eq!(universal, vec![dir.join("universal").join("universal.apk")]);
+    }
+}
@@ -0,0 +1,552 @@
+use std::fs::{self, File};
+use std::io::{Cursor, Write};
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+use der::{Decode, Encode};
+use sha2::{Digest, Sha256};
+use tracing::{debug, warn};
+use x509_cert::Certificate;
+use zip::write::FileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+
+use crate::config::Config;
+use crate::jar_signer::{self, JarAlgorithms};
+use crate::keystore::{self, DigestAlgorithm, KeyEntry, PrivateKey};
+use crate::zipalign;
+
+const APK_SIGNATURE_SCHEME_V2_BLOCK_ID: u32 = 0x7109_871a;
+const APK_SIGNATURE_SCHEME_V3_BLOCK_ID: u32 = 0xf053_68c0;
+const APK_SIG_BLOCK_MAGIC: &[u8; 16] = b"APK Sig Block 42";
+/// v2 签名中声明同时存在 v3 签名, 防止被降级剥离.
+const STRIPPING_PROTECTION_ATTR_ID: u32 = 0xbeef_f00d;
+/// v3 签名中的密钥轮替证明(lineage).
+const PROOF_OF_ROTATION_ATTR_ID: u32 = 0x3ba0_6f8c;
+const LINEAGE_MAGIC: u32 = 0x3eff_39d1;
+const LINEAGE_VERSION: u32 = 1;
+
+const SIGNATURE_RSA_PKCS1_V1_5_WITH_SHA256: u32 = 0x0103;
+const SIGNATURE_ECDSA_WITH_SHA256: u32 = 0x0201;
+
+/// v3 签名只在 Android 9 (API 28) 及以上生效.
+const V3_MIN_SDK: u32 = 28;
+const V3_MAX_SDK: u32 = i32::MAX as u32;
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+const EOCD_MAGIC: u32 = 0x0605_4b50;
+const EOCD_SIZE: usize = 22;
+
+/// 使用 `[sign]` 中的 keystore 对 apk 进行 v1, v2, v3 签名.
+/// 配置了 `[sign.previous]` 时 v1/v2 使用轮替前的密钥, v3 使用新密钥并附带 lineage.
+pub(crate) fn sign(config: &Config, input: &Path, output: &Path) -> Result<()> {
+    let sign = &config.sign;
+    let current = keystore::load(Path::new(sign.keystore.as_str()), sign.keystore_pass.expose(),
+                                 sign.keystore_key_alias.as_str(), sign.keystore_key_pass.expose())?;
+    let previous = match &sign.previous {
+        Some(previous) => Some(keystore::load(Path::new(previous.keystore.as_str()), previous.keystore_pass.expose(),
+                                              previous.keystore_key_alias.as_str(), previous.keystore_key_pass.expose())?),
+        None => None,
+    };
+    let lineage = match &sign.lineage {
+        Some(path) => Some(read_lineage(Path::new(path.as_str()), &current, previous.as_ref())?),
+        None => None,
+    };
+    if lineage.is_some() && previous.is_none() {
+        warn!("lineage without [sign.previous], devices before Android 9 will see the new certificate");
+    }
+    let legacy = previous.as_ref().unwrap_or(&current);
+
+    let apk = jar_sign(config, input, legacy)?;
+    let sections = ZipSections::parse(&apk)?;
+    let digest = content_digest(&apk, &sections);
+
+    let v2 = v2_block(legacy, &digest)?;
+    let v3 = v3_block(&current, &digest, lineage.as_deref())?;
+    let block = signing_block(&[(APK_SIGNATURE_SCHEME_V2_BLOCK_ID, v2), (APK_SIGNATURE_SCHEME_V3_BLOCK_ID, v3)]);
+    debug!("apk signing block {} bytes", block.len());
+
+    let mut eocd = apk[sections.eocd..].to_vec();
+    let cd_offset = u32::try_from(sections.cd + block.len())?;
+    eocd[16..20].copy_from_slice(&cd_offset.to_le_bytes());
+
+    let mut file = File::create(output)?;
+    file.write_all(&apk[..sections.cd])?;
+    file.write_all(&block)?;
+    file.write_all(&apk[sections.cd..sections.eocd])?;
+    file.write_all(&eocd)?;
+    Ok(())
+}
+
+/// v1 签名: 签名文件写在最前面, 其余条目保持对齐.
+fn jar_sign(config: &Config, input: &Path, signer: &KeyEntry) -> Result<Vec<u8>> {
+    let page = zipalign::page_bytes(config.build_apk.zipalign_page_size)?;
+    let mut archive = ZipArchive::new(File::open(input)?)?;
+    let signature = jar_signer::sign(&mut archive, signer, JarAlgorithms::SHA256, &[("X-Android-APK-Signed", "2, 3")])?;
+
+    let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+    let options = FileOptions::default().compression_method(CompressionMethod::Deflated);
+    for (name, data) in &signature.files {
+        writer.start_file(name.as_str(), options)?;
+        writer.write_all(data)?;
+    }
+    for i in 0..archive.len() {
+        if jar_signer::is_signature_entry(archive.by_index_raw(i)?.name()) {
+            continue;
+        }
+        zipalign::copy_aligned(&mut archive, &mut writer, i, page)?;
+    }
+    Ok(writer.finish()?.into_inner())
+}
+
+/// zip 中需要计算摘要的三段: 条目内容, 中央目录, 目录结束记录.
+struct ZipSections {
+    cd: usize,
+    eocd: usize,
+}
+
+impl ZipSections {
+    fn parse(apk: &[u8]) -> Result<Self> {
+        if apk.len() < EOCD_SIZE {
+            return Err(anyhow!("not a zip file"));
+        }
+        let eocd = (0..=apk.len().saturating_sub(EOCD_SIZE))
+            .rev()
+            .take(EOCD_SIZE + u16::MAX as usize)
+            .find(|&offset| {
+                read_u32(apk, offset) == EOCD_MAGIC
+                    && offset + EOCD_SIZE + read_u16(apk, offset + 20) as usize == apk.len()
+            })
+            .ok_or_else(|| anyhow!("zip end of central directory not found"))?;
+        let cd = read_u32(apk, eocd + 16) as usize;
+        if cd > eocd {
+            return Err(anyhow!("invalid central directory offset {}", cd));
+        }
+        Ok(ZipSections { cd, eocd })
+    }
+}
+
+/// 按 1 MiB 分块计算 SHA-256, 再对所有分块摘要计算顶层摘要.
+fn content_digest(apk: &[u8], sections: &ZipSections) -> Vec<u8> {
+    let parts = [&apk[..sections.cd], &apk[sections.cd..sections.eocd], &apk[sections.eocd..]];
+    let chunks: Vec<&[u8]> = parts.iter().flat_map(|part| part.chunks(CHUNK_SIZE)).collect();
+
+    let mut top = Sha256::new();
+    top.update([0x5a]);
+    top.update((chunks.len() as u32).to_le_bytes());
+    for chunk in chunks {
+        let mut hash = Sha256::new();
+        hash.update([0xa5]);
+        hash.update((chunk.len() as u32).to_le_bytes());
+        hash.update(chunk);
+        top.update(hash.finalize());
+    }
+    top.finalize().to_vec()
+}
+
+fn v2_block(signer: &KeyEntry, digest: &[u8]) -> Result<Vec<u8>> {
+    let algorithm = signature_algorithm(&signer.key);
+    let attributes = vec![attribute(STRIPPING_PROTECTION_ATTR_ID, &3u32.to_le_bytes())];
+
+    let mut signed_data = Vec::new();
+    put_prefixed(&mut signed_data, &sequence(&[digest_entry(algorithm, digest)]));
+    put_prefixed(&mut signed_data, &sequence(&signer.certificates));
+    put_prefixed(&mut signed_data, &sequence(&attributes));
+
+    let mut block = Vec::new();
+    put_prefixed(&mut block, &signed_data);
+    put_prefixed(&mut block, &sequence(&[signature_entry(algorithm, &signer.key.sign(DigestAlgorithm::Sha256, &signed_data)?)]));
+    put_prefixed(&mut block, &public_key(signer)?);
+    Ok(prefixed(&sequence(&[block])))
+}
+
+fn v3_block(signer: &KeyEntry, digest: &[u8], lineage: Option<&[u8]>) -> Result<Vec<u8>> {
+    let algorithm = signature_algorithm(&signer.key);
+    let attributes: Vec<Vec<u8>> = lineage.map(|lineage| attribute(PROOF_OF_ROTATION_ATTR_ID, lineage)).into_iter().collect();
+
+    let mut signed_data = Vec::new();
+    put_prefixed(&mut signed_data, &sequence(&[digest_entry(algorithm, digest)]));
+    put_prefixed(&mut signed_data, &sequence(&signer.certificates));
+    signed_data.extend(V3_MIN_SDK.to_le_bytes());
+    signed_data.extend(V3_MAX_SDK.to_le_bytes());
+    put_prefixed(&mut signed_data, &sequence(&attributes));
+
+    let mut block = Vec::new();
+    put_prefixed(&mut block, &signed_data);
+    block.extend(V3_MIN_SDK.to_le_bytes());
+    block.extend(V3_MAX_SDK.to_le_bytes());
+    put_prefixed(&mut block, &sequence(&[signature_entry(algorithm, &signer.key.sign(DigestAlgorithm::Sha256, &signed_data)?)]));
+    put_prefixed(&mut block, &public_key(signer)?);
+    Ok(prefixed(&sequence(&[block])))
+}
+
+/// APK Signing Block, 插入在中央目录之前.
+fn signing_block(pairs: &[(u32, Vec<u8>)]) -> Vec<u8> {
+    let mut body = Vec::new();
+    for (id, value) in pairs {
+        body.extend((4 + value.len() as u64).to_le_bytes());
+        body.extend(id.to_le_bytes());
+        body.extend(value);
+    }
+    let size = (body.len() + 8 + APK_SIG_BLOCK_MAGIC.len()) as u64;
+    let mut block = Vec::with_capacity(body.len() + 32);
+    block.extend(size.to_le_bytes());
+    block.extend(body);
+    block.extend(size.to_le_bytes());
+    block.extend(APK_SIG_BLOCK_MAGIC);
+    block
+}
+
+fn signature_algorithm(key: &PrivateKey) -> u32 {
+    match key {
+        PrivateKey::Rsa(_) => SIGNATURE_RSA_PKCS1_V1_5_WITH_SHA256,
+        PrivateKey::Ec(_) => SIGNATURE_ECDSA_WITH_SHA256,
+    }
+}
+
+fn digest_entry(algorithm: u32, digest: &[u8]) -> Vec<u8> {
+    let mut entry = algorithm.to_le_bytes().to_vec();
+    put_prefixed(&mut entry, digest);
+    entry
+}
+
+fn signature_entry(algorithm: u32, signature: &[u8]) -> Vec<u8> {
+    digest_entry(algorithm, signature)
+}
+
+fn attribute(id: u32, value: &[u8]) -> Vec<u8> {
+    let mut entry = id.to_le_bytes().to_vec();
+    entry.extend(value);
+    entry
+}
+
+/// 签名证书中的 SubjectPublicKeyInfo.
+fn public_key(signer: &KeyEntry) -> Result<Vec<u8>> {
+    let der = signer.certificates.first().ok_or_else(|| anyhow!("key `{}` has no certificate", signer.alias))?;
+    Ok(Certificate::from_der(der)?.tbs_certificate.subject_public_key_info.to_der()?)
+}
+
+/// 读取 `apksigner rotate` 生成的 lineage 文件, 返回 v3 属性中使用的编码.
+fn read_lineage(path: &Path, current: &KeyEntry, previous: Option<&KeyEntry>) -> Result<Vec<u8>> {
+    let data = fs::read(path)?;
+    if data.len() < 12 || read_u32(&data, 0) != LINEAGE_MAGIC {
+        return Err(anyhow!("{} is not a signing certificate lineage file", path.to_string_lossy()));
+    }
+    if read_u32(&data, 4) != LINEAGE_VERSION {
+        return Err(anyhow!("unsupported lineage version {}", read_u32(&data, 4)));
+    }
+    let encoded = take_prefixed(&data[8..])?.0.to_vec();
+    if encoded.len() < 4 {
+        return Err(anyhow!("lineage {} is empty", path.to_string_lossy()));
+    }
+
+    let mut certificates = Vec::new();
+    let mut rest = &encoded[4..];
+    while !rest.is_empty() {
+        let (node, tail) = take_prefixed(rest)?;
+        let (signed_data, _) = take_prefixed(node)?;
+        certificates.push(take_prefixed(signed_data)?.0.to_vec());
+        rest = tail;
+    }
+    debug!("lineage has {} certificates", certificates.len());
+    if certificates.last() != current.certificates.first() {
+        return Err(anyhow!("lineage {} does not end with the certificate of `{}`", path.to_string_lossy(), current.alias));
+    }
+    if let Some(previous) = previous {
+        if !certificates.iter().any(|cert| Some(cert) == previous.certificates.first()) {
+            return Err(anyhow!("lineage {} does not contain the certificate of `{}`", path.to_string_lossy(), previous.alias));
+        }
+    }
+    Ok(encoded)
+}
+
+fn sequence(items: &[Vec<u8>]) -> Vec<u8> {
+    let mut out = Vec::new();
+    for item in items {
+        put_prefixed(&mut out, item);
+    }
+    out
+}
+
+fn prefixed(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() + 4);
+    put_prefixed(&mut out, data);
+    out
+}
+
+fn put_prefixed(out: &mut Vec<u8>, data: &[u8]) {
+    out.extend((data.len() as u32).to_le_bytes());
+    out.extend(data);
+}
+
+fn take_prefixed(data: &[u8]) -> Result<(&[u8], &[u8])> {
+    if data.len() < 4 {
+        return Err(anyhow!("length-prefixed value is truncated"));
+    }
+    let len = read_u32(data, 0) as usize;
+    let rest = &data[4..];
+    if rest.len() < len {
+        return Err(anyhow!("length-prefixed value is truncated"));
+    }
+    Ok(rest.split_at(len))
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]])
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::*;
+    use crate::config::{Secret, Sign, SignKey};
+    use crate::keystore::tests::{pkcs12_path, testdata, verify, STORE_PASS};
+    use crate::test_util::{self, TempDir};
+
+    fn config(sign: Sign) -> Config {
+        Config { sign, ..test_util::config() }
+    }
+
+    fn pkcs12_sign() -> Sign {
+        Sign::new(pkcs12_path().to_string_lossy(), Secret::new(STORE_PASS), "abtool", Secret::new(STORE_PASS))
+    }
+
+    fn write_apk(path: &Path) {
+        let mut writer = ZipWriter::new(File::create(path).unwrap());
+        let deflated = FileOptions::default().compression_method(CompressionMethod::Deflated);
+        let stored = FileOptions::default().compression_method(CompressionMethod::Stored);
+        writer.start_file("AndroidManifest.xml", deflated).unwrap();
+        writer.write_all(b"<manifest/>").unwrap();
+        writer.start_file("META-INF/OLD.SF", deflated).unwrap();
+        writer.write_all(b"Signature-Version: 1.0\r\n").unwrap();
+        writer.start_file("classes.dex", deflated).unwrap();
+        writer.write_all(&vec![7; 3 * CHUNK_SIZE / 2]).unwrap();
+        writer.start_file("lib/arm64-v8a/libdemo.so", stored).unwrap();
+        writer.write_all(&[0x7f; 5000]).unwrap();
+        writer.finish().unwrap();
+    }
+
+    fn u64_at(data: &[u8], offset: usize) -> u64 {
+        u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+    }
+
+    /// 解析签名后的 apk: 返回 APK Signing Block 中的 (id, value), 以及去掉签名块后的原始 zip.
+    fn split_signing_block(apk: &[u8]) -> (Vec<(u32, Vec<u8>)>, Vec<u8>) {
+        let sections = ZipSections::parse(apk).unwrap();
+        assert_eq!(&apk[sections.cd - 16..sections.cd], APK_SIG_BLOCK_MAGIC);
+        let size = u64_at(apk, sections.cd - 24) as usize;
+        let start = sections.cd - size - 8;
+        assert_eq!(u64_at(apk, start), size as u64);
+
+        let mut pairs = Vec::new();
+        let mut offset = start + 8;
+        while offset < sections.cd - 24 {
+            let len = u64_at(apk, offset) as usize;
+            pairs.push((read_u32(apk, offset + 8), apk[offset + 12..offset + 8 + len].to_vec()));
+            offset += 8 + len;
+        }
+        assert_eq!(offset, sections.cd - 24);
+
+        // 还原签名前 EOCD 中的中央目录偏移
+        let mut unsigned = apk[..start].to_vec();
+        unsigned.extend(&apk[sections.cd..sections.eocd]);
+        let mut eocd = apk[sections.eocd..].to_vec();
+        eocd[16..20].copy_from_slice(&(start as u32).to_le_bytes());
+        unsigned.extend(eocd);
+        (pairs, unsigned)
+    }
+
+    /// v2/v3 signer 中的 (signed data, 摘要, 证书, 签名, 属性).
+    struct Signer {
+        signed_data: Vec<u8>,
+        digest: Vec<u8>,
+        certificates: Vec<Vec<u8>>,
+        signature: Vec<u8>,
+        attributes: Vec<Vec<u8>>,
+    }
+
+    fn items(mut data: &[u8]) -> Vec<Vec<u8>> {
+        let mut items = Vec::new();
+        while !data.is_empty() {
+            let (item, rest) = take_prefixed(data).unwrap();
+            items.push(item.to_vec());
+            data = rest;
+        }
+        items
+    }
+
+    fn parse_signer(value: &[u8], v3: bool) -> Signer {
+        let (signers, rest) = take_prefixed(value).unwrap();
+        assert!(rest.is_empty());
+        let signers = items(signers);
+        assert_eq!(signers.len(), 1);
+        let (signed_data, rest) = take_prefixed(&signers[0]).unwrap();
+        let rest = if v3 {
+            assert_eq!(read_u32(rest, 0), V3_MIN_SDK);
+            assert_eq!(read_u32(rest, 4), V3_MAX_SDK);
+            &rest[8..]
+        } else {
+            rest
+        };
+        let (signatures, _) = take_prefixed(rest).unwrap();
+
+        let (digests, data) = take_prefixed(signed_data).unwrap();
+        let (certificates, data) = take_prefixed(data).unwrap();
+        let data = if v3 { &data[8..] } else { data };
+        let (attributes, _) = take_prefixed(data).unwrap();
+        let digest = &items(digests)[0];
+        let signature = &items(signatures)[0];
+        assert_eq!(read_u32(digest, 0), read_u32(signature, 0));
+        Signer {
+            signed_data: signed_data.to_vec(),
+            digest: take_prefixed(&digest[4..]).unwrap().0.to_vec(),
+            certificates: items(certificates),
+            signature: take_prefixed(&signature[4..]).unwrap().0.to_vec(),
+            attributes: items(attributes),
+        }
+    }
+
+    #[test]
+    fn sign_with_v1_v2_v3() {
+        let dir = TempDir::new("apk-signer-sign");
+        let input = dir.join("unsigned.apk");
+        let output = dir.join("signed.apk");
+        write_apk(input.as_path());
+        sign(&config(pkcs12_sign()), input.as_path(), output.as_path()).unwrap();
+
+        let apk = fs::read(output.as_path()).unwrap();
+        let (pairs, unsigned) = split_signing_block(&apk);
+        let ids: Vec<u32> = pairs.iter().map(|(id, _)| *id).collect();
+        assert_eq!(ids, [APK_SIGNATURE_SCHEME_V2_BLOCK_ID, APK_SIGNATURE_SCHEME_V3_BLOCK_ID]);
+        let digest = content_digest(&unsigned, &ZipSections::parse(&unsigned).unwrap());
+
+        let certificate = keystore::load(pkcs12_path().as_path(), STORE_PASS, "abtool", STORE_PASS).unwrap().certificates;
+        for (id, value) in &pairs {
+            let signer = parse_signer(value, *id == APK_SIGNATURE_SCHEME_V3_BLOCK_ID);
+            assert_eq!(signer.digest, digest);
+            assert_eq!(signer.certificates, certificate);
+            verify(&signer.certificates[0], DigestAlgorithm::Sha256, &signer.signed_data, &signer.signature);
+        }
+        let v2 = parse_signer(&pairs[0].1, false);
+        assert_eq!(v2.attributes, [attribute(STRIPPING_PROTECTION_ATTR_ID, &3u32.to_le_bytes())]);
+
+        // 签名块插入后 zip 仍可读取, 旧的签名文件被替换, 条目保持对齐
+        let mut archive = ZipArchive::new(Cursor::new(&apk)).unwrap();
+        let mut signature_file = String::new();
+        archive.by_name("META-INF/ABTOOL.SF").unwrap().read_to_string(&mut signature_file).unwrap();
+        assert!(signature_file.contains("X-Android-APK-Signed: 2, 3\r\n"));
+        assert!(archive.by_name("META-INF/ABTOOL.RSA").is_ok());
+        assert!(archive.by_name("META-INF/OLD.SF").is_err());
+        assert_eq!(archive.by_name("classes.dex").unwrap().size(), 3 * CHUNK_SIZE as u64 / 2);
+        assert!(zipalign::check(output.as_path(), crate::DEFAULT_PAGE_SIZE).unwrap().is_empty());
+    }
+
+    #[test]
+    fn digest_in_1_mib_chunks_per_section() {
+        let apk: Vec<u8> = (0..CHUNK_SIZE * 5 / 2 + 100).map(|i| i as u8).collect();
+        let sections = ZipSections { cd: CHUNK_SIZE * 5 / 2, eocd: CHUNK_SIZE * 5 / 2 + 40 };
+        // 条目内容分为 1 MiB, 1 MiB, 0.5 MiB 三块, 中央目录和 EOCD 各一块, 分块不跨段
+        let bounds = [0, CHUNK_SIZE, CHUNK_SIZE * 2, sections.cd, sections.eocd, apk.len()];
+        let mut top = Sha256::new();
+        top.update([0x5a]);
+        top.update(5u32.to_le_bytes());
+        for range in bounds.windows(2) {
+            let chunk = &apk[range[0]..range[1]];
+            top.update(Sha256::new().chain_update([0xa5]).chain_update((chunk.len() as u32).to_le_bytes()).chain_update(chunk).finalize());
+        }
+        assert_eq!(content_digest(&apk, &sections), top.finalize().to_vec());
+    }
+
+    #[test]
+    fn signing_block_layout() {
+        let block = signing_block(&[(APK_SIGNATURE_SCHEME_V2_BLOCK_ID, vec![1, 2, 3])]);
+        // size(8) + pair len(8) + id(4) + value(3) + size(8) + magic(16)
+        assert_eq!(block.len(), 47);
+        assert_eq!(u64_at(&block, 0), 39);
+        assert_eq!(u64_at(&block, 8), 7);
+        assert_eq!(read_u32(&block, 16), APK_SIGNATURE_SCHEME_V2_BLOCK_ID);
+        assert_eq!(&block[20..23], [1, 2, 3]);
+        assert_eq!(u64_at(&block, 23), 39);
+        assert_eq!(&block[31..], APK_SIG_BLOCK_MAGIC);
+    }
+
+    #[test]
+    fn reject_non_zip() {
+        assert!(ZipSections::parse(b"not a zip").is_err());
+        assert!(ZipSections::parse(&[0; 64]).is_err());
+    }
+
+    /// 按 `apksigner rotate` 的格式写入 lineage 文件, 签名内容不参与解析.
+    fn write_lineage(path: &Path, certificates: &[&[u8]]) -> Vec<u8> {
+        let mut encoded = LINEAGE_VERSION.to_le_bytes().to_vec();
+        for certificate in certificates {
+            let mut signed_data = prefixed(certificate);
+            signed_data.extend(SIGNATURE_RSA_PKCS1_V1_5_WITH_SHA256.to_le_bytes());
+            let mut node = prefixed(&signed_data);
+            node.extend(0x1fu32.to_le_bytes());
+            node.extend(SIGNATURE_RSA_PKCS1_V1_5_WITH_SHA256.to_le_bytes());
+            put_prefixed(&mut node, &[0; 8]);
+            put_prefixed(&mut encoded, &node);
+        }
+        let mut file = LINEAGE_MAGIC.to_le_bytes().to_vec();
+        file.extend(LINEAGE_VERSION.to_le_bytes());
+        put_prefixed(&mut file, &encoded);
+        fs::write(path, file).unwrap();
+        encoded
+    }
+
+    #[test]
+    fn read_lineage_file() {
+        let dir = TempDir::new("apk-signer-lineage");
+        let path = dir.join("lineage");
+        let old = keystore::load(pkcs12_path().as_path(), STORE_PASS, "abtool", STORE_PASS).unwrap();
+        let new = keystore::load(testdata("abtool.jks").as_path(), STORE_PASS, "ec", "ec-key").unwrap();
+
+        let encoded = write_lineage(path.as_path(), &[&old.certificates[0], &new.certificates[0]]);
+        assert_eq!(read_lineage(path.as_path(), &new, Some(&old)).unwrap(), encoded);
+        assert_eq!(read_lineage(path.as_path(), &new, None).unwrap(), encoded);
+        let e = read_lineage(path.as_path(), &old, None).unwrap_err();
+        assert!(e.to_string().contains("does not end with the certificate of `abtool`"), "{}", e);
+
+        write_lineage(path.as_path(), &[&new.certificates[0]]);
+        let e = read_lineage(path.as_path(), &new, Some(&old)).unwrap_err();
+        assert!(e.to_string().contains("does not contain the certificate of `abtool`"), "{}", e);
+
+        fs::write(path.as_path(), [0; 16]).unwrap();
+        assert!(read_lineage(path.as_path(), &new, None).unwrap_err().to_string().contains("is not a signing certificate lineage file"));
+    }
+
+    #[test]
+    fn sign_with_rotated_key() {
+        let dir = TempDir::new("apk-signer-rotation");
+        let input = dir.join("unsigned.apk");
+        let output = dir.join("signed.apk");
+        let lineage = dir.join("lineage");
+        write_apk(input.as_path());
+        let old = keystore::load(pkcs12_path().as_path(), STORE_PASS, "abtool", STORE_PASS).unwrap();
+        let new = keystore::load(testdata("abtool.jks").as_path(), STORE_PASS, "ec", "ec-key").unwrap();
+        let encoded = write_lineage(lineage.as_path(), &[&old.certificates[0], &new.certificates[0]]);
+
+        let mut sign_config = Sign::new(testdata("abtool.jks").to_string_lossy(), Secret::new(STORE_PASS), "ec", Secret::new("ec-key"));
+        sign_config.lineage = Some(lineage.to_string_lossy().to_string());
+        sign_config.previous = Some(SignKey {
+            keystore: pkcs12_path().to_string_lossy().to_string(),
+            keystore_pass: Secret::new(STORE_PASS),
+            keystore_key_alias: "abtool".to_string(),
+            keystore_key_pass: Secret::new(STORE_PASS),
+        });
+        sign(&config(sign_config), input.as_path(), output.as_path()).unwrap();
+
+        let (pairs, _) = split_signing_block(&fs::read(output.as_path()).unwrap());
+        let v2 = parse_signer(&pairs[0].1, false);
+        let v3 = parse_signer(&pairs[1].1, true);
+        assert_eq!(v2.certificates, old.certificates);
+        assert_eq!(v3.certificates, new.certificates);
+        assert_eq!(v3.attributes, [attribute(PROOF_OF_ROTATION_ATTR_ID, &encoded)]);
+        verify(&v3.certificates[0], DigestAlgorithm::Sha256, &v3.signed_data, &v3.signature);
+    }
+}
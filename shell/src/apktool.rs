@@ -0,0 +1,301 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+
+use crate::config::Config;
+use crate::file_path::BuildLayout;
+use crate::validate;
+
+/// base 资源的 package id.
+pub(crate) const BASE_PACKAGE_ID: u32 = 0x7f;
+
+/// apktool 反编译时写入的 `apktool.yml` 中与构建相关的部分.
+#[derive(Debug, Default)]
+pub(crate) struct ApktoolInfo {
+    pub(crate) min_sdk_version: Option<u32>,
+    pub(crate) target_sdk_version: Option<u32>,
+    pub(crate) version_code: Option<u32>,
+    pub(crate) version_name: Option<String>,
+    /// 原 apk 中不压缩的文件, 可以是扩展名(如 `png`)或完整路径.
+    pub(crate) do_not_compress: Vec<String>,
+    /// apktool 无法识别的文件(保存在 `unknown/` 下)及其在原 apk 中的压缩方式, 0 为不压缩.
+    pub(crate) unknown_files: Vec<(String, u16)>,
+    /// 资源的 package id, feature 模块通常小于 `0x7f`.
+    pub(crate) package_id: Option<u32>,
+    /// 无法解析为整数的值, 如 `minSdkVersion: 'S'`, 键为 `sdkInfo.minSdkVersion` 的形式.
+    /// 对应字段为 None, 由使用方决定是否必须.
+    invalid: Vec<(String, String)>,
+}
+
+impl ApktoolInfo {
+    /// 读取 `root/apktool.yml`, 文件不存在时返回空信息.
+    pub(crate) fn read(root: &Path) -> Result<Self> {
+        let path = root.join("apktool.yml");
+        match fs::read_to_string(path.as_path()) {
+            Ok(content) => Ok(ApktoolInfo::parse(content.as_str())),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(ApktoolInfo::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// apktool.yml 只用到了 YAML 的很小一部分: 顶层键, 缩进两格的子键和 `- ` 列表.
+    fn parse(content: &str) -> Self {
+        let mut info = ApktoolInfo::default();
+        let mut section = String::new();
+        for line in content.lines() {
+            let trimmed = line.trim();
+            if trimmed.is_empty() || trimmed.starts_with('#') || trimmed.starts_with("!!") {
+                continue;
+            }
+            if let Some(item) = trimmed.strip_prefix("- ") {
+                if section == "doNotCompress" {
+                    info.do_not_compress.extend(scalar(item));
+                }
+                continue;
+            }
+            let (key, value) = match trimmed.split_once(": ") {
+                Some((key, value)) => (key, value),
+                None => (trimmed.strip_suffix(':').unwrap_or(trimmed), ""),
+            };
+            let key = scalar(key).unwrap_or_default();
+            if !line.starts_with(' ') {
+                section = key;
+                continue;
+            }
+            match (section.as_str(), key.as_str()) {
+                ("sdkInfo", "minSdkVersion") => info.min_sdk_version = info.number(section.as_str(), key.as_str(), value),
+                ("sdkInfo", "targetSdkVersion") => info.target_sdk_version = info.number(section.as_str(), key.as_str(), value),
+                ("versionInfo", "versionCode") => info.version_code = info.number(section.as_str(), key.as_str(), value),
+                ("versionInfo", "versionName") => info.version_name = scalar(value),
+                ("packageInfo", "forcedPackageId") => info.package_id = info.number(section.as_str(), key.as_str(), value),
+                ("unknownFiles", _) => {
+                    let method = info.number(section.as_str(), key.as_str(), value).and_then(|method| u16::try_from(method).ok()).unwrap_or(8);
+                    info.unknown_files.push((key, method));
+                }
+                _ => {}
+            }
+        }
+        info
+    }
+
+    /// 解析非负整数, 无法解析时记录到 `invalid` 并返回 None.
+    fn number(&mut self, section: &str, key: &str, value: &str) -> Option<u32> {
+        let value = scalar(value)?;
+        let number = value.parse::<u32>().ok();
+        if number.is_none() {
+            self.invalid.push((format!("{}.{}", section, key), value));
+        }
+        number
+    }
+
+    /// `key`(如 `sdkInfo.minSdkVersion`)无法解析时的原值.
+    pub(crate) fn invalid(&self, key: &str) -> Option<&str> {
+        self.invalid.iter().find(|(k, _)| k == key).map(|(_, value)| value.as_str())
+    }
+
+    /// 无法解析且 `[apk]` 中没有覆盖的版本号. 原 apk 有这些值, 省略会改变构建结果, 因此作为问题报告.
+    pub(crate) fn version_problems(&self, config: &Config) -> Vec<String> {
+        let versions = [
+            ("sdkInfo.minSdkVersion", "min_sdk_version", config.apk.min_sdk_version),
+            ("sdkInfo.targetSdkVersion", "target_sdk_version", config.apk.target_sdk_version),
+            ("versionInfo.versionCode", "version_code", config.apk.version_code),
+        ];
+        versions.iter()
+            .filter(|(_, _, configured)| configured.is_none())
+            .filter_map(|(key, name, _)| {
+                let value = self.invalid(key)?;
+                Some(format!("apktool.yml {} `{}` is not a non-negative integer, set {} in [apk]", key, value, name))
+            })
+            .collect()
+    }
+
+    /// 模块中的文件(如 `root/x.bin`, `assets/a.txt`)在原 apk 中是否未压缩.
+    pub(crate) fn is_uncompressed(&self, module_path: &str) -> bool {
+        let path = ["root/", "dex/", "manifest/"].iter()
+            .find_map(|prefix| module_path.strip_prefix(prefix))
+            .unwrap_or(module_path);
+        let extension = Path::new(path).extension().map(|ext| ext.to_string_lossy());
+        self.do_not_compress.iter().any(|entry| entry == path || extension.as_deref() == Some(entry.as_str()))
+            || self.unknown_files.iter().any(|(name, method)| *method == 0 && name == path)
+    }
+
+    /// 转为 BundleConfig 中的 `uncompressedGlob`: 扩展名匹配任意目录, 路径按模块内路径匹配.
+    /// `resources.arsc` 由 bundletool 生成, 不需要处理.
+    pub(crate) fn uncompressed_globs(&self) -> Vec<String> {
+        let mut globs = Vec::new();
+        for entry in &self.do_not_compress {
+            let glob = if entry.contains('/') || entry.contains('.') {
+                module_path(entry)
+            } else {
+                format!("**.{}", entry)
+            };
+            if entry != "arsc" && entry != "resources.arsc" && !globs.contains(&glob) {
+                globs.push(glob);
+            }
+        }
+        for (name, method) in &self.unknown_files {
+            let glob = format!("root/{}", name);
+            if *method == 0 && !globs.contains(&glob) {
+                globs.push(glob);
+            }
+        }
+        globs
+    }
+}
+
+/// apk 中的路径在模块中的位置, `assets/`, `lib/`, `res/` 以外的文件位于 `root/` 下.
+fn module_path(path: &str) -> String {
+    let path = path.trim_start_matches('/');
+    if ["assets/", "lib/", "res/"].iter().any(|prefix| path.starts_with(prefix)) {
+        path.to_string()
+    } else if path.ends_with(".dex") && !path.contains('/') {
+        format!("dex/{}", path)
+    } else {
+        format!("root/{}", path)
+    }
+}
+
+/// 去掉 YAML 标量两侧的引号, `null` 和空值返回 None.
+fn scalar(value: &str) -> Option<String> {
+    let value = value.trim();
+    let value = if let Some(quoted) = value.strip_prefix('\'').and_then(|v| v.strip_suffix('\'')) {
+        quoted.replace("''", "'")
+    } else if let Some(quoted) = value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+        quoted.to_string()
+    } else if value == "null" || value == "~" {
+        return None;
+    } else {
+        value.to_string()
+    };
+    (!value.is_empty()).then_some(value)
+}
+
+/// 按 `[[module]]` 的顺序为 feature 模块分配资源的 package id. `forced` 为各模块 `apktool.yml` 中的
+/// `forcedPackageId`, 没有时从 `0x7e` 开始递减, 跳过 base 和其他模块已使用的 id.
+pub(crate) fn package_ids(forced: &[Option<u32>]) -> Result<Vec<u32>> {
+    let mut used: Vec<u32> = forced.iter().flatten().copied().chain([BASE_PACKAGE_ID]).collect();
+    let mut next = BASE_PACKAGE_ID;
+    forced.iter()
+        .map(|forced| match forced {
+            Some(id) => Ok(*id),
+            None => {
+                // 0x01 为系统资源
+                next = (0x02..next).rev().find(|id| !used.contains(id)).ok_or_else(|| anyhow!("no free resource package id"))?;
+                used.push(next);
+                Ok(next)
+            }
+        })
+        .collect()
+}
+
+/// 传给 `aapt2 link` 的版本信息, `[apk]` 中的配置优先, 其次为 `apktool.yml`, 都没有时不传.
+#[derive(Debug)]
+pub(crate) struct Versions {
+    pub(crate) min_sdk_version: Option<u32>,
+    pub(crate) target_sdk_version: Option<u32>,
+    pub(crate) version_code: Option<u32>,
+    pub(crate) version_name: Option<String>,
+}
+
+impl Versions {
+    pub(crate) fn resolve(config: &Config, layout: &BuildLayout) -> Result<Self> {
+        let info = ApktoolInfo::read(layout.root.as_path())?;
+        let mut problems = info.version_problems(config);
+        let versions = Versions {
+            min_sdk_version: config.apk.min_sdk_version.or(info.min_sdk_version),
+            target_sdk_version: config.apk.target_sdk_version.or(info.target_sdk_version),
+            version_code: config.apk.version_code.or(info.version_code),
+            version_name: config.apk.version_name.clone().or(info.version_name),
+        };
+        problems.extend(validate::versions(versions.min_sdk_version, versions.target_sdk_version, versions.version_code));
+        if !problems.is_empty() {
+            return Err(anyhow!("invalid versions: {}", problems.join(", ")));
+        }
+        Ok(versions)
+    }
+
+    /// `aapt2 link` 的版本参数.
+    pub(crate) fn args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        let values = [
+            ("--min-sdk-version", self.min_sdk_version.map(|v| v.to_string())),
+            ("--target-sdk-version", self.target_sdk_version.map(|v| v.to_string())),
+            ("--version-code", self.version_code.map(|v| v.to_string())),
+            ("--version-name", self.version_name.clone()),
+        ];
+        for (flag, value) in values {
+            if let Some(value) = value {
+                args.extend([flag.to_string(), value]);
+            }
+        }
+        args
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{self, config};
+
+    const APKTOOL_YML: &str = "!!brut.androlib.meta.MetaInfo
+apkFileName: app.apk
+doNotCompress:
+- arsc
+- png
+packageInfo:
+  forcedPackageId: '0x7e'
+sdkInfo:
+  minSdkVersion: 'S'
+  targetSdkVersion: '34'
+unknownFiles:
+  lib.bin: '0'
+  odd.bin: stored
+versionInfo:
+  versionCode: 12
+  versionName: '1.0'
+";
+
+    #[test]
+    fn parse_ignores_non_numeric_values() {
+        let info = ApktoolInfo::parse(APKTOOL_YML);
+        assert_eq!(info.min_sdk_version, None);
+        assert_eq!(info.target_sdk_version, Some(34));
+        assert_eq!(info.version_code, Some(12));
+        assert_eq!(info.version_name.as_deref(), Some("1.0"));
+        assert_eq!(info.package_id, None);
+        assert_eq!(info.unknown_files, vec![("lib.bin".to_string(), 0), ("odd.bin".to_string(), 8)]);
+        assert_eq!(info.do_not_compress, vec!["arsc", "png"]);
+        assert_eq!(info.invalid("sdkInfo.minSdkVersion"), Some("S"));
+        assert_eq!(info.invalid("packageInfo.forcedPackageId"), Some("0x7e"));
+        assert_eq!(info.invalid("unknownFiles.odd.bin"), Some("stored"));
+        assert_eq!(info.invalid("sdkInfo.targetSdkVersion"), None);
+    }
+
+    #[test]
+    fn package_ids_skip_used_ids() {
+        assert_eq!(package_ids(&[None, None]).unwrap(), vec![0x7e, 0x7d]);
+        // 第二个模块的原 apk 使用了 0x7e, 第一个模块不能再按顺序使用它
+        assert_eq!(package_ids(&[None, Some(0x7e), None]).unwrap(), vec![0x7d, 0x7e, 0x7c]);
+        assert_eq!(package_ids(&[Some(0x80), None]).unwrap(), vec![0x80, 0x7e]);
+        assert!(package_ids(&[]).unwrap().is_empty());
+        let forced: Vec<Option<u32>> = (0x02..0x7f).map(Some).chain([None]).collect();
+        assert_eq!(package_ids(&forced).unwrap_err().to_string(), "no free resource package id");
+    }
+
+    #[test]
+    fn invalid_version_is_a_problem_unless_configured() {
+        let info = ApktoolInfo::parse(APKTOOL_YML);
+        let mut config = config();
+        assert_eq!(
+            info.version_problems(&config),
+            vec!["apktool.yml sdkInfo.minSdkVersion `S` is not a non-negative integer, set min_sdk_version in [apk]"],
+        );
+        config.apk.min_sdk_version = Some(31);
+        assert!(info.version_problems(&config).is_empty());
+        // 不影响版本号的值无法解析时不报告
+        let package = ApktoolInfo::parse("packageInfo:\n  forcedPackageId: x\n");
+        assert!(package.version_problems(&test_util::config()).is_empty());
+    }
+}
@@ -0,0 +1,180 @@
+use std::fs::{self, File};
+use std::io;
+use std::path::PathBuf;
+use std::process::Command;
+
+use anyhow::{anyhow, Context, Result};
+use tracing::{debug, info};
+use walkdir::WalkDir;
+use zip::ZipArchive;
+
+use crate::apktool::ApktoolInfo;
+use crate::config::AssetPack;
+use crate::file_path::{BuildContext, BuildLayout};
+use crate::{manifest, tool};
+
+/// 资源包的工作目录: `apk_outdir/.abtool/asset_packs/<name>/`.
+pub(crate) struct PackLayout {
+    /// 生成的 manifest(XML).
+    pub(crate) manifest: PathBuf,
+    /// `aapt2 link --proto-format` 的输出, 只使用其中的 manifest.
+    pub(crate) apk: PathBuf,
+    /// 模块目录, 包含 `manifest/` 和 `assets/`.
+    pub(crate) module: PathBuf,
+    /// `<name>.zip`, bundletool 以文件名作为模块名.
+    pub(crate) zip: PathBuf,
+}
+
+impl PackLayout {
+    pub(crate) fn new(layout: &BuildLayout, pack: &AssetPack) -> Self {
+        let root = layout.asset_packs.join(pack.name.as_str());
+        PackLayout {
+            manifest: root.join("AndroidManifest.xml"),
+            apk: root.join("manifest.apk"),
+            module: root.join("module"),
+            zip: layout.asset_packs.join(format!("{}.zip", pack.name)),
+        }
+    }
+}
+
+/// 生成资源包的 manifest 并转为 proto 格式, `package` 取自 base 的 manifest.
+pub(crate) fn link(ctx: &BuildContext, pack: &AssetPack) -> Result<()> {
+    let config = ctx.config;
+    debug!("link asset pack {}", pack.name);
+    let paths = PackLayout::new(&ctx.layout, pack);
+    let base_manifest = ctx.layout.root.join("AndroidManifest.xml");
+    let content = fs::read_to_string(base_manifest.as_path()).with_context(|| format!("read {}", base_manifest.to_string_lossy()))?;
+    let package = manifest::package(content.as_str()).ok_or_else(|| anyhow!("no package in {}", base_manifest.to_string_lossy()))?;
+
+    if let Some(parent) = paths.manifest.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(paths.manifest.as_path(), manifest::asset_pack(package.as_str(), pack))?;
+    if paths.apk.exists() {
+        fs::remove_file(paths.apk.as_path())?;
+    }
+    info!("exec command: aapt2 link --proto-format -o {} -I {} --manifest {}", paths.apk.to_string_lossy(), config.jar.android_jar_path, paths.manifest.to_string_lossy());
+    tool::exec("link asset pack", Command::new("aapt2")
+        .arg("link")
+        .arg("--proto-format")
+        .arg("-o")
+        .arg(paths.apk.as_path())
+        .arg("-I")
+        .arg(config.jar.android_jar_path.as_str())
+        .arg("--manifest")
+        .arg(paths.manifest.as_path()))?;
+    debug!("link asset pack {} success", pack.name);
+    Ok(())
+}
+
+/// 组装资源包模块: `manifest/AndroidManifest.xml` 以及从 `assets/` 拷贝的目录.
+pub(crate) fn copy(ctx: &BuildContext, pack: &AssetPack) -> Result<()> {
+    debug!("copy asset pack {}", pack.name);
+    let paths = PackLayout::new(&ctx.layout, pack);
+    if paths.module.exists() {
+        fs::remove_dir_all(paths.module.as_path())?;
+    }
+    let manifest_dir = paths.module.join("manifest");
+    fs::create_dir_all(manifest_dir.as_path())?;
+    let mut archive = ZipArchive::new(File::open(paths.apk.as_path()).with_context(|| format!("open {}", paths.apk.to_string_lossy()))?)?;
+    io::copy(&mut archive.by_name("AndroidManifest.xml")?, &mut File::create(manifest_dir.join("AndroidManifest.xml"))?)?;
+
+    for directory in &pack.directories {
+        let source = ctx.layout.assets.join(directory);
+        if !source.is_dir() {
+            return Err(anyhow!("asset pack {}: {} is not a directory", pack.name, source.to_string_lossy()));
+        }
+        let target = paths.module.join("assets").join(directory);
+        fs::create_dir_all(target.as_path())?;
+        crate::copy_dir(source.as_path(), target.as_path())?;
+    }
+    debug!("copy asset pack {} success", pack.name);
+    Ok(())
+}
+
+pub(crate) fn zip(ctx: &BuildContext, pack: &AssetPack) -> Result<()> {
+    debug!("zip asset pack {}", pack.name);
+    let paths = PackLayout::new(&ctx.layout, pack);
+    if paths.zip.exists() {
+        fs::remove_file(paths.zip.as_path())?;
+    }
+    // 与 base 一致, 原 apk 中未压缩的文件保持不压缩
+    let info = ApktoolInfo::read(ctx.layout.root.as_path())?;
+    let file = File::create(paths.zip.as_path())?;
+    let mut entries = WalkDir::new(paths.module.as_path()).into_iter().filter_map(|e| e.ok());
+    crate::zip_dir(&mut entries, paths.module.to_string_lossy().as_ref(), file, &|name| info.is_uncompressed(name))?;
+    debug!("zip asset pack {} success", pack.name);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use zip::write::FileOptions;
+    use zip::ZipWriter;
+
+    use super::*;
+    use crate::config::Config;
+    use crate::test_util::{config_in, TempDir};
+
+    #[test]
+    fn pack_layout() {
+        let config = config_in(std::path::Path::new("out"));
+        let paths = PackLayout::new(&BuildLayout::new(&config), &AssetPack::new("levels", vec!["levels".to_string()]));
+        assert_eq!(paths.manifest, PathBuf::from("out/.abtool/asset_packs/levels/AndroidManifest.xml"));
+        assert_eq!(paths.apk, PathBuf::from("out/.abtool/asset_packs/levels/manifest.apk"));
+        assert_eq!(paths.module, PathBuf::from("out/.abtool/asset_packs/levels/module"));
+        assert_eq!(paths.zip, PathBuf::from("out/.abtool/asset_packs/levels.zip"));
+    }
+
+    fn write(path: PathBuf, content: &str) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+    }
+
+    #[test]
+    fn pack_directories_move_out_of_base() {
+        let dir = TempDir::new("asset-pack-copy");
+        let pack = AssetPack::new("media", vec!["levels".to_string(), "video/intro".to_string()]);
+        let config = Config { asset_pack: vec![pack.clone()], ..config_in(dir.path()) };
+        let ctx = BuildContext::new(&config, "t");
+        write(dir.join("assets/levels/1.bin"), "level");
+        write(dir.join("assets/video/intro/a.mp4"), "intro");
+        write(dir.join("assets/video/b.mp4"), "b");
+        write(dir.join("assets/shared.txt"), "shared");
+        write(dir.join("base/AndroidManifest.xml"), "manifest");
+
+        // `link` 的输出, 只用到其中的 manifest
+        let paths = PackLayout::new(&ctx.layout, &pack);
+        fs::create_dir_all(paths.apk.parent().unwrap()).unwrap();
+        let mut writer = ZipWriter::new(File::create(paths.apk.as_path()).unwrap());
+        writer.start_file("AndroidManifest.xml", FileOptions::default()).unwrap();
+        writer.write_all(b"proto manifest").unwrap();
+        writer.finish().unwrap();
+
+        crate::copy_resources(&ctx, &ctx.modules[0]).unwrap();
+        copy(&ctx, &pack).unwrap();
+        zip(&ctx, &pack).unwrap();
+
+        let base = &ctx.layout.new_assets;
+        assert!(base.join("shared.txt").is_file());
+        assert!(base.join("video/b.mp4").is_file());
+        assert!(!base.join("levels").exists());
+        assert!(!base.join("video/intro").exists());
+        // 原目录不变
+        assert!(dir.join("assets/levels/1.bin").is_file());
+
+        let mut names: Vec<String> = ZipArchive::new(File::open(paths.zip.as_path()).unwrap()).unwrap()
+            .file_names().map(str::to_string).filter(|name| !name.ends_with('/')).collect();
+        names.sort();
+        assert_eq!(names, ["assets/levels/1.bin", "assets/video/intro/a.mp4", "manifest/AndroidManifest.xml"]);
+        assert_eq!(fs::read_to_string(paths.module.join("manifest/AndroidManifest.xml")).unwrap(), "proto manifest");
+
+        let missing = AssetPack::new("missing", vec!["sounds".to_string()]);
+        let paths = PackLayout::new(&ctx.layout, &missing);
+        fs::create_dir_all(paths.apk.parent().unwrap()).unwrap();
+        fs::copy(PackLayout::new(&ctx.layout, &pack).apk, paths.apk.as_path()).unwrap();
+        assert!(copy(&ctx, &missing).unwrap_err().to_string().contains("sounds is not a directory"));
+    }
+}
@@ -0,0 +1,179 @@
+use std::borrow::Cow;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use sha2::{Digest, Sha256};
+
+use crate::config::Config;
+use crate::keystore;
+use crate::pipeline::{Flow, StageTiming};
+use crate::step::{Step, StepRange};
+
+/// 以库的形式执行 aab/apk 流程.
+///
+/// ```no_run
+/// # fn main() -> anyhow::Result<()> {
+/// let config = shell::read_config("config.toml")?;
+/// let output = shell::BuildOptions::new(shell::Flow::Aab, "2024-01-01_00-00-00")
+///     .install(false)
+///     .build(&config)?;
+/// if let Some(artifact) = &output.artifact {
+///     println!("{}", artifact.display());
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct BuildOptions {
+    flow: Flow,
+    time: String,
+    range: StepRange,
+    install: Option<bool>,
+    launch: Option<bool>,
+    incremental: Option<bool>,
+}
+
+impl BuildOptions {
+    /// `time` 用于产物文件名, 如 `2024-01-01_00-00-00`.
+    pub fn new(flow: Flow, time: &str) -> Self {
+        BuildOptions { flow, time: time.to_string(), range: StepRange::all(), install: None, launch: None, incremental: None }
+    }
+
+    pub fn aab(time: &str) -> Self {
+        BuildOptions::new(Flow::Aab, time)
+    }
+
+    pub fn apk(time: &str) -> Self {
+        BuildOptions::new(Flow::Apk, time)
+    }
+
+    /// 只执行部分步骤, 默认执行全部.
+    pub fn range(mut self, range: StepRange) -> Self {
+        self.range = range;
+        self
+    }
+
+    /// 覆盖 `[config]` 中的 `install`.
+    pub fn install(mut self, install: bool) -> Self {
+        self.install = Some(install);
+        self
+    }
+
+    /// 覆盖 `[config]` 中的 `launch`.
+    pub fn launch(mut self, launch: bool) -> Self {
+        self.launch = Some(launch);
+        self
+    }
+
+    /// 覆盖 `[config]` 中的 `incremental`.
+    pub fn incremental(mut self, incremental: bool) -> Self {
+        self.incremental = Some(incremental);
+        self
+    }
+
+    pub fn build(&self, config: &Config) -> Result<BuildOutput> {
+        let start = Instant::now();
+        let config = self.apply(config)?;
+        let (ctx, stages) = crate::run_flow(config.as_ref(), self.time.as_str(), &self.range, self.flow)?;
+
+        let ran = |step: Step| stages.iter().any(|stage| !stage.skipped && Step::from_str(stage.name.as_str()).ok() == Some(step));
+        // `--to`/`--only` 在生成产物之前停止时没有产物
+        let artifact = match self.flow {
+            Flow::Aab => ctx.aab.clone(),
+            Flow::Apk => ctx.apk_sign.clone(),
+        };
+        let artifact = artifact.exists().then_some(artifact);
+        let apks = match self.flow {
+            Flow::Aab if ran(Step::BuildApks) => Some(ctx.layout.apks.clone()),
+            _ => None,
+        };
+        // aab 只有 JAR 签名, bundletool 生成的 apks 和 apk 流程使用 v1, v2, v3
+        let schemes: &[&str] = if ran(Step::BuildApks) || ran(Step::Apksigner) {
+            &["v1", "v2", "v3"]
+        } else if ran(Step::SignAppBundle) {
+            &["v1"]
+        } else {
+            &[]
+        };
+        let signing = if schemes.is_empty() {
+            None
+        } else {
+            Some(SigningInfo::new(config.as_ref(), schemes)?)
+        };
+        Ok(BuildOutput {
+            flow: self.flow,
+            time: ctx.time().to_string(),
+            artifact,
+            apks,
+            signing,
+            stages,
+            duration: start.elapsed(),
+        })
+    }
+
+    /// 解析密码并应用覆盖项, 都不需要时直接使用调用方的配置.
+    fn apply<'a>(&self, config: &'a Config) -> Result<Cow<'a, Config>> {
+        let config = config.resolved()?;
+        if self.install.is_none() && self.launch.is_none() && self.incremental.is_none() {
+            return Ok(config);
+        }
+        let mut config = config.into_owned();
+        if let Some(install) = self.install {
+            config.set_install(install);
+        }
+        if let Some(launch) = self.launch {
+            config.set_launch(launch);
+        }
+        if let Some(incremental) = self.incremental {
+            config.set_incremental(incremental);
+        }
+        Ok(Cow::Owned(config))
+    }
+}
+
+/// 一次构建的结果.
+#[derive(Debug, Clone)]
+pub struct BuildOutput {
+    pub flow: Flow,
+    /// 产物文件名中的时间戳, 从中间步骤开始时为上一次构建的时间戳.
+    pub time: String,
+    /// 本次构建的 `.aab` 或签名后的 `.apk`, 执行的步骤没有生成产物时为 None.
+    pub artifact: Option<PathBuf>,
+    /// 执行了 `build_apks` 时生成的 `.apks`.
+    pub apks: Option<PathBuf>,
+    /// 执行了签名步骤时使用的密钥.
+    pub signing: Option<SigningInfo>,
+    pub stages: Vec<StageTiming>,
+    pub duration: Duration,
+}
+
+/// 签名使用的密钥和签名方案.
+#[derive(Debug, Clone)]
+pub struct SigningInfo {
+    pub alias: String,
+    /// `RSA` 或 `ECDSA`.
+    pub key_algorithm: String,
+    /// 签名证书的 SHA-256 指纹, 格式与 keytool 一致.
+    pub certificate_sha256: String,
+    /// 本次执行的签名步骤使用的方案: 只签名 aab 时为 `v1`, 生成 apks 或签名 apk 时为 `v1`, `v2`, `v3`.
+    pub schemes: Vec<String>,
+}
+
+impl SigningInfo {
+    fn new(config: &Config, schemes: &[&str]) -> Result<Self> {
+        let sign = &config.sign;
+        let entry = keystore::load(Path::new(sign.keystore.as_str()), sign.keystore_pass.expose(),
+                                   sign.keystore_key_alias.as_str(), sign.keystore_key_pass.expose())?;
+        let certificate_sha256 = entry.certificates.first()
+            .map(|der| Sha256::digest(der).iter().map(|b| format!("{:02X}", b)).collect::<Vec<_>>().join(":"))
+            .unwrap_or_default();
+        Ok(SigningInfo {
+            alias: entry.alias,
+            key_algorithm: entry.key.algorithm_name().to_string(),
+            certificate_sha256,
+            schemes: schemes.iter().map(|scheme| scheme.to_string()).collect(),
+        })
+    }
+}
@@ -0,0 +1,231 @@
+use std::fs;
+use std::path::PathBuf;
+
+use anyhow::{anyhow, Context, Result};
+use serde_json::{json, Map, Value};
+use tracing::debug;
+
+use crate::apktool::ApktoolInfo;
+use crate::config::BundleConfig;
+use crate::file_path::BuildContext;
+
+/// 生成传给 `build-bundle --config` 的 BundleConfig(JSON).
+///
+/// 以 `bundletool_config_path` 为基础, 依次合并 `[bundle]` 和各模块 `apktool.yml` 中原 apk 未压缩的文件.
+/// 都没有内容时返回 None, 不传 `--config`.
+pub(crate) fn write(ctx: &BuildContext) -> Result<Option<PathBuf>> {
+    let config = ctx.config;
+    let mut globs = Vec::new();
+    for module in &ctx.modules {
+        for glob in ApktoolInfo::read(module.layout.root.as_path())?.uncompressed_globs() {
+            if !globs.contains(&glob) {
+                globs.push(glob);
+            }
+        }
+    }
+    if globs.is_empty() && config.config.bundletool_config_path.is_empty() && is_empty(&config.bundle) {
+        return Ok(None);
+    }
+
+    let mut bundle = if config.config.bundletool_config_path.is_empty() {
+        Value::Object(Map::new())
+    } else {
+        let path = config.config.bundletool_config_path.as_str();
+        let content = fs::read_to_string(path).with_context(|| format!("read bundletool config {}", path))?;
+        serde_json::from_str(content.as_str()).with_context(|| format!("parse bundletool config {}", path))?
+    };
+    let root = bundle.as_object_mut().ok_or_else(|| anyhow!("bundletool config must be a JSON object"))?;
+    apply(root, &config.bundle)?;
+    append(object(root, "compression")?, "uncompressedGlob", globs.into_iter().map(Value::String))?;
+
+    let path = ctx.layout.bundle_config.clone();
+    fs::create_dir_all(ctx.layout.cache_dir.as_path())?;
+    fs::write(path.as_path(), serde_json::to_string_pretty(&bundle)?)?;
+    debug!("write bundle config {}", path.to_string_lossy());
+    Ok(Some(path))
+}
+
+fn is_empty(bundle: &BundleConfig) -> bool {
+    bundle.uncompressed_glob.is_empty()
+        && bundle.uncompress_native_libraries.is_none()
+        && bundle.uncompress_dex_files.is_none()
+        && bundle.master_resources.is_empty()
+        && bundle.master_resource_ids.is_empty()
+        && bundle.split.is_empty()
+}
+
+/// 把 `[bundle]` 写入 BundleConfig, 字段名与 bundletool 的 `config.proto` 一致.
+fn apply(root: &mut Map<String, Value>, bundle: &BundleConfig) -> Result<()> {
+    append(object(root, "compression")?, "uncompressedGlob", bundle.uncompressed_glob.iter().cloned().map(Value::String))?;
+
+    let optimizations = object(root, "optimizations")?;
+    if let Some(enabled) = bundle.uncompress_native_libraries {
+        optimizations.insert("uncompressNativeLibraries".to_string(), json!({ "enabled": enabled }));
+    }
+    if let Some(enabled) = bundle.uncompress_dex_files {
+        optimizations.insert("uncompressDexFiles".to_string(), json!({ "enabled": enabled }));
+    }
+    if !bundle.split.is_empty() {
+        let dimensions: Vec<Value> = bundle.split.iter().map(|split| {
+            let mut dimension = json!({ "value": split.dimension.name(), "negate": split.negate });
+            if split.suffix_stripping {
+                let mut stripping = json!({ "enabled": true });
+                if let Some(suffix) = &split.default_suffix {
+                    stripping["defaultSuffix"] = json!(suffix);
+                }
+                dimension["suffixStripping"] = stripping;
+            }
+            dimension
+        }).collect();
+        object(optimizations, "splitsConfig")?.insert("splitDimension".to_string(), Value::Array(dimensions));
+    }
+
+    if !bundle.master_resources.is_empty() || !bundle.master_resource_ids.is_empty() {
+        let master = object(root, "masterResources")?;
+        append(master, "resourceNames", bundle.master_resources.iter().cloned().map(Value::String))?;
+        append(master, "resourceIds", bundle.master_resource_ids.iter().map(|id| json!(id)))?;
+    }
+    Ok(())
+}
+
+/// 取出 `parent[key]`, 不存在时创建空对象.
+fn object<'a>(parent: &'a mut Map<String, Value>, key: &str) -> Result<&'a mut Map<String, Value>> {
+    parent.entry(key).or_insert_with(|| json!({}))
+        .as_object_mut().ok_or_else(|| anyhow!("`{}` in bundletool config must be an object", key))
+}
+
+/// 追加到数组 `parent[key]`, 保留已有的值并去重.
+fn append(parent: &mut Map<String, Value>, key: &str, values: impl Iterator<Item = Value>) -> Result<()> {
+    let mut values = values.peekable();
+    if values.peek().is_none() {
+        return Ok(());
+    }
+    let existing = parent.entry(key).or_insert_with(|| json!([]))
+        .as_array_mut().ok_or_else(|| anyhow!("`{}` in bundletool config must be an array", key))?;
+    for value in values {
+        if !existing.contains(&value) {
+            existing.push(value);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{config_in, TempDir};
+
+    fn bundle(content: &str) -> BundleConfig {
+        toml::from_str(content).unwrap()
+    }
+
+    fn root(value: Value) -> Map<String, Value> {
+        value.as_object().unwrap().clone()
+    }
+
+    #[test]
+    fn apply_keeps_user_entries() {
+        let mut root = root(json!({
+            "compression": { "uncompressedGlob": ["res/raw/**"] },
+            "optimizations": { "uncompressDexFiles": { "enabled": true }, "standaloneConfig": { "strip64BitLibraries": true } },
+            "masterResources": { "resourceIds": [1] },
+        }));
+        let bundle = bundle(r#"
+uncompressed_glob = ["res/raw/**", "assets/video/**", "assets/video/**"]
+uncompress_native_libraries = false
+master_resources = ["string/app_name"]
+master_resource_ids = [1, 2]
+[[split]]
+dimension = "density"
+negate = true
+[[split]]
+dimension = "texture"
+suffix_stripping = true
+default_suffix = "etc1"
+"#);
+        apply(&mut root, &bundle).unwrap();
+        apply(&mut root, &bundle).unwrap();
+
+        assert_eq!(Value::Object(root), json!({
+            "compression": { "uncompressedGlob": ["res/raw/**", "assets/video/**"] },
+            "optimizations": {
+                "uncompressDexFiles": { "enabled": true },
+                "standaloneConfig": { "strip64BitLibraries": true },
+                "uncompressNativeLibraries": { "enabled": false },
+                "splitsConfig": { "splitDimension": [
+                    { "value": "SCREEN_DENSITY", "negate": true },
+                    { "value": "TEXTURE_COMPRESSION_FORMAT", "negate": false, "suffixStripping": { "enabled": true, "defaultSuffix": "etc1" } },
+                ] },
+            },
+            "masterResources": { "resourceIds": [1, 2], "resourceNames": ["string/app_name"] },
+        }));
+    }
+
+    #[test]
+    fn empty_bundle_changes_nothing_but_objects() {
+        let mut root = root(json!({ "compression": { "uncompressedGlob": ["a"] } }));
+        apply(&mut root, &BundleConfig::default()).unwrap();
+        assert_eq!(Value::Object(root), json!({ "compression": { "uncompressedGlob": ["a"] }, "optimizations": {} }));
+    }
+
+    #[test]
+    fn reject_fields_of_wrong_type() {
+        let globs = bundle(r#"uncompressed_glob = ["**.mp4"]"#);
+        let e = apply(&mut root(json!({ "compression": { "uncompressedGlob": "**.mp4" } })), &globs).unwrap_err();
+        assert_eq!(e.to_string(), "`uncompressedGlob` in bundletool config must be an array");
+        let e = apply(&mut root(json!({ "compression": ["**.mp4"] })), &globs).unwrap_err();
+        assert_eq!(e.to_string(), "`compression` in bundletool config must be an object");
+        let e = apply(&mut root(json!({ "masterResources": { "resourceIds": 1 } })), &bundle("master_resource_ids = [2]")).unwrap_err();
+        assert_eq!(e.to_string(), "`resourceIds` in bundletool config must be an array");
+
+        // 没有要追加的值时不检查已有字段
+        let mut parent = root(json!({ "uncompressedGlob": "**.mp4" }));
+        append(&mut parent, "uncompressedGlob", std::iter::empty()).unwrap();
+        assert_eq!(parent["uncompressedGlob"], json!("**.mp4"));
+    }
+
+    #[test]
+    fn write_merges_into_bundletool_config() {
+        let dir = TempDir::new("bundle-config-write");
+        let mut config = config_in(dir.path());
+        assert_eq!(write(&BuildContext::new(&config, "t")).unwrap(), None);
+
+        fs::write(dir.join("apktool.yml"), "doNotCompress:\n- arsc\n- mp4\n").unwrap();
+        let base = dir.join("bundletool.json");
+        fs::write(base.as_path(), r#"{ "compression": { "uncompressedGlob": ["**.mp4", "res/raw/**"] }, "bundletool": { "version": "1.15.2" } }"#).unwrap();
+        config.config.bundletool_config_path = base.to_string_lossy().to_string();
+        config.bundle = bundle(r#"uncompressed_glob = ["assets/video/**"]"#);
+        let ctx = BuildContext::new(&config, "t");
+        let path = write(&ctx).unwrap().unwrap();
+        assert_eq!(path, ctx.layout.bundle_config);
+        let written: Value = serde_json::from_str(fs::read_to_string(path).unwrap().as_str()).unwrap();
+        assert_eq!(written, json!({
+            "compression": { "uncompressedGlob": ["**.mp4", "res/raw/**", "assets/video/**"] },
+            "bundletool": { "version": "1.15.2" },
+            "optimizations": {},
+        }));
+        // 基础配置文件不会被修改
+        assert!(fs::read_to_string(base).unwrap().contains("\"1.15.2\" } }"));
+    }
+
+    #[test]
+    fn write_rejects_invalid_bundletool_config() {
+        let dir = TempDir::new("bundle-config-invalid");
+        let mut config = config_in(dir.path());
+        let base = dir.join("bundletool.json");
+        config.config.bundletool_config_path = base.to_string_lossy().to_string();
+
+        fs::write(base.as_path(), r#"["**.mp4"]"#).unwrap();
+        let e = write(&BuildContext::new(&config, "t")).unwrap_err();
+        assert_eq!(e.to_string(), "bundletool config must be a JSON object");
+
+        fs::write(base.as_path(), "{").unwrap();
+        let e = write(&BuildContext::new(&config, "t")).unwrap_err();
+        assert_eq!(e.to_string(), format!("parse bundletool config {}", base.to_string_lossy()));
+
+        fs::remove_file(base.as_path()).unwrap();
+        let e = write(&BuildContext::new(&config, "t")).unwrap_err();
+        assert_eq!(e.to_string(), format!("read bundletool config {}", base.to_string_lossy()));
+        assert!(!dir.join(".abtool/bundle_config.json").exists());
+    }
+}
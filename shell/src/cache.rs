@@ -0,0 +1,223 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::OnceLock;
+
+use anyhow::Result;
+use md5::{Digest, Md5};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+use walkdir::WalkDir;
+
+use crate::config::Config;
+use crate::file_path::BuildLayout;
+
+const CACHE_VERSION: u32 = 1;
+
+static AAPT2_VERSION: OnceLock<String> = OnceLock::new();
+
+/// 记录每个阶段上一次成功执行后的输入指纹, 保存在输出目录的 `.abtool/cache.toml`.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(crate) struct BuildCache {
+    #[serde(default)]
+    version: u32,
+    #[serde(default)]
+    stages: BTreeMap<String, String>,
+    #[serde(skip)]
+    path: PathBuf,
+}
+
+impl BuildCache {
+    pub(crate) fn load(layout: &BuildLayout) -> Result<Self> {
+        let path = &layout.cache;
+        let mut cache = match fs::read_to_string(path) {
+            Ok(content) => match toml::from_str::<BuildCache>(content.as_str()) {
+                Ok(cache) if cache.version == CACHE_VERSION => cache,
+                _ => {
+                    warn!("ignore invalid build cache {}", path.to_string_lossy());
+                    BuildCache::default()
+                }
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => BuildCache::default(),
+            Err(e) => return Err(e.into()),
+        };
+        cache.version = CACHE_VERSION;
+        cache.path = path.clone();
+        Ok(cache)
+    }
+
+    pub(crate) fn is_fresh(&self, stage: &str, fingerprint: &str) -> bool {
+        self.stages.get(stage).is_some_and(|last| last == fingerprint)
+    }
+
+    pub(crate) fn update(&mut self, stage: &str, fingerprint: Option<String>) -> Result<()> {
+        match fingerprint {
+            Some(fingerprint) => self.stages.insert(stage.to_string(), fingerprint),
+            None => self.stages.remove(stage),
+        };
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(self.path.as_path(), toml::to_string(self)?)?;
+        Ok(())
+    }
+}
+
+/// 根据阶段名称, 附加信息(工具版本/配置)和所有输入文件的内容计算指纹.
+pub(crate) fn fingerprint(stage: &str, key: &str, inputs: &[PathBuf], ignore: &Path) -> Result<String> {
+    let mut hash = Md5::new();
+    hash.update(format!("{}\0{}\0{}\0", CACHE_VERSION, stage, key));
+    for input in inputs {
+        hash.update(input.to_string_lossy().as_bytes());
+        if input.is_file() {
+            hash.update(b"\0f\0");
+            hash.update(file_md5(input)?);
+        } else if input.is_dir() {
+            hash.update(b"\0d\0");
+            let walkdir = WalkDir::new(input).sort_by_file_name();
+            for entry in walkdir.into_iter().filter_entry(|e| !e.path().starts_with(ignore)) {
+                let entry = entry?;
+                if entry.file_type().is_file() {
+                    hash.update(entry.path().strip_prefix(input)?.to_string_lossy().as_bytes());
+                    hash.update(b"\0");
+                    hash.update(file_md5(entry.path())?);
+                }
+            }
+        } else {
+            hash.update(b"\0missing\0");
+        }
+    }
+    Ok(hex(hash.finalize().as_slice()))
+}
+
+pub(crate) fn file_md5(path: &Path) -> io::Result<[u8; 16]> {
+    let mut file = fs::File::open(path)?;
+    let mut hash = Md5::new();
+    io::copy(&mut file, &mut hash)?;
+    Ok(hash.finalize().into())
+}
+
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// 外部工具的版本, 计入每个阶段的指纹, 工具升级后缓存自动失效.
+/// apktool 和 bundletool 的 jar 没有统一的版本命令, 以文件大小和修改时间代替.
+pub(crate) fn tool_versions(config: &Config) -> String {
+    let jar = |path: &str| match fs::metadata(path) {
+        Ok(metadata) => {
+            let modified = metadata.modified().ok()
+                .and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok())
+                .map(|time| time.as_nanos())
+                .unwrap_or_default();
+            format!("{}:{}", metadata.len(), modified)
+        }
+        Err(_) => "missing".to_string(),
+    };
+    format!("aapt2={} apktool={} bundletool={}", aapt2_version(), jar(config.jar.apktool_path.as_str()), jar(config.jar.bundletool_path.as_str()))
+}
+
+/// `aapt2 version` 的输出, 工具升级后缓存自动失效.
+pub(crate) fn aapt2_version() -> &'static str {
+    AAPT2_VERSION.get_or_init(|| {
+        let version = Command::new("aapt2")
+            .arg("version")
+            .output()
+            .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+            .unwrap_or_else(|_| "unknown".to_string());
+        debug!("aapt2 version: {}", version);
+        version
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{config_in, TempDir};
+
+    #[test]
+    fn fingerprint_follows_file_contents() {
+        let dir = TempDir::new("cache-fingerprint");
+        let res = dir.join("res");
+        fs::create_dir_all(res.join("values")).unwrap();
+        fs::write(res.join("values/strings.xml"), "<resources/>").unwrap();
+        let manifest = dir.join("AndroidManifest.xml");
+        fs::write(manifest.as_path(), "<manifest/>").unwrap();
+        let ignore = dir.join(".abtool");
+        let inputs = [res.clone(), manifest.clone()];
+        let fingerprint = || super::fingerprint("compile", "aapt2=1", &inputs, ignore.as_path()).unwrap();
+
+        let first = fingerprint();
+        assert_eq!(fingerprint(), first);
+        assert_ne!(super::fingerprint("compile", "aapt2=2", &inputs, ignore.as_path()).unwrap(), first);
+        assert_ne!(super::fingerprint("link", "aapt2=1", &inputs, ignore.as_path()).unwrap(), first);
+
+        // 修改文件
+        fs::write(manifest.as_path(), "<manifest package=\"a\"/>").unwrap();
+        let changed = fingerprint();
+        assert_ne!(changed, first);
+
+        // 目录中新增文件
+        fs::write(res.join("values/colors.xml"), "<resources/>").unwrap();
+        let added = fingerprint();
+        assert_ne!(added, changed);
+
+        // 输入目录中的 `.abtool` 不影响指纹
+        let cached = dir.join(".abtool");
+        fs::create_dir_all(cached.as_path()).unwrap();
+        fs::write(cached.join("cache.toml"), "version = 1").unwrap();
+        let root = [dir.path().to_path_buf()];
+        let before = super::fingerprint("zip", "", &root, ignore.as_path()).unwrap();
+        fs::write(cached.join("cache.toml"), "version = 2").unwrap();
+        assert_eq!(super::fingerprint("zip", "", &root, ignore.as_path()).unwrap(), before);
+        fs::write(dir.join("classes.dex"), "dex").unwrap();
+        assert_ne!(super::fingerprint("zip", "", &root, ignore.as_path()).unwrap(), before);
+
+        // 删除的输入与存在时不同
+        fs::remove_file(manifest.as_path()).unwrap();
+        assert_ne!(fingerprint(), added);
+    }
+
+    #[test]
+    fn cache_is_saved_between_builds() {
+        let dir = TempDir::new("cache-update");
+        let config = config_in(dir.path());
+        let layout = BuildLayout::new(&config);
+
+        let mut cache = BuildCache::load(&layout).unwrap();
+        assert!(!cache.is_fresh("compile_resources", "a"));
+        cache.update("compile_resources", Some("a".to_string())).unwrap();
+        cache.update("link_resources", Some("b".to_string())).unwrap();
+
+        let mut cache = BuildCache::load(&layout).unwrap();
+        assert!(cache.is_fresh("compile_resources", "a"));
+        assert!(!cache.is_fresh("compile_resources", "b"));
+        assert!(cache.is_fresh("link_resources", "b"));
+
+        // 失败的阶段清除记录
+        cache.update("link_resources", None).unwrap();
+        assert!(!BuildCache::load(&layout).unwrap().is_fresh("link_resources", "b"));
+
+        // 版本不同或无法解析时忽略
+        fs::write(layout.cache.as_path(), "version = 0\n[stages]\ncompile_resources = \"a\"\n").unwrap();
+        assert!(!BuildCache::load(&layout).unwrap().is_fresh("compile_resources", "a"));
+        fs::write(layout.cache.as_path(), "not toml [").unwrap();
+        assert!(!BuildCache::load(&layout).unwrap().is_fresh("compile_resources", "a"));
+    }
+
+    #[test]
+    fn tool_versions_follow_jars() {
+        let dir = TempDir::new("cache-tools");
+        let mut config = config_in(dir.path());
+        config.jar.bundletool_path = dir.join("bundletool.jar").to_string_lossy().to_string();
+        let missing = tool_versions(&config);
+        assert!(missing.contains("bundletool=missing"), "{}", missing);
+        fs::write(config.jar.bundletool_path.as_str(), "1.15").unwrap();
+        let installed = tool_versions(&config);
+        assert_ne!(installed, missing);
+        fs::write(config.jar.bundletool_path.as_str(), "1.16.0").unwrap();
+        assert_ne!(tool_versions(&config), installed);
+    }
+}
@@ -1,49 +1,753 @@
+use std::borrow::Cow;
+use std::fmt;
+use std::fs;
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::str::FromStr;
+
+use anyhow::{anyhow, Context, Result};
 use serde::Deserialize;
 
-#[derive(Debug, Deserialize)]
+/// 日志中代替密码显示的内容.
+const REDACTED: &str = "******";
+
+/// 密码等敏感配置, `Debug` 和 `Display` 都不会输出原文, 只能通过 [`Secret::expose`] 读取.
+#[derive(Clone)]
+pub struct Secret {
+    value: String,
+    /// 已解析过 `env:` 等来源, 解析结果不会再被当作来源解析.
+    resolved: bool,
+}
+
+impl Secret {
+    pub fn new(value: impl Into<String>) -> Self {
+        Secret { value: value.into(), resolved: false }
+    }
+
+    pub(crate) fn expose(&self) -> &str {
+        self.value.as_str()
+    }
+
+    /// 按 apksigner 的约定解析密码来源: `pass:<密码>`, `env:<变量名>`, `file:<路径>`(取第一行)
+    /// 或 `prompt`(从终端读取), 其余值视为密码本身. `name` 用于提示和错误信息.
+    fn resolve(&mut self, name: String) -> Result<()> {
+        if self.resolved {
+            return Ok(());
+        }
+        if let Some(value) = self.value.strip_prefix("pass:") {
+            self.value = value.to_string();
+        } else if let Some(var) = self.value.strip_prefix("env:") {
+            self.value = std::env::var(var).with_context(|| format!("{}: environment variable `{}` is not set", name, var))?;
+        } else if let Some(path) = self.value.strip_prefix("file:") {
+            let content = fs::read_to_string(path).with_context(|| format!("{}: read {}", name, path))?;
+            self.value = content.lines().next().unwrap_or_default().to_string();
+        } else if self.value == "prompt" {
+            self.value = prompt(name.as_str())?;
+        }
+        self.resolved = true;
+        Ok(())
+    }
+}
+
+impl<'de> Deserialize<'de> for Secret {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        String::deserialize(deserializer).map(Secret::new)
+    }
+}
+
+/// 从终端读取密码, 不回显输入. stdin 不是终端时报错.
+fn prompt(name: &str) -> Result<String> {
+    let stdin = io::stdin();
+    if !stdin.is_terminal() {
+        return Err(anyhow!("{} is `prompt` but stdin is not a terminal, use `env:` or `file:` instead", name));
+    }
+    eprint!("{}: ", name);
+    io::stderr().flush()?;
+    let echo = EchoGuard::disable();
+    let mut line = String::new();
+    stdin.lock().read_line(&mut line)?;
+    drop(echo);
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+/// 读取密码期间关闭终端回显, drop 时恢复.
+struct EchoGuard {
+    #[cfg(unix)]
+    original: Option<libc::termios>,
+}
+
+impl EchoGuard {
+    #[cfg(unix)]
+    fn disable() -> Self {
+        // SAFETY: termios 为纯数据结构, 只在 tcgetattr 成功后使用
+        unsafe {
+            let mut termios: libc::termios = std::mem::zeroed();
+            if libc::tcgetattr(libc::STDIN_FILENO, &mut termios) != 0 {
+                return EchoGuard { original: None };
+            }
+            let original = termios;
+            termios.c_lflag &= !libc::ECHO;
+            termios.c_lflag |= libc::ECHONL;
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &termios);
+            EchoGuard { original: Some(original) }
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn disable() -> Self {
+        EchoGuard {}
+    }
+}
+
+impl Drop for EchoGuard {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        if let Some(original) = &self.original {
+            // SAFETY: 恢复 disable 时读取的终端设置
+            unsafe {
+                libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, original);
+            }
+        }
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(REDACTED)
+    }
+}
+
+impl fmt::Display for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(REDACTED)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
 pub struct Sign {
     pub(crate) keystore: String,
-    pub(crate) keystore_pass: String,
+    pub(crate) keystore_pass: Secret,
     pub(crate) keystore_key_alias: String,
-    pub(crate) keystore_key_pass: String,
+    pub(crate) keystore_key_pass: Secret,
+    /// aab 签名的摘要算法, 如 `SHA-256`, 默认 `SHA-256`.
+    pub(crate) digest_algorithm: Option<String>,
+    /// aab 签名算法, 如 `SHA256withRSA`, `SHA256withECDSA`, 默认根据密钥类型选择.
+    pub(crate) signature_algorithm: Option<String>,
+    /// `apksigner rotate` 生成的 lineage 文件, 用于 v3 密钥轮替.
+    pub(crate) lineage: Option<String>,
+    /// 轮替前的密钥, 配置后 v1/v2 使用该密钥签名.
+    pub(crate) previous: Option<SignKey>,
 }
 
-#[derive(Debug, Deserialize)]
+impl Sign {
+    pub fn new(keystore: impl Into<String>, keystore_pass: Secret, keystore_key_alias: impl Into<String>, keystore_key_pass: Secret) -> Self {
+        Sign {
+            keystore: keystore.into(),
+            keystore_pass,
+            keystore_key_alias: keystore_key_alias.into(),
+            keystore_key_pass,
+            digest_algorithm: None,
+            signature_algorithm: None,
+            lineage: None,
+            previous: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SignKey {
+    pub(crate) keystore: String,
+    pub(crate) keystore_pass: Secret,
+    pub(crate) keystore_key_alias: String,
+    pub(crate) keystore_key_pass: Secret,
+}
+
+#[derive(Debug, Clone, Deserialize)]
 pub struct Apk {
     pub(crate) apk_path: String,
     pub(crate) apk_outdir: String,
-    pub(crate) min_sdk_version: String,
-    pub(crate) target_sdk_version: String,
-    pub(crate) version_code: String,
-    pub(crate) version_name: String,
+    /// 以下版本信息省略时使用反编译得到的 `apktool.yml` 中的值, 数字可以写成整数或字符串.
+    #[serde(default, deserialize_with = "integer")]
+    pub(crate) min_sdk_version: Option<u32>,
+    #[serde(default, deserialize_with = "integer")]
+    pub(crate) target_sdk_version: Option<u32>,
+    #[serde(default, deserialize_with = "integer")]
+    pub(crate) version_code: Option<u32>,
+    pub(crate) version_name: Option<String>,
     pub(crate) app_name: String,
 }
 
-#[derive(Debug, Deserialize)]
+impl Apk {
+    /// `apk_path` 反编译到 `apk_outdir`, 版本信息默认取自 `apktool.yml`.
+    pub fn new(apk_path: impl Into<String>, apk_outdir: impl Into<String>, app_name: impl Into<String>) -> Self {
+        Apk {
+            apk_path: apk_path.into(),
+            apk_outdir: apk_outdir.into(),
+            min_sdk_version: None,
+            target_sdk_version: None,
+            version_code: None,
+            version_name: None,
+            app_name: app_name.into(),
+        }
+    }
+
+    pub fn with_sdk_versions(mut self, min_sdk_version: u32, target_sdk_version: u32) -> Self {
+        self.min_sdk_version = Some(min_sdk_version);
+        self.target_sdk_version = Some(target_sdk_version);
+        self
+    }
+
+    pub fn with_version(mut self, version_code: u32, version_name: impl Into<String>) -> Self {
+        self.version_code = Some(version_code);
+        self.version_name = Some(version_name.into());
+        self
+    }
+}
+
+/// 兼容旧配置中 `version_code = "101"` 的写法, 空字符串视为未设置.
+fn integer<'de, D>(deserializer: D) -> std::result::Result<Option<u32>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Integer {
+        Number(i64),
+        Text(String),
+    }
+
+    let value = match Integer::deserialize(deserializer)? {
+        Integer::Number(number) => number.to_string(),
+        Integer::Text(text) => text.trim().to_string(),
+    };
+    if value.is_empty() {
+        return Ok(None);
+    }
+    value.parse::<u32>()
+        .map(Some)
+        .map_err(|_| serde::de::Error::custom(format!("`{}` is not a non-negative integer", value)))
+}
+
+#[derive(Debug, Clone, Deserialize)]
 pub struct Jar {
     pub(crate) apktool_path: String,
     pub(crate) bundletool_path: String,
     pub(crate) android_jar_path: String,
 }
 
-#[derive(Debug, Deserialize)]
+impl Jar {
+    pub fn new(apktool_path: impl Into<String>, bundletool_path: impl Into<String>, android_jar_path: impl Into<String>) -> Self {
+        Jar {
+            apktool_path: apktool_path.into(),
+            bundletool_path: bundletool_path.into(),
+            android_jar_path: android_jar_path.into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
 pub struct Config {
     pub(crate) sign: Sign,
     pub(crate) apk: Apk,
     pub(crate) jar: Jar,
     pub(crate) config: ConfigInfo,
     pub(crate) build_apk: BuildApk,
+    #[serde(default)]
+    pub(crate) pipeline: PipelineConfig,
+    #[serde(default)]
+    pub(crate) bundle: BundleConfig,
+    #[serde(default)]
+    pub(crate) build_apks: BuildApksConfig,
+    #[serde(default)]
+    pub(crate) module: Vec<FeatureModule>,
+    #[serde(default)]
+    pub(crate) asset_pack: Vec<AssetPack>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
 pub struct ConfigInfo {
     pub(crate) install: bool,
     pub(crate) launch: bool,
     pub(crate) main_activity: String,
+    /// 可选, 作为 `[bundle]` 基础的 BundleConfig(JSON).
+    #[serde(default)]
     pub(crate) bundletool_config_path: String,
+    #[serde(default = "default_true")]
+    pub(crate) incremental: bool,
 }
 
-#[derive(Debug, Deserialize)]
+impl ConfigInfo {
+    /// 默认不安装, 不启动, 不使用 bundletool 配置文件, 开启增量构建.
+    pub fn new(main_activity: impl Into<String>) -> Self {
+        ConfigInfo {
+            install: false,
+            launch: false,
+            main_activity: main_activity.into(),
+            bundletool_config_path: String::new(),
+            incremental: true,
+        }
+    }
+
+    pub fn with_bundletool_config(mut self, path: impl Into<String>) -> Self {
+        self.bundletool_config_path = path.into();
+        self
+    }
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, Deserialize)]
 pub struct BuildApk {
     pub(crate) app_path: String,
-}
\ No newline at end of file
+    /// 未压缩 `.so` 的对齐页大小(KiB), 可选 4, 16.
+    #[serde(default = "default_page_size")]
+    pub(crate) zipalign_page_size: u32,
+}
+
+impl BuildApk {
+    pub fn new(app_path: impl Into<String>) -> Self {
+        BuildApk { app_path: app_path.into(), zipalign_page_size: default_page_size() }
+    }
+}
+
+fn default_page_size() -> u32 {
+    crate::DEFAULT_PAGE_SIZE
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct PipelineConfig {
+    #[serde(default)]
+    pub(crate) aab: Vec<String>,
+    #[serde(default)]
+    pub(crate) apk: Vec<String>,
+    #[serde(default)]
+    pub(crate) skip: Vec<String>,
+    #[serde(default)]
+    pub(crate) stage: Vec<CommandStageConfig>,
+}
+
+/// `[bundle]`: 生成 bundletool 的 BundleConfig, 覆盖 `bundletool_config_path` 中的同名设置.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct BundleConfig {
+    /// 追加到 `compression.uncompressedGlob`.
+    #[serde(default)]
+    pub(crate) uncompressed_glob: Vec<String>,
+    pub(crate) uncompress_native_libraries: Option<bool>,
+    pub(crate) uncompress_dex_files: Option<bool>,
+    /// 始终保留在 base apk 中的资源, 如 `string/app_name`.
+    #[serde(default)]
+    pub(crate) master_resources: Vec<String>,
+    #[serde(default)]
+    pub(crate) master_resource_ids: Vec<u32>,
+    /// 按顺序替换 `optimizations.splitsConfig.splitDimension`.
+    #[serde(default)]
+    pub(crate) split: Vec<SplitDimensionConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SplitDimensionConfig {
+    pub(crate) dimension: SplitDimension,
+    /// 为 true 时不按该维度拆分.
+    #[serde(default)]
+    pub(crate) negate: bool,
+    /// 去掉 `assets/xxx#tcf_etc1` 形式的目录后缀, 只对 texture 和 device_tier 有效.
+    #[serde(default)]
+    pub(crate) suffix_stripping: bool,
+    /// 不支持该维度的设备使用的后缀.
+    pub(crate) default_suffix: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SplitDimension {
+    Abi,
+    #[serde(alias = "screen_density")]
+    Density,
+    Language,
+    #[serde(alias = "texture_compression_format")]
+    Texture,
+    DeviceTier,
+}
+
+impl SplitDimension {
+    /// BundleConfig 中的枚举名.
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            SplitDimension::Abi => "ABI",
+            SplitDimension::Density => "SCREEN_DENSITY",
+            SplitDimension::Language => "LANGUAGE",
+            SplitDimension::Texture => "TEXTURE_COMPRESSION_FORMAT",
+            SplitDimension::DeviceTier => "DEVICE_TIER",
+        }
+    }
+}
+
+/// `[build_apks]`: `bundletool build-apks` 生成哪些 apk.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct BuildApksConfig {
+    #[serde(default)]
+    pub(crate) mode: ApksMode,
+    /// 只生成已连接设备需要的 apk.
+    #[serde(default)]
+    pub(crate) connected_device: bool,
+    /// 只生成符合设备描述文件(JSON)的 apk.
+    pub(crate) device_spec: Option<String>,
+    #[serde(default)]
+    pub(crate) local_testing: bool,
+    pub(crate) device_tier: Option<u32>,
+}
+
+impl BuildApksConfig {
+    /// 设置了任一选项时, 即使不安装也生成 `.apks`.
+    pub(crate) fn is_requested(&self) -> bool {
+        self.mode != ApksMode::Default || self.connected_device || self.device_spec.is_some() || self.local_testing || self.device_tier.is_some()
+    }
+}
+
+/// `build-apks --mode`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ApksMode {
+    #[default]
+    Default,
+    /// 单个包含全部资源的 apk, 可以直接侧载.
+    Universal,
+    System,
+    Instant,
+    Archive,
+}
+
+impl ApksMode {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ApksMode::Default => "default",
+            ApksMode::Universal => "universal",
+            ApksMode::System => "system",
+            ApksMode::Instant => "instant",
+            ApksMode::Archive => "archive",
+        }
+    }
+}
+
+impl FromStr for ApksMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        [ApksMode::Default, ApksMode::Universal, ApksMode::System, ApksMode::Instant, ApksMode::Archive].into_iter()
+            .find(|mode| mode.name().eq_ignore_ascii_case(s.trim()))
+            .ok_or_else(|| anyhow!("unknown apks mode `{}`, expected one of: default, universal, system, instant, archive", s))
+    }
+}
+
+/// `[[module]]`: 与 base 一起打包进 aab 的 feature 模块, 通常来自 `split_<name>.apk`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct FeatureModule {
+    /// 模块名, 即 `split` 属性和 aab 中的目录名.
+    pub(crate) name: String,
+    pub(crate) apk_path: String,
+    /// 默认为 `<apk_outdir>_<name>`.
+    pub(crate) apk_outdir: Option<String>,
+    #[serde(default)]
+    pub(crate) delivery: ModuleDelivery,
+    /// 为 true 时合并进低版本设备使用的 standalone apk.
+    #[serde(default = "default_true")]
+    pub(crate) fusing: bool,
+    /// `dist:title`, 如 `@string/title_camera`, 省略时保留 manifest 中原有的值.
+    pub(crate) title: Option<String>,
+}
+
+impl FeatureModule {
+    /// 安装时下发, 合并进 standalone apk.
+    pub fn new(name: impl Into<String>, apk_path: impl Into<String>) -> Self {
+        FeatureModule {
+            name: name.into(),
+            apk_path: apk_path.into(),
+            apk_outdir: None,
+            delivery: ModuleDelivery::default(),
+            fusing: true,
+            title: None,
+        }
+    }
+
+    pub fn with_outdir(mut self, apk_outdir: impl Into<String>) -> Self {
+        self.apk_outdir = Some(apk_outdir.into());
+        self
+    }
+
+    pub fn with_delivery(mut self, delivery: ModuleDelivery) -> Self {
+        self.delivery = delivery;
+        self
+    }
+
+    pub fn with_title(mut self, title: impl Into<String>) -> Self {
+        self.title = Some(title.into());
+        self
+    }
+
+    pub(crate) fn outdir(&self, config: &Config) -> String {
+        self.apk_outdir.clone().unwrap_or_else(|| format!("{}_{}", config.apk.apk_outdir, self.name))
+    }
+}
+
+/// feature 模块的 `<dist:delivery>`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ModuleDelivery {
+    #[default]
+    InstallTime,
+    OnDemand,
+}
+
+/// `[[asset_pack]]`: 把 `assets/` 下的目录移出 base, 打包为 Play Asset Delivery 资源包.
+#[derive(Debug, Clone, Deserialize)]
+pub struct AssetPack {
+    /// 资源包名, 即 aab 中的模块名.
+    pub(crate) name: String,
+    /// `assets/` 下的目录, 如 `levels` 或 `video/intro`.
+    pub(crate) directories: Vec<String>,
+    #[serde(default)]
+    pub(crate) delivery: AssetPackDelivery,
+}
+
+impl AssetPack {
+    /// 安装时下发.
+    pub fn new(name: impl Into<String>, directories: Vec<String>) -> Self {
+        AssetPack { name: name.into(), directories, delivery: AssetPackDelivery::default() }
+    }
+
+    pub fn with_delivery(mut self, delivery: AssetPackDelivery) -> Self {
+        self.delivery = delivery;
+        self
+    }
+}
+
+/// 资源包的 `<dist:delivery>`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AssetPackDelivery {
+    #[default]
+    InstallTime,
+    FastFollow,
+    OnDemand,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CommandStageConfig {
+    pub(crate) name: String,
+    pub(crate) command: Vec<String>,
+    #[serde(default)]
+    pub(crate) inputs: Vec<String>,
+    #[serde(default)]
+    pub(crate) outputs: Vec<String>,
+    pub(crate) flow: Option<String>,
+    #[serde(default)]
+    pub(crate) cache: bool,
+}
+
+impl Config {
+    pub fn new(sign: Sign, apk: Apk, jar: Jar, config: ConfigInfo, build_apk: BuildApk) -> Self {
+        Config { sign, apk, jar, config, build_apk, pipeline: PipelineConfig::default(), bundle: BundleConfig::default(),
+                 build_apks: BuildApksConfig::default(), module: Vec::new(), asset_pack: Vec::new() }
+    }
+
+    /// 追加一个 feature 模块.
+    pub fn with_module(mut self, module: FeatureModule) -> Self {
+        self.module.push(module);
+        self
+    }
+
+    /// 追加一个资源包.
+    pub fn with_asset_pack(mut self, asset_pack: AssetPack) -> Self {
+        self.asset_pack.push(asset_pack);
+        self
+    }
+
+    /// 解析 `config.toml` 的内容, 并解析其中 `env:`, `file:`, `prompt` 形式的密码.
+    pub fn from_toml(content: &str) -> Result<Self> {
+        let mut config: Config = toml::from_str(content)?;
+        config.resolve_secrets()?;
+        Ok(config)
+    }
+
+    /// 返回密码均已解析的配置, 代码中用 [`Secret::new`] 构造的 `env:` 等密码在这里解析.
+    /// 全部已解析时直接借用, 否则复制一份.
+    pub(crate) fn resolved(&self) -> Result<Cow<'_, Config>> {
+        let sign = &self.sign;
+        let previous = sign.previous.as_ref().is_none_or(|p| p.keystore_pass.resolved && p.keystore_key_pass.resolved);
+        if sign.keystore_pass.resolved && sign.keystore_key_pass.resolved && previous {
+            return Ok(Cow::Borrowed(self));
+        }
+        let mut config = self.clone();
+        config.resolve_secrets()?;
+        Ok(Cow::Owned(config))
+    }
+
+    /// 解析 `[sign]` 与 `[sign.previous]` 中 `env:`, `file:`, `prompt` 形式的密码.
+    pub(crate) fn resolve_secrets(&mut self) -> Result<()> {
+        let sign = &mut self.sign;
+        sign.keystore_pass.resolve(format!("keystore_pass of {}", sign.keystore))?;
+        sign.keystore_key_pass.resolve(format!("keystore_key_pass of `{}`", sign.keystore_key_alias))?;
+        if let Some(previous) = &mut sign.previous {
+            previous.keystore_pass.resolve(format!("keystore_pass of {}", previous.keystore))?;
+            previous.keystore_key_pass.resolve(format!("keystore_key_pass of `{}`", previous.keystore_key_alias))?;
+        }
+        Ok(())
+    }
+
+    /// 非空的密码, 长的在前.
+    fn secrets(&self) -> Vec<&str> {
+        let mut secrets = vec![&self.sign.keystore_pass, &self.sign.keystore_key_pass];
+        if let Some(previous) = &self.sign.previous {
+            secrets.extend([&previous.keystore_pass, &previous.keystore_key_pass]);
+        }
+        let mut secrets: Vec<&str> = secrets.iter().map(|secret| secret.expose()).filter(|secret| !secret.is_empty()).collect();
+        secrets.sort_by_key(|secret| std::cmp::Reverse(secret.len()));
+        secrets
+    }
+
+    /// 外部命令的一个参数等于密码时替换为 `******`, 包括 `pass:<密码>` 和 `--name=<密码>` 形式.
+    /// 按参数整体比较, 密码中含空格也能去掉, 短密码不会误伤恰好包含它的路径.
+    pub(crate) fn redact_arg(&self, arg: &str) -> String {
+        let prefix = self.secrets().into_iter().find_map(|secret| {
+            let prefix = arg.strip_suffix(secret)?;
+            (prefix.is_empty() || prefix.ends_with("pass:") || (prefix.starts_with('-') && prefix.ends_with('='))).then_some(prefix)
+        });
+        match prefix {
+            Some(prefix) => format!("{}{}", prefix, REDACTED),
+            None => arg.to_string(),
+        }
+    }
+
+    /// 将 `text`(如 stderr 的一行)中的密码替换为 `******`. 只替换前后为空白、引号(含反引号)或行首尾,
+    /// 前面也可以是 `:` 和 `=` 的完整出现, 不会替换路径或单词中恰好相同的部分.
+    pub(crate) fn redact(&self, text: &str) -> String {
+        let mut text = text.to_string();
+        for secret in self.secrets() {
+            let mut redacted = String::with_capacity(text.len());
+            let mut last = 0;
+            for (at, _) in text.match_indices(secret) {
+                let before = text[..at].chars().next_back();
+                let after = text[at + secret.len()..].chars().next();
+                let quote = |c: char| c.is_whitespace() || matches!(c, '"' | '\'' | '`');
+                if before.is_none_or(|c| quote(c) || c == ':' || c == '=') && after.is_none_or(quote) {
+                    redacted.push_str(&text[last..at]);
+                    redacted.push_str(REDACTED);
+                    last = at + secret.len();
+                }
+            }
+            redacted.push_str(&text[last..]);
+            text = redacted;
+        }
+        text
+    }
+
+    pub fn set_install(&mut self, install: bool) {
+        self.config.install = install;
+    }
+
+    pub fn set_launch(&mut self, launch: bool) {
+        self.config.launch = launch;
+    }
+
+    pub fn set_incremental(&mut self, incremental: bool) {
+        self.config.incremental = incremental;
+    }
+
+    pub fn set_apks_mode(&mut self, mode: ApksMode) {
+        self.build_apks.mode = mode;
+    }
+
+    pub fn set_connected_device(&mut self, connected_device: bool) {
+        self.build_apks.connected_device = connected_device;
+    }
+
+    pub fn set_device_spec(&mut self, device_spec: impl Into<String>) {
+        self.build_apks.device_spec = Some(device_spec.into());
+    }
+
+    pub fn set_local_testing(&mut self, local_testing: bool) {
+        self.build_apks.local_testing = local_testing;
+    }
+
+    pub fn set_device_tier(&mut self, device_tier: u32) {
+        self.build_apks.device_tier = Some(device_tier);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::error::Error;
+    use crate::test_util;
+
+    fn config(keystore_pass: &str, keystore_key_pass: &str) -> Config {
+        Config { sign: test_util::sign(keystore_pass, keystore_key_pass), ..test_util::config() }
+    }
+
+    #[test]
+    fn redact_password_arguments() {
+        let config = config("android", "1");
+        assert_eq!(
+            config.redact("java -jar bundletool.jar --ks-pass pass:android --key-pass=pass:1 --ks /home/android/1.jks"),
+            "java -jar bundletool.jar --ks-pass pass:****** --key-pass=pass:****** --ks /home/android/1.jks",
+        );
+        assert_eq!(config.redact("apksigner --ks-pass=android 1 android.jar"), "apksigner --ks-pass=****** ****** android.jar");
+        assert_eq!(config.redact("keystore 1.jks at android/1"), "keystore 1.jks at android/1");
+    }
+
+    #[test]
+    fn redact_password_with_space() {
+        let config = config("my secret", "1");
+        let args = ["--ks-pass", "pass:my secret", "--key-pass=pass:1", "my secret", "--ks-pass=my secret", "my", "secret.jks", "1.jks"];
+        let redacted: Vec<String> = args.iter().map(|arg| config.redact_arg(arg)).collect();
+        assert_eq!(redacted, ["--ks-pass", "pass:******", "--key-pass=pass:******", "******", "--ks-pass=******", "my", "secret.jks", "1.jks"]);
+
+        let error = Error::ToolFailed {
+            stage: "build apks".to_string(),
+            program: "java".to_string(),
+            args: args.iter().map(|arg| arg.to_string()).collect(),
+            status: Some(1),
+            stderr_tail: vec!["keystore password was `my secret` for my secret.jks".to_string()],
+        };
+        let message = error.redact(&config).to_string();
+        assert!(!message.contains("my secret`"), "{}", message);
+        assert!(message.contains("pass:****** "), "{}", message);
+        assert!(message.contains("password was `******` for my secret.jks"), "{}", message);
+    }
+
+    #[test]
+    fn debug_hides_secrets() {
+        let config = config("android", "1");
+        let debug = format!("{:?}", config);
+        assert!(!debug.contains("\"android\""));
+        assert!(debug.contains("keystore_pass: ******"));
+    }
+
+    #[test]
+    fn resolve_secrets_of_config_built_in_code() {
+        let var = format!("ABTOOL_TEST_PASS_{}", std::process::id());
+        std::env::set_var(var.as_str(), "env:NOT_A_SOURCE");
+        let unresolved = config(format!("env:{}", var).as_str(), "pass:123456");
+        let resolved = unresolved.resolved().unwrap();
+        assert!(matches!(resolved, Cow::Owned(_)));
+        assert_eq!(resolved.sign.keystore_pass.expose(), "env:NOT_A_SOURCE");
+        assert_eq!(resolved.sign.keystore_key_pass.expose(), "123456");
+        // 解析结果不会被再次当作来源
+        let again = resolved.resolved().unwrap();
+        assert!(matches!(again, Cow::Borrowed(_)));
+        assert_eq!(again.sign.keystore_pass.expose(), "env:NOT_A_SOURCE");
+        std::env::remove_var(var.as_str());
+
+        let missing = config("env:ABTOOL_TEST_MISSING_PASS", "1").resolved().unwrap_err();
+        assert!(format!("{:#}", missing).contains("ABTOOL_TEST_MISSING_PASS"));
+    }
+
+    #[test]
+    fn pass_prefix_resolves_once() {
+        let config = config("pass:env:X", "1").resolved().unwrap().into_owned();
+        assert_eq!(config.sign.keystore_pass.expose(), "env:X");
+        assert!(matches!(config.resolved().unwrap(), Cow::Borrowed(_)));
+    }
+}
@@ -0,0 +1,87 @@
+use std::fmt;
+use std::io;
+
+use crate::config::Config;
+
+/// 通过 `anyhow::Error::downcast_ref` 取出的错误类型, 用于区分失败原因:
+///
+/// ```no_run
+/// # let config = shell::read_config("config.toml").unwrap();
+/// if let Err(e) = shell::run_aab(&config, "2024-01-01_00-00-00", &shell::StepRange::all()) {
+///     match e.downcast_ref::<shell::Error>() {
+///         Some(shell::Error::ToolFailed { stderr_tail, .. }) => eprintln!("{}", stderr_tail.join("\n")),
+///         _ => eprintln!("{:#}", e),
+///     }
+/// }
+/// ```
+#[derive(Debug)]
+pub enum Error {
+    /// 找不到外部工具, 如 `java` 或 `aapt2` 不在 `PATH` 中.
+    ToolNotFound { program: String },
+    /// 外部工具返回了非零退出码. `status` 为 None 时表示被信号终止.
+    ToolFailed {
+        stage: String,
+        program: String,
+        args: Vec<String>,
+        status: Option<i32>,
+        /// stderr 的最后几行.
+        stderr_tail: Vec<String>,
+    },
+    /// 配置文件无法解析或配置项不合法.
+    ConfigInvalid(String),
+    /// keystore 无法读取或签名失败.
+    SigningFailed(String),
+    Io(io::Error),
+}
+
+impl Error {
+    /// 去掉命令参数中的密码.
+    pub(crate) fn redact(self, config: &Config) -> Self {
+        match self {
+            Error::ToolFailed { stage, program, args, status, stderr_tail } => Error::ToolFailed {
+                stage,
+                program,
+                args: args.iter().map(|arg| config.redact_arg(arg)).collect(),
+                status,
+                stderr_tail: stderr_tail.iter().map(|line| config.redact(line)).collect(),
+            },
+            e => e,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::ToolNotFound { program } => write!(f, "`{}` not found, make sure it is installed and on PATH", program),
+            Error::ToolFailed { stage, program, args, status, stderr_tail } => {
+                match status {
+                    Some(code) => write!(f, "{} failed, `{} {}` exited with status {}", stage, program, args.join(" "), code)?,
+                    None => write!(f, "{} failed, `{} {}` was terminated by a signal", stage, program, args.join(" "))?,
+                }
+                for line in stderr_tail {
+                    write!(f, "\n  {}", line)?;
+                }
+                Ok(())
+            }
+            Error::ConfigInvalid(message) => write!(f, "invalid config: {}", message),
+            Error::SigningFailed(message) => write!(f, "signing failed: {}", message),
+            Error::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
@@ -1,283 +1,228 @@
+use std::fs;
 use std::path::{Path, PathBuf};
-use std::sync::OnceLock;
+use std::time::SystemTime;
 
 use anyhow::Result;
 
-use config::Config;
-
-use crate::config;
-
-static RES_PATH: OnceLock<PathBuf> = OnceLock::new();
-static APKS_PATH: OnceLock<PathBuf> = OnceLock::new();
-
-static APP_PATH: OnceLock<PathBuf> = OnceLock::new();
-
-static AAB_PATH: OnceLock<PathBuf> = OnceLock::new();
-static BASE_ZIP_PATH: OnceLock<PathBuf> = OnceLock::new();
-static BASE_DIR_PATH: OnceLock<PathBuf> = OnceLock::new();
-static ROOT_PATH: OnceLock<PathBuf> = OnceLock::new();
-static MANIFEST_PATH: OnceLock<PathBuf> = OnceLock::new();
-static ASSETS_PATH: OnceLock<PathBuf> = OnceLock::new();
-static NEW_ASSETS_PATH: OnceLock<PathBuf> = OnceLock::new();
-
-static LIB_PATH: OnceLock<PathBuf> = OnceLock::new();
-static NEW_LIB_PATH: OnceLock<PathBuf> = OnceLock::new();
-
-static BASE_ROOT_PATH: OnceLock<PathBuf> = OnceLock::new();
-static UNKNOWN_PATH: OnceLock<PathBuf> = OnceLock::new();
-static NEW_UNKNOWN_PATH: OnceLock<PathBuf> = OnceLock::new();
-static KOTLIN_PATH: OnceLock<PathBuf> = OnceLock::new();
-static NEW_KOTLIN_PATH: OnceLock<PathBuf> = OnceLock::new();
-
-static META_PATH: OnceLock<PathBuf> = OnceLock::new();
-static NEW_META_PATH: OnceLock<PathBuf> = OnceLock::new();
-static DEX_PATH: OnceLock<PathBuf> = OnceLock::new();
-
-static BASE_APK_PATH: OnceLock<PathBuf> = OnceLock::new();
-static RESOURCES_ZIP_PATH: OnceLock<PathBuf> = OnceLock::new();
-
-static APK_BUILD_PATH: OnceLock<PathBuf> = OnceLock::new();
-static APK_DIST_PATH: OnceLock<PathBuf> = OnceLock::new();
-static APK_UN_SIGN_PATH: OnceLock<PathBuf> = OnceLock::new();
-static APK_ZIPALIGN_PATH: OnceLock<PathBuf> = OnceLock::new();
-static APK_SIGN_PATH: OnceLock<PathBuf> = OnceLock::new();
-
-pub fn get_res_path(config: &Config) -> Result<&PathBuf> {
-    let res = RES_PATH.get_or_try_init(|| {
-        let res_path = Path::new(config.apk.apk_outdir.as_str()).join("res");
-        Ok(res_path)
-    });
-    res
-}
-
-pub fn get_apks_path(config: &Config) -> Result<&PathBuf> {
-    let apks = APKS_PATH.get_or_try_init(|| {
-        let apks_path = Path::new(config.apk.apk_outdir.as_str()).join("app.apks");
-        Ok(apks_path)
-    });
-    apks
-}
-
-pub fn get_aab_path<'a>(config: &'a Config, time: &'a str) -> Result<&'a PathBuf> {
-    let aab = AAB_PATH.get_or_try_init(|| {
-        let aab_path = Path::new(config.apk.apk_outdir.as_str()).join(format!("{}{}.aab", time, config.apk.app_name));
-        Ok(aab_path)
-    });
-    aab
-}
-
-pub fn get_base_zip_path(config: &Config) -> Result<&PathBuf> {
-    let base_zip = BASE_ZIP_PATH.get_or_try_init(|| {
-        let base_zip_path = Path::new(config.apk.apk_outdir.as_str()).join("base.zip");
-        Ok(base_zip_path
-        )
-    });
-    base_zip
-}
-
-pub fn get_base_dir_path(config: &Config) -> Result<&PathBuf> {
-    let base_dir = BASE_DIR_PATH.get_or_try_init(|| {
-        let base_dir_path = Path::new(config.apk.apk_outdir.as_str()).join("base");
-
-        Ok(base_dir_path)
-    });
-    base_dir
-}
-
-pub fn get_root_path(config: &Config) -> Result<&PathBuf> {
-    let root = ROOT_PATH.get_or_try_init(|| {
-        let root_path = Path::new(config.apk.apk_outdir.as_str()).to_path_buf();
-
-        Ok(root_path)
-    });
-    root
-}
-
-pub fn get_manifest_path(config: &Config) -> Result<&PathBuf> {
-    let manifest = MANIFEST_PATH.get_or_try_init(|| {
-        let manifest_path = get_base_dir_path(config)?.join("manifest");
-
-        Ok(manifest_path)
-    });
-    manifest
-}
-
-pub fn get_assets_path(config: &Config) -> Result<&PathBuf> {
-    let assets = ASSETS_PATH.get_or_try_init(|| {
-        let assets_path = get_root_path(config)?.join("assets");
-
-        Ok(assets_path)
-    });
-    assets
-}
-
-pub fn get_new_assets_path(config: &Config) -> Result<&PathBuf> {
-    let new_assets = NEW_ASSETS_PATH.get_or_try_init(|| {
-        let new_assets_path = get_base_dir_path(config)?.join("assets");
-
-        Ok(new_assets_path)
-    });
-    new_assets
-}
-
-pub fn get_lib_path(config: &Config) -> Result<&PathBuf> {
-    let lib = LIB_PATH.get_or_try_init(|| {
-        let lib_path = get_root_path(config)?.join("lib");
-
-        Ok(lib_path)
-    });
-    lib
-}
-
-pub fn get_new_lib_path(config: &Config) -> Result<&PathBuf> {
-    let new_lib = NEW_LIB_PATH.get_or_try_init(|| {
-        let new_lib_path = get_base_dir_path(config)?.join("lib");
-
-        Ok(new_lib_path)
-    });
-    new_lib
-}
-
-pub fn get_base_root_path(config: &Config) -> Result<&PathBuf> {
-    let base_root = BASE_ROOT_PATH.get_or_try_init(|| {
-        let base_root_path = get_base_dir_path(config)?.join("root");
-
-        Ok(base_root_path)
-    });
-    base_root
-}
-
-pub fn get_unknown_path(config: &Config) -> Result<&PathBuf> {
-    let unknown = UNKNOWN_PATH.get_or_try_init(|| {
-        let unknown_path = get_root_path(config)?.join("unknown");
-
-        Ok(unknown_path)
-    });
-    unknown
-}
-
-pub fn get_new_unknown_path(config: &Config) -> Result<&PathBuf> {
-    let new_unknown = NEW_UNKNOWN_PATH.get_or_try_init(|| {
-        let new_unknown_path = get_base_root_path(config)?.join("root").join("unknown");
-
-        Ok(new_unknown_path)
-    });
-    new_unknown
-}
-
-pub fn get_kotlin_path(config: &Config) -> Result<&PathBuf> {
-    let kotlin = KOTLIN_PATH.get_or_try_init(|| {
-        let kotlin_path = get_root_path(config)?.join("kotlin");
-
-        Ok(kotlin_path)
-    });
-    kotlin
-}
-
-pub fn get_new_kotlin_path(config: &Config) -> Result<&PathBuf> {
-    let new_kotlin = NEW_KOTLIN_PATH.get_or_try_init(|| {
-        let new_kotlin_path = get_base_root_path(config)?.join("kotlin");
-
-        Ok(new_kotlin_path)
-    });
-    new_kotlin
-}
-
-pub fn get_meta_path(config: &Config) -> Result<&PathBuf> {
-    let meta = META_PATH.get_or_try_init(|| {
-        let meta_path = get_root_path(config)?.join("original").join("META-INF");
-
-        Ok(meta_path)
-    });
-    meta
-}
-
-pub fn get_new_meta_path(config: &Config) -> Result<&PathBuf> {
-    let new_meta = NEW_META_PATH.get_or_try_init(|| {
-        let new_meta_path = get_base_root_path(config)?.join("root").join("META-INF");
-
-        Ok(new_meta_path)
-    });
-    new_meta
-}
-
-pub fn get_dex_path(config: &Config) -> Result<&PathBuf> {
-    let dex = DEX_PATH.get_or_try_init(|| {
-        let dex_path = get_base_dir_path(config)?.join("dex");
-
-        Ok(dex_path)
-    });
-    dex
-}
-
-pub fn get_base_apk_path(config: &Config) -> Result<&PathBuf> {
-    let base_apk = BASE_APK_PATH.get_or_try_init(|| {
-        let base_apk_path = get_root_path(config)?.join("base.apk");
-
-        Ok(base_apk_path)
-    });
-    base_apk
-}
-
-pub fn get_resources_zip_path(config: &Config) -> Result<&PathBuf> {
-    let resources = RESOURCES_ZIP_PATH.get_or_try_init(|| {
-        let resources_path = get_root_path(config)?.join("resources.zip");
-
-        Ok(resources_path)
-    });
-    resources
-}
-
-pub fn get_app_path(config: &Config) -> Result<&PathBuf> {
-    let app = APP_PATH.get_or_try_init(|| {
-        let app_path = Path::new(config.build_apk.app_path.as_str()).to_path_buf();
-
-        Ok(app_path)
-    });
-    app
-}
-
-pub fn get_apk_build_path(config: &Config) -> Result<&PathBuf> {
-    let build = APK_BUILD_PATH.get_or_try_init(|| {
-        let build_path = get_app_path(config)?.join("build");
-
-        Ok(build_path)
-    });
-    build
-}
-
-pub fn get_apk_dist_path(config: &Config) -> Result<&PathBuf> {
-    let dist = APK_DIST_PATH.get_or_try_init(|| {
-        let dist_path = get_app_path(config)?.join("dist");
-
-        Ok(dist_path)
-    });
-    dist
-}
-
-pub fn get_apk_un_sign_path<'a>(config: &'a Config, time: &'a str) -> Result<&'a PathBuf> {
-    let un_sign = APK_UN_SIGN_PATH.get_or_try_init(|| {
-        let un_sign_path = get_apk_dist_path(config)?.join(format!("{}_{}-unsign.apk", time, config.apk.app_name));
-
-        Ok(un_sign_path)
-    });
-    un_sign
-}
-
-pub fn get_apk_zipalign_path<'a>(config: &'a Config, time: &'a str) -> Result<&'a PathBuf> {
-    let zipalign = APK_ZIPALIGN_PATH.get_or_try_init(|| {
-        let zipalign_path = get_apk_dist_path(config)?.join(format!("{}_{}-zip.apk", time, config.apk.app_name));
-
-        Ok(zipalign_path)
-    });
-    zipalign
-}
-
-pub fn get_apk_sign_path<'a>(config: &'a Config, time: &'a str) -> Result<&'a PathBuf> {
-    let sign = APK_SIGN_PATH.get_or_try_init(|| {
-        let sign_path = get_apk_dist_path(config)?.join(format!("{}_{}-sign.apk", time, config.apk.app_name));
-
-        Ok(sign_path)
-    });
-    sign
+use crate::config::{Config, FeatureModule};
+use crate::split_apks;
+
+/// 由配置推导出的工作目录路径, 与构建时间戳无关.
+#[derive(Debug, Clone)]
+pub struct BuildLayout {
+    /// `apk_outdir`, apktool 反编译输出目录.
+    pub(crate) root: PathBuf,
+    pub(crate) res: PathBuf,
+    pub(crate) apks: PathBuf,
+    /// 传给 `build-bundle --modules` 的模块 zip, bundletool 以文件名作为模块名.
+    pub(crate) base_zip: PathBuf,
+    pub(crate) base_dir: PathBuf,
+    pub(crate) manifest: PathBuf,
+    pub(crate) assets: PathBuf,
+    pub(crate) new_assets: PathBuf,
+    pub(crate) lib: PathBuf,
+    pub(crate) new_lib: PathBuf,
+    pub(crate) base_root: PathBuf,
+    pub(crate) unknown: PathBuf,
+    pub(crate) new_unknown: PathBuf,
+    pub(crate) kotlin: PathBuf,
+    pub(crate) new_kotlin: PathBuf,
+    pub(crate) meta: PathBuf,
+    pub(crate) new_meta: PathBuf,
+    pub(crate) dex: PathBuf,
+    pub(crate) base_apk: PathBuf,
+    /// `apk_path` 为 split apk 集合时, 解压和反编译 split 的中间目录.
+    pub(crate) splits: PathBuf,
+    pub(crate) flat_dir: PathBuf,
+    pub(crate) flat_index: PathBuf,
+    pub(crate) flat_list: PathBuf,
+    /// `build_apk.app_path`, smali 工程目录.
+    pub(crate) app: PathBuf,
+    pub(crate) apk_build: PathBuf,
+    pub(crate) apk_dist: PathBuf,
+    pub(crate) cache_dir: PathBuf,
+    pub(crate) cache: PathBuf,
+    /// 生成的 BundleConfig.
+    pub(crate) bundle_config: PathBuf,
+    /// 加入 `<dist:module>` 后传给 `aapt2 link` 的 feature 模块 manifest.
+    pub(crate) feature_manifest: PathBuf,
+    /// `[[asset_pack]]` 的工作目录.
+    pub(crate) asset_packs: PathBuf,
+}
+
+impl BuildLayout {
+    pub fn new(config: &Config) -> Self {
+        BuildLayout::module(PathBuf::from(config.apk.apk_outdir.as_str()), "base", config)
+    }
+
+    /// `[[module]]` 中 feature 模块的工作目录.
+    pub(crate) fn feature(config: &Config, module: &FeatureModule) -> Self {
+        BuildLayout::module(PathBuf::from(module.outdir(config)), module.name.as_str(), config)
+    }
+
+    fn module(root: PathBuf, name: &str, config: &Config) -> Self {
+        let base_dir = root.join("base");
+        let base_root = base_dir.join("root");
+        let cache_dir = root.join(".abtool");
+        let app = PathBuf::from(config.build_apk.app_path.as_str());
+        BuildLayout {
+            res: root.join("res"),
+            apks: root.join("app.apks"),
+            base_zip: root.join(format!("{}.zip", name)),
+            manifest: base_dir.join("manifest"),
+            assets: root.join("assets"),
+            new_assets: base_dir.join("assets"),
+            lib: root.join("lib"),
+            new_lib: base_dir.join("lib"),
+            unknown: root.join("unknown"),
+            new_unknown: base_root.join("root").join("unknown"),
+            kotlin: root.join("kotlin"),
+            new_kotlin: base_root.join("kotlin"),
+            meta: root.join("original").join("META-INF"),
+            new_meta: base_root.join("root").join("META-INF"),
+            dex: base_dir.join("dex"),
+            base_apk: root.join("base.apk"),
+            splits: PathBuf::from(format!("{}.splits", root.to_string_lossy())),
+            flat_dir: root.join("res_flat"),
+            flat_index: cache_dir.join("flat.toml"),
+            flat_list: cache_dir.join("flat.list"),
+            apk_build: app.join("build"),
+            apk_dist: app.join("dist"),
+            cache: cache_dir.join("cache.toml"),
+            bundle_config: cache_dir.join("bundle_config.json"),
+            feature_manifest: cache_dir.join("AndroidManifest.xml"),
+            asset_packs: cache_dir.join("asset_packs"),
+            root,
+            base_dir,
+            base_root,
+            app,
+            cache_dir,
+        }
+    }
+}
+
+/// 一次构建的上下文: 配置, 时间戳以及由两者推导出的路径.
+/// 每次构建单独创建, 同一进程中可以用不同的配置多次构建.
+pub struct BuildContext<'a> {
+    pub(crate) config: &'a Config,
+    pub(crate) time: String,
+    pub(crate) layout: BuildLayout,
+    /// aab 中的全部模块, 第一个为 base.
+    pub(crate) modules: Vec<Module<'a>>,
+    pub(crate) aab: PathBuf,
+    pub(crate) apk_un_sign: PathBuf,
+    pub(crate) apk_zipalign: PathBuf,
+    pub(crate) apk_sign: PathBuf,
+}
+
+impl<'a> BuildContext<'a> {
+    pub fn new(config: &'a Config, time: &str) -> Self {
+        let layout = BuildLayout::new(config);
+        let app_name = config.apk.app_name.as_str();
+        let mut modules = vec![Module { feature: None, layout: layout.clone() }];
+        modules.extend(config.module.iter().map(|feature| Module { feature: Some(feature), layout: BuildLayout::feature(config, feature) }));
+        BuildContext {
+            config,
+            time: time.to_string(),
+            modules,
+            aab: layout.root.join(format!("{}{}.aab", time, app_name)),
+            apk_un_sign: layout.apk_dist.join(format!("{}_{}-unsign.apk", time, app_name)),
+            apk_zipalign: layout.apk_dist.join(format!("{}_{}-zip.apk", time, app_name)),
+            apk_sign: layout.apk_dist.join(format!("{}_{}-sign.apk", time, app_name)),
+            layout,
+        }
+    }
+
+    pub fn config(&self) -> &Config {
+        self.config
+    }
+
+    pub fn time(&self) -> &str {
+        self.time.as_str()
+    }
+
+    /// feature 模块链接资源时引用的 base apk: `apk_path` 为 split apk 集合时是反编译时保存的 base split,
+    /// 否则为 `apk_path` 本身.
+    pub(crate) fn original_base_apk(&self) -> PathBuf {
+        let apk_path = Path::new(self.config.apk.apk_path.as_str());
+        if split_apks::is_split_set(apk_path) {
+            split_apks::base_copy(self.layout.splits.as_path())
+        } else {
+            apk_path.to_path_buf()
+        }
+    }
+
+    /// 本次构建的 aab 路径.
+    pub fn aab_path(&self) -> &Path {
+        self.aab.as_path()
+    }
+
+    /// 本次构建签名后的 apk 路径.
+    pub fn apk_sign_path(&self) -> &Path {
+        self.apk_sign.as_path()
+    }
+}
+
+/// aab 中的一个模块及其工作目录, base 模块的 `feature` 为 None.
+pub(crate) struct Module<'a> {
+    pub(crate) feature: Option<&'a FeatureModule>,
+    pub(crate) layout: BuildLayout,
+}
+
+impl Module<'_> {
+    pub(crate) fn name(&self) -> &str {
+        self.feature.map_or("base", |feature| feature.name.as_str())
+    }
+
+    /// 反编译的 apk.
+    pub(crate) fn apk_path<'c>(&'c self, config: &'c Config) -> &'c str {
+        self.feature.map_or(config.apk.apk_path.as_str(), |feature| feature.apk_path.as_str())
+    }
+}
+
+/// 在 `dir` 中查找以 `suffix` 结尾的最新文件, 返回去掉后缀的时间戳部分.
+pub fn find_latest_time(dir: &Path, suffix: &str) -> Result<Option<String>> {
+    if !dir.exists() {
+        return Ok(None);
+    }
+    let mut latest: Option<(SystemTime, String)> = None;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        let time = match name.strip_suffix(suffix) {
+            Some(time) => time.to_string(),
+            None => continue,
+        };
+        let modified = entry.metadata()?.modified()?;
+        if latest.as_ref().is_none_or(|(latest_modified, _)| modified > *latest_modified) {
+            latest = Some((modified, time));
+        }
+    }
+    Ok(latest.map(|(_, time)| time))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::config;
+
+    #[test]
+    fn contexts_do_not_share_paths() {
+        let mut first = config();
+        first.apk.apk_outdir = "out1".to_string();
+        let mut second = config();
+        second.apk.apk_outdir = "out2".to_string();
+        let a = BuildContext::new(&first, "20240101_1");
+        let b = BuildContext::new(&second, "20240102_1");
+
+        assert_eq!(a.layout.root, Path::new("out1"));
+        assert_eq!(b.layout.root, Path::new("out2"));
+        assert_eq!(a.aab, Path::new("out1/20240101_1demo.aab"));
+        assert_eq!(b.aab, Path::new("out2/20240102_1demo.aab"));
+        assert_eq!(a.apk_sign, Path::new("app/dist/20240101_1_demo-sign.apk"));
+        assert_eq!(b.apk_sign, Path::new("app/dist/20240102_1_demo-sign.apk"));
+        assert_eq!(a.modules[0].layout.cache, Path::new("out1/.abtool/cache.toml"));
+        assert_eq!(b.modules[0].layout.cache, Path::new("out2/.abtool/cache.toml"));
+        assert_eq!((a.time(), b.time()), ("20240101_1", "20240102_1"));
+    }
 }
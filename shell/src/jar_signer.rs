@@ -0,0 +1,364 @@
+use std::fs::{self, File};
+use std::io::{Read, Seek, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use cms::cert::{CertificateChoices, IssuerAndSerialNumber};
+use cms::content_info::{CmsVersion, ContentInfo};
+use cms::signed_data::{CertificateSet, EncapsulatedContentInfo, SignedData, SignerIdentifier, SignerInfo, SignerInfos};
+use der::asn1::{ObjectIdentifier, OctetString, SetOfVec};
+use der::{Any, Decode, Encode};
+use x509_cert::spki::AlgorithmIdentifierOwned;
+use x509_cert::Certificate;
+use zip::write::FileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+
+use crate::config::Sign;
+use crate::keystore::{DigestAlgorithm, KeyEntry, PrivateKey};
+
+pub(crate) const MANIFEST_NAME: &str = "META-INF/MANIFEST.MF";
+
+const ID_DATA: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113549.1.7.1");
+const ID_SIGNED_DATA: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113549.1.7.2");
+const ID_RSA_ENCRYPTION: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113549.1.1.1");
+
+/// manifest 每行最多 72 字节, 超出部分以空格开头续行.
+const LINE_LENGTH: usize = 72;
+const CREATED_BY: &str = "1.0 (Android)";
+
+/// 对应 jarsigner 的 `-digestalg` 和 `-sigalg`.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct JarAlgorithms {
+    /// manifest 和 `.SF` 中条目摘要使用的算法.
+    pub(crate) digest: DigestAlgorithm,
+    /// 对 `.SF` 签名时使用的摘要算法.
+    pub(crate) signature: DigestAlgorithm,
+}
+
+impl JarAlgorithms {
+    pub(crate) const SHA256: JarAlgorithms = JarAlgorithms { digest: DigestAlgorithm::Sha256, signature: DigestAlgorithm::Sha256 };
+
+    /// 读取 `[sign]` 中的 `digest_algorithm` 和 `signature_algorithm`, 默认均为 SHA-256,
+    /// 签名算法的密钥部分必须与 keystore 中的密钥类型一致.
+    pub(crate) fn from_config(sign: &Sign, key: &PrivateKey) -> Result<Self> {
+        let digest = match &sign.digest_algorithm {
+            Some(name) => DigestAlgorithm::from_str(name)?,
+            None => DigestAlgorithm::Sha256,
+        };
+        let signature = match &sign.signature_algorithm {
+            Some(name) => {
+                let (digest, key_algorithm) = name.split_once("with")
+                    .ok_or_else(|| anyhow!("invalid signature algorithm `{}`, expected e.g. SHA256with{}", name, key.algorithm_name()))?;
+                if !key_algorithm.eq_ignore_ascii_case(key.algorithm_name()) {
+                    return Err(anyhow!("signature algorithm `{}` does not match the {} key", name, key.algorithm_name()));
+                }
+                DigestAlgorithm::from_str(digest)?
+            }
+            None => DigestAlgorithm::Sha256,
+        };
+        Ok(JarAlgorithms { digest, signature })
+    }
+}
+
+/// JAR 签名(v1)生成的 `META-INF` 文件, 按写入顺序排列.
+pub(crate) struct JarSignature {
+    pub(crate) files: Vec<(String, Vec<u8>)>,
+}
+
+/// 是否为需要在重新签名时丢弃的 manifest 或签名文件.
+pub(crate) fn is_signature_entry(name: &str) -> bool {
+    let Some(file) = name.strip_prefix("META-INF/") else {
+        return false;
+    };
+    if file.contains('/') {
+        return false;
+    }
+    let upper = file.to_ascii_uppercase();
+    upper == "MANIFEST.MF"
+        || upper.starts_with("SIG-")
+        || [".SF", ".RSA", ".DSA", ".EC"].iter().any(|ext| upper.ends_with(ext))
+}
+
+/// 对 `input` 进行 JAR 签名后写入 `output`, 两者相同时原地签名. 用于 aab.
+pub(crate) fn sign_file(input: &Path, output: &Path, signer: &KeyEntry, algorithms: JarAlgorithms) -> Result<()> {
+    let mut archive = ZipArchive::new(File::open(input)?)?;
+    let signature = sign(&mut archive, signer, algorithms, &[])?;
+
+    let temp = output.with_extension("signing");
+    let mut writer = ZipWriter::new(File::create(temp.as_path())?);
+    let options = FileOptions::default().compression_method(CompressionMethod::Deflated);
+    for (name, data) in &signature.files {
+        writer.start_file(name.as_str(), options)?;
+        writer.write_all(data)?;
+    }
+    for i in 0..archive.len() {
+        let file = archive.by_index_raw(i)?;
+        if !is_signature_entry(file.name()) {
+            writer.raw_copy_file(file)?;
+        }
+    }
+    writer.finish()?;
+    fs::rename(temp, output)?;
+    Ok(())
+}
+
+/// 对 `archive` 中除签名文件外的所有条目计算摘要并签名.
+/// `attributes` 会写入 `.SF` 的主属性, 如 apk 的 `X-Android-APK-Signed`.
+pub(crate) fn sign<R: Read + Seek>(archive: &mut ZipArchive<R>, signer: &KeyEntry, algorithms: JarAlgorithms, attributes: &[(&str, &str)]) -> Result<JarSignature> {
+    let digest_name = format!("{}-Digest", algorithms.digest.name());
+    let mut names: Vec<String> = archive.file_names()
+        .filter(|name| !name.ends_with('/') && !is_signature_entry(name))
+        .map(|name| name.to_string())
+        .collect();
+    names.sort();
+
+    let mut manifest = Vec::new();
+    attribute(&mut manifest, "Manifest-Version", "1.0");
+    attribute(&mut manifest, "Created-By", CREATED_BY);
+    manifest.extend(b"\r\n");
+
+    let mut sections = Vec::with_capacity(names.len());
+    for name in &names {
+        let mut data = Vec::new();
+        archive.by_name(name)?.read_to_end(&mut data)?;
+        let mut section = Vec::new();
+        attribute(&mut section, "Name", name);
+        attribute(&mut section, digest_name.as_str(), STANDARD.encode(algorithms.digest.digest(&data)).as_str());
+        section.extend(b"\r\n");
+        manifest.extend(&section);
+        sections.push(section);
+    }
+
+    let mut signature_file = Vec::new();
+    attribute(&mut signature_file, "Signature-Version", "1.0");
+    attribute(&mut signature_file, "Created-By", CREATED_BY);
+    attribute(&mut signature_file, format!("{}-Manifest", digest_name).as_str(), STANDARD.encode(algorithms.digest.digest(&manifest)).as_str());
+    for (name, value) in attributes {
+        attribute(&mut signature_file, name, value);
+    }
+    signature_file.extend(b"\r\n");
+    for (name, section) in names.iter().zip(&sections) {
+        attribute(&mut signature_file, "Name", name);
+        attribute(&mut signature_file, digest_name.as_str(), STANDARD.encode(algorithms.digest.digest(section)).as_str());
+        signature_file.extend(b"\r\n");
+    }
+
+    let block = signature_block(signer, algorithms.signature, &signature_file)?;
+    let base = signature_base_name(signer.alias.as_str());
+    let extension = if signer.key.is_rsa() { "RSA" } else { "EC" };
+    Ok(JarSignature {
+        files: vec![
+            (MANIFEST_NAME.to_string(), manifest),
+            (format!("META-INF/{}.SF", base), signature_file),
+            (format!("META-INF/{}.{}", base, extension), block),
+        ],
+    })
+}
+
+/// 与 jarsigner 一致: 别名转大写, 非字母数字替换为 `_`, 最多 8 个字符.
+fn signature_base_name(alias: &str) -> String {
+    let name: String = alias.chars()
+        .take(8)
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c.to_ascii_uppercase() } else { '_' })
+        .collect();
+    if name.is_empty() {
+        "CERT".to_string()
+    } else {
+        name
+    }
+}
+
+fn attribute(out: &mut Vec<u8>, name: &str, value: &str) {
+    let line = format!("{}: {}", name, value);
+    let mut rest = line.as_bytes();
+    let mut first = true;
+    while !rest.is_empty() {
+        let max = if first { LINE_LENGTH } else { LINE_LENGTH - 1 };
+        let (head, tail) = rest.split_at(max.min(rest.len()));
+        if !first {
+            out.push(b' ');
+        }
+        out.extend(head);
+        out.extend(b"\r\n");
+        rest = tail;
+        first = false;
+    }
+}
+
+/// 不带签名属性的 PKCS#7 SignedData, 签名内容为 `.SF` 文件本身.
+fn signature_block(signer: &KeyEntry, digest: DigestAlgorithm, signature_file: &[u8]) -> Result<Vec<u8>> {
+    let der = signer.certificates.first().ok_or_else(|| anyhow!("key `{}` has no certificate", signer.alias))?;
+    let certificate = Certificate::from_der(der)?;
+    let digest_alg = AlgorithmIdentifierOwned { oid: digest.oid(), parameters: None };
+    let signature_algorithm = match signer.key {
+        PrivateKey::Rsa(_) => AlgorithmIdentifierOwned { oid: ID_RSA_ENCRYPTION, parameters: Some(Any::null()) },
+        PrivateKey::Ec(_) => AlgorithmIdentifierOwned { oid: ecdsa_oid(digest), parameters: None },
+    };
+
+    let signer_info = SignerInfo {
+        version: CmsVersion::V1,
+        sid: SignerIdentifier::IssuerAndSerialNumber(IssuerAndSerialNumber {
+            issuer: certificate.tbs_certificate.issuer.clone(),
+            serial_number: certificate.tbs_certificate.serial_number.clone(),
+        }),
+        digest_alg: digest_alg.clone(),
+        signed_attrs: None,
+        signature_algorithm,
+        signature: OctetString::new(signer.key.sign(digest, signature_file)?)?,
+        unsigned_attrs: None,
+    };
+
+    let mut certificates = SetOfVec::new();
+    for der in &signer.certificates {
+        certificates.insert(CertificateChoices::Certificate(Certificate::from_der(der)?))?;
+    }
+    let signed_data = SignedData {
+        version: CmsVersion::V1,
+        digest_algorithms: SetOfVec::try_from(vec![digest_alg])?,
+        encap_content_info: EncapsulatedContentInfo { econtent_type: ID_DATA, econtent: None },
+        certificates: Some(CertificateSet(certificates)),
+        crls: None,
+        signer_infos: SignerInfos(SetOfVec::try_from(vec![signer_info])?),
+    };
+    let content_info = ContentInfo { content_type: ID_SIGNED_DATA, content: Any::encode_from(&signed_data)? };
+    Ok(content_info.to_der()?)
+}
+
+fn ecdsa_oid(digest: DigestAlgorithm) -> ObjectIdentifier {
+    match digest {
+        DigestAlgorithm::Sha1 => ObjectIdentifier::new_unwrap("1.2.840.10045.4.1"),
+        DigestAlgorithm::Sha256 => ObjectIdentifier::new_unwrap("1.2.840.10045.4.3.2"),
+        DigestAlgorithm::Sha384 => ObjectIdentifier::new_unwrap("1.2.840.10045.4.3.3"),
+        DigestAlgorithm::Sha512 => ObjectIdentifier::new_unwrap("1.2.840.10045.4.3.4"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::keystore::tests::{pkcs12_path, testdata, verify, STORE_PASS};
+    use crate::keystore::{self, KeyEntry};
+
+    fn archive(entries: &[(&str, &[u8])]) -> ZipArchive<Cursor<Vec<u8>>> {
+        let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, data) in entries {
+            writer.start_file(*name, FileOptions::default()).unwrap();
+            writer.write_all(data).unwrap();
+        }
+        ZipArchive::new(Cursor::new(writer.finish().unwrap().into_inner())).unwrap()
+    }
+
+    fn signer() -> KeyEntry {
+        keystore::load(pkcs12_path().as_path(), STORE_PASS, "abtool", STORE_PASS).unwrap()
+    }
+
+    fn file<'a>(signature: &'a JarSignature, name: &str) -> &'a [u8] {
+        &signature.files.iter().find(|(n, _)| n == name).unwrap().1
+    }
+
+    fn text(data: &[u8]) -> &str {
+        std::str::from_utf8(data).unwrap()
+    }
+
+    /// `.SF` 主属性中的值.
+    fn main_attribute<'a>(signature_file: &'a str, name: &str) -> &'a str {
+        let main = signature_file.split("\r\n\r\n").next().unwrap();
+        main.lines().find_map(|line| line.strip_prefix(format!("{}: ", name).as_str())).unwrap()
+    }
+
+    #[test]
+    fn signature_file_digests_manifest() {
+        let long_name = format!("assets/{}.txt", "a".repeat(80));
+        let mut archive = archive(&[
+            ("classes.dex", b"dex"),
+            (long_name.as_str(), b"long"),
+            ("META-INF/CERT.RSA", b"old"),
+            ("AndroidManifest.xml", b"<manifest/>"),
+        ]);
+        let signature = sign(&mut archive, &signer(), JarAlgorithms::SHA256, &[("X-Android-APK-Signed", "2, 3")]).unwrap();
+        let names: Vec<&str> = signature.files.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, [MANIFEST_NAME, "META-INF/ABTOOL.SF", "META-INF/ABTOOL.RSA"]);
+
+        let manifest = file(&signature, MANIFEST_NAME);
+        let signature_file = text(file(&signature, "META-INF/ABTOOL.SF"));
+        assert_eq!(main_attribute(signature_file, "SHA-256-Digest-Manifest"), STANDARD.encode(DigestAlgorithm::Sha256.digest(manifest)));
+        assert_eq!(main_attribute(signature_file, "X-Android-APK-Signed"), "2, 3");
+        assert_eq!(main_attribute(signature_file, "Signature-Version"), "1.0");
+
+        // 条目按名称排序, 旧签名文件不参与摘要, 长行以空格续行
+        let manifest = text(manifest);
+        assert!(manifest.starts_with("Manifest-Version: 1.0\r\nCreated-By: 1.0 (Android)\r\n\r\nName: AndroidManifest.xml\r\n"));
+        assert!(!manifest.contains("CERT.RSA"));
+        assert!(manifest.lines().all(|line| line.len() <= LINE_LENGTH));
+        assert!(manifest.replace("\r\n ", "").contains(format!("Name: {}\r\n", long_name).as_str()));
+        assert!(manifest.contains(format!("Name: classes.dex\r\nSHA-256-Digest: {}\r\n", STANDARD.encode(DigestAlgorithm::Sha256.digest(b"dex"))).as_str()));
+
+        // `.SF` 中每个条目的摘要是 manifest 中对应段落的摘要
+        for section in manifest.split("\r\n\r\n").skip(1).filter(|section| !section.is_empty()) {
+            let section = format!("{}\r\n\r\n", section);
+            let name_line = section.split("\r\nSHA-256-Digest").next().unwrap();
+            let expected = format!("{}\r\nSHA-256-Digest: {}\r\n", name_line, STANDARD.encode(DigestAlgorithm::Sha256.digest(section.as_bytes())));
+            assert!(signature_file.contains(expected.as_str()), "{}", name_line);
+        }
+    }
+
+    #[test]
+    fn signature_block_verifies() {
+        for (signer, extension) in [
+            (signer(), "RSA"),
+            (keystore::load(testdata("abtool.jks").as_path(), STORE_PASS, "ec", "ec-key").unwrap(), "EC"),
+        ] {
+            let mut archive = archive(&[("classes.dex", b"dex")]);
+            let signature = sign(&mut archive, &signer, JarAlgorithms::SHA256, &[]).unwrap();
+            let base = signature_base_name(signer.alias.as_str());
+            let signature_file = file(&signature, format!("META-INF/{}.SF", base).as_str());
+            let block = file(&signature, format!("META-INF/{}.{}", base, extension).as_str());
+
+            let content_info = ContentInfo::from_der(block).unwrap();
+            assert_eq!(content_info.content_type, ID_SIGNED_DATA);
+            let signed_data = content_info.content.decode_as::<SignedData>().unwrap();
+            let signer_info = signed_data.signer_infos.0.get(0).unwrap();
+            assert_eq!(signer_info.digest_alg.oid, DigestAlgorithm::Sha256.oid());
+            verify(&signer.certificates[0], DigestAlgorithm::Sha256, signature_file, signer_info.signature.as_bytes());
+        }
+    }
+
+    #[test]
+    fn base_name_like_jarsigner() {
+        assert_eq!(signature_base_name("abtool"), "ABTOOL");
+        assert_eq!(signature_base_name("my release key"), "MY_RELEA");
+        assert_eq!(signature_base_name("key-1_a"), "KEY-1_A");
+        assert_eq!(signature_base_name(""), "CERT");
+    }
+
+    #[test]
+    fn detect_signature_entries() {
+        for name in ["META-INF/MANIFEST.MF", "META-INF/CERT.SF", "META-INF/cert.rsa", "META-INF/KEY.EC", "META-INF/SIG-A"] {
+            assert!(is_signature_entry(name), "{}", name);
+        }
+        for name in ["META-INF/services/a.SF", "META-INF/com/android/build/gradle/app-metadata.properties", "MANIFEST.MF", "assets/CERT.RSA"] {
+            assert!(!is_signature_entry(name), "{}", name);
+        }
+    }
+
+    #[test]
+    fn algorithms_from_config() {
+        let key = signer().key;
+        let mut sign = Sign::new("abtool.jks", crate::config::Secret::new(STORE_PASS), "abtool", crate::config::Secret::new(STORE_PASS));
+        let algorithms = JarAlgorithms::from_config(&sign, &key).unwrap();
+        assert_eq!((algorithms.digest, algorithms.signature), (DigestAlgorithm::Sha256, DigestAlgorithm::Sha256));
+
+        sign.digest_algorithm = Some("SHA-1".to_string());
+        sign.signature_algorithm = Some("SHA512withRSA".to_string());
+        let algorithms = JarAlgorithms::from_config(&sign, &key).unwrap();
+        assert_eq!((algorithms.digest, algorithms.signature), (DigestAlgorithm::Sha1, DigestAlgorithm::Sha512));
+
+        sign.signature_algorithm = Some("SHA256withECDSA".to_string());
+        assert!(JarAlgorithms::from_config(&sign, &key).is_err());
+    }
+}
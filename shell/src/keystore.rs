@@ -0,0 +1,709 @@
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::{anyhow, Context, Result};
+use cbc::cipher::block_padding::Pkcs7;
+use cbc::cipher::{BlockDecryptMut, InnerIvInit, KeyIvInit};
+use cms::content_info::ContentInfo;
+use cms::encrypted_data::EncryptedData;
+use der::asn1::{BmpString, ContextSpecific, ObjectIdentifier, OctetString};
+use der::{Decode, Encode};
+use hmac::{Mac, SimpleHmac};
+use md5::Md5;
+use pkcs12::cert_type::CertBag;
+use pkcs12::kdf::{derive_key_utf8, Pkcs12KeyType};
+use pkcs12::mac_data::MacData;
+use pkcs12::pbe_params::{EncryptedPrivateKeyInfo, Pkcs12PbeParams};
+use pkcs12::pfx::Pfx;
+use pkcs12::safe_bag::{SafeBag, SafeContents};
+use pkcs8::DecodePrivateKey;
+use rsa::{Pkcs1v15Sign, RsaPrivateKey};
+use sha1::Sha1;
+use sha2::digest::core_api::BlockSizeUser;
+use sha2::digest::FixedOutputReset;
+use sha2::{Digest, Sha256, Sha384, Sha512};
+use signature::hazmat::PrehashSigner;
+use signature::SignatureEncoding;
+use tracing::debug;
+use x509_cert::spki::AlgorithmIdentifierOwned;
+
+const JKS_MAGIC: u32 = 0xfeed_feed;
+/// JKS 文件完整性校验使用的固定字符串.
+const JKS_WHITENER: &[u8] = b"Mighty Aphrodite";
+const JKS_PRIVATE_KEY: u32 = 1;
+const JKS_TRUSTED_CERT: u32 = 2;
+const JCEKS_MAGIC: u32 = 0xcece_cece;
+const JCEKS_SECRET_KEY: u32 = 3;
+
+const ID_DATA: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113549.1.7.1");
+const ID_ENCRYPTED_DATA: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113549.1.7.6");
+const ID_PBES2: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113549.1.5.13");
+const ID_SHA1: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.3.14.3.2.26");
+const ID_SHA256: ObjectIdentifier = ObjectIdentifier::new_unwrap("2.16.840.1.101.3.4.2.1");
+const ID_RSA: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113549.1.1.1");
+const ID_EC: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.10045.2.1");
+const ID_JKS_KEY_PROTECTOR: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.3.6.1.4.1.42.2.17.1.1");
+const ID_JCEKS_KEY_PROTECTOR: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.3.6.1.4.1.42.2.19.1");
+const ID_FRIENDLY_NAME: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113549.1.9.20");
+const ID_LOCAL_KEY_ID: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113549.1.9.21");
+
+/// 签名使用的私钥, 目前支持 RSA 和 EC P-256.
+pub(crate) enum PrivateKey {
+    Rsa(Box<RsaPrivateKey>),
+    Ec(p256::ecdsa::SigningKey),
+}
+
+impl PrivateKey {
+    fn from_pkcs8(der: &[u8]) -> Result<Self> {
+        let info = pkcs8::PrivateKeyInfo::from_der(der)?;
+        if info.algorithm.oid == ID_RSA {
+            Ok(PrivateKey::Rsa(Box::new(RsaPrivateKey::from_pkcs8_der(der)?)))
+        } else if info.algorithm.oid == ID_EC {
+            let key = p256::SecretKey::from_pkcs8_der(der).map_err(|_| anyhow!("unsupported EC key, only P-256 is supported"))?;
+            Ok(PrivateKey::Ec(key.into()))
+        } else {
+            Err(anyhow!("unsupported key algorithm {}", info.algorithm.oid))
+        }
+    }
+
+    /// 使用 `digest` 摘要签名: RSA 为 PKCS#1 v1.5, EC 为 DER 编码的 ECDSA.
+    pub(crate) fn sign(&self, digest: DigestAlgorithm, data: &[u8]) -> Result<Vec<u8>> {
+        let hash = digest.digest(data);
+        match self {
+            PrivateKey::Rsa(key) => {
+                let scheme = match digest {
+                    DigestAlgorithm::Sha1 => Pkcs1v15Sign::new::<Sha1>(),
+                    DigestAlgorithm::Sha256 => Pkcs1v15Sign::new::<Sha256>(),
+                    DigestAlgorithm::Sha384 => Pkcs1v15Sign::new::<Sha384>(),
+                    DigestAlgorithm::Sha512 => Pkcs1v15Sign::new::<Sha512>(),
+                };
+                Ok(key.sign(scheme, &hash)?)
+            }
+            PrivateKey::Ec(key) => {
+                let signature: p256::ecdsa::Signature = key.sign_prehash(&hash)?;
+                Ok(signature.to_der().to_vec())
+            }
+        }
+    }
+
+    pub(crate) fn is_rsa(&self) -> bool {
+        matches!(self, PrivateKey::Rsa(_))
+    }
+
+    /// 与 Java 签名算法名称中的密钥部分一致, 如 `SHA256withRSA` 中的 `RSA`.
+    pub(crate) fn algorithm_name(&self) -> &'static str {
+        match self {
+            PrivateKey::Rsa(_) => "RSA",
+            PrivateKey::Ec(_) => "ECDSA",
+        }
+    }
+}
+
+/// 签名使用的摘要算法, 名称与 jarsigner 的 `-digestalg` 一致.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum DigestAlgorithm {
+    Sha1,
+    Sha256,
+    Sha384,
+    Sha512,
+}
+
+impl DigestAlgorithm {
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            DigestAlgorithm::Sha1 => "SHA1",
+            DigestAlgorithm::Sha256 => "SHA-256",
+            DigestAlgorithm::Sha384 => "SHA-384",
+            DigestAlgorithm::Sha512 => "SHA-512",
+        }
+    }
+
+    pub(crate) fn oid(&self) -> ObjectIdentifier {
+        match self {
+            DigestAlgorithm::Sha1 => ID_SHA1,
+            DigestAlgorithm::Sha256 => ID_SHA256,
+            DigestAlgorithm::Sha384 => ObjectIdentifier::new_unwrap("2.16.840.1.101.3.4.2.2"),
+            DigestAlgorithm::Sha512 => ObjectIdentifier::new_unwrap("2.16.840.1.101.3.4.2.3"),
+        }
+    }
+
+    pub(crate) fn digest(&self, data: &[u8]) -> Vec<u8> {
+        match self {
+            DigestAlgorithm::Sha1 => Sha1::digest(data).to_vec(),
+            DigestAlgorithm::Sha256 => Sha256::digest(data).to_vec(),
+            DigestAlgorithm::Sha384 => Sha384::digest(data).to_vec(),
+            DigestAlgorithm::Sha512 => Sha512::digest(data).to_vec(),
+        }
+    }
+}
+
+impl FromStr for DigestAlgorithm {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_uppercase().replace('-', "").as_str() {
+            "SHA1" => Ok(DigestAlgorithm::Sha1),
+            "SHA256" => Ok(DigestAlgorithm::Sha256),
+            "SHA384" => Ok(DigestAlgorithm::Sha384),
+            "SHA512" => Ok(DigestAlgorithm::Sha512),
+            _ => Err(anyhow!("unsupported digest algorithm `{}`, expected one of: SHA1, SHA-256, SHA-384, SHA-512", s)),
+        }
+    }
+}
+
+/// keystore 中的一个私钥条目, `certificates` 第一个为签名证书(DER).
+pub(crate) struct KeyEntry {
+    pub(crate) alias: String,
+    pub(crate) key: PrivateKey,
+    pub(crate) certificates: Vec<Vec<u8>>,
+}
+
+/// keystore 文件格式, 按文件头识别.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum KeystoreFormat {
+    Jks,
+    Jceks,
+    Pkcs12,
+}
+
+impl KeystoreFormat {
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            KeystoreFormat::Jks => "JKS",
+            KeystoreFormat::Jceks => "JCEKS",
+            KeystoreFormat::Pkcs12 => "PKCS12",
+        }
+    }
+}
+
+/// 条目类型, 名称与 `keytool -list` 一致.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum EntryKind {
+    PrivateKey,
+    TrustedCertificate,
+}
+
+impl EntryKind {
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            EntryKind::PrivateKey => "PrivateKeyEntry",
+            EntryKind::TrustedCertificate => "trustedCertEntry",
+        }
+    }
+}
+
+struct Entry {
+    /// PKCS#12 的私钥可能没有别名, 此时为空.
+    alias: String,
+    /// 仍需 key 密码解密的私钥, 证书条目为 None.
+    key: Option<EncryptedPrivateKeyInfo>,
+    certificates: Vec<Vec<u8>>,
+}
+
+/// 已通过 store 密码校验的 keystore, 私钥在 [`Keystore::key`] 中才用 key 密码解密.
+pub(crate) struct Keystore {
+    pub(crate) format: KeystoreFormat,
+    entries: Vec<Entry>,
+}
+
+impl Keystore {
+    /// 读取 JKS, JCEKS 或 PKCS#12 keystore, store 密码错误时返回错误.
+    pub(crate) fn open(path: &Path, store_pass: &str) -> Result<Self> {
+        let data = fs::read(path).with_context(|| format!("read keystore {}", path.to_string_lossy()))?;
+        let format = if data.starts_with(&JKS_MAGIC.to_be_bytes()) {
+            KeystoreFormat::Jks
+        } else if data.starts_with(&JCEKS_MAGIC.to_be_bytes()) {
+            KeystoreFormat::Jceks
+        } else {
+            KeystoreFormat::Pkcs12
+        };
+        debug!("load {} keystore {}", format.name().to_lowercase(), path.to_string_lossy());
+        let entries = match format {
+            KeystoreFormat::Jks | KeystoreFormat::Jceks => read_jks(&data, store_pass)?,
+            KeystoreFormat::Pkcs12 => read_pkcs12(&data, store_pass)?,
+        };
+        Ok(Keystore { format, entries })
+    }
+
+    /// 所有条目的别名和类型, 按文件中的顺序排列.
+    pub(crate) fn entries(&self) -> impl Iterator<Item = (&str, EntryKind)> {
+        self.entries.iter().map(|entry| {
+            let kind = if entry.key.is_some() { EntryKind::PrivateKey } else { EntryKind::TrustedCertificate };
+            (entry.alias.as_str(), kind)
+        })
+    }
+
+    /// 私钥条目的别名.
+    pub(crate) fn aliases(&self) -> Vec<&str> {
+        self.entries()
+            .filter(|(_, kind)| *kind == EntryKind::PrivateKey)
+            .map(|(alias, _)| alias)
+            .collect()
+    }
+
+    /// 用 `key_pass` 解密 `alias` 对应的私钥, 别名不区分大小写.
+    pub(crate) fn key(&self, alias: &str, key_pass: &str) -> Result<KeyEntry> {
+        let keys: Vec<&Entry> = self.entries.iter().filter(|entry| entry.key.is_some()).collect();
+        // 只有一个私钥且没有别名时直接使用
+        let entry = keys.iter()
+            .find(|entry| entry.alias.eq_ignore_ascii_case(alias))
+            .or_else(|| (keys.len() == 1 && keys[0].alias.is_empty()).then_some(&keys[0]))
+            .ok_or_else(|| anyhow!("alias `{}` not found, keystore has: {}", alias, self.aliases().join(", ")))?;
+        let info = entry.key.as_ref().ok_or_else(|| anyhow!("`{}` is not a private key entry", alias))?;
+        let key = decrypt_key(info, key_pass)?;
+        Ok(KeyEntry {
+            alias: if entry.alias.is_empty() { alias.to_string() } else { entry.alias.clone() },
+            key: PrivateKey::from_pkcs8(&key)?,
+            certificates: entry.certificates.clone(),
+        })
+    }
+}
+
+/// 读取 keystore 中 `alias` 对应的私钥和证书链.
+pub(crate) fn load(path: &Path, store_pass: &str, alias: &str, key_pass: &str) -> Result<KeyEntry> {
+    Keystore::open(path, store_pass)?.key(alias, key_pass)
+}
+
+/// JKS 与 JCEKS 的文件结构相同, 只是私钥保护算法不同.
+fn read_jks(data: &[u8], store_pass: &str) -> Result<Vec<Entry>> {
+    if data.len() < 20 {
+        return Err(anyhow!("keystore is truncated"));
+    }
+    let (body, digest) = data.split_at(data.len() - 20);
+    let mut hash = Sha1::new();
+    hash.update(utf16_be(store_pass));
+    hash.update(JKS_WHITENER);
+    hash.update(body);
+    if hash.finalize().as_slice() != digest {
+        return Err(anyhow!("keystore password was incorrect"));
+    }
+
+    let mut reader = Reader::new(body);
+    reader.u32()?;
+    let version = reader.u32()?;
+    let count = reader.u32()?;
+    let mut entries = Vec::new();
+    for _ in 0..count {
+        let tag = reader.u32()?;
+        let alias = reader.utf()?;
+        reader.u64()?;
+        match tag {
+            JKS_PRIVATE_KEY => {
+                let key = EncryptedPrivateKeyInfo::from_der(reader.bytes()?)?;
+                let mut certificates = Vec::new();
+                for _ in 0..reader.u32()? {
+                    if version == 2 {
+                        reader.utf()?;
+                    }
+                    certificates.push(reader.bytes()?.to_vec());
+                }
+                entries.push(Entry { alias, key: Some(key), certificates });
+            }
+            JKS_TRUSTED_CERT => {
+                if version == 2 {
+                    reader.utf()?;
+                }
+                let certificate = reader.bytes()?.to_vec();
+                entries.push(Entry { alias, key: None, certificates: vec![certificate] });
+            }
+            // 密钥条目是 Java 序列化对象, 没有长度前缀, 无法跳过
+            JCEKS_SECRET_KEY => return Err(anyhow!("secret key entry `{}` is not supported", alias)),
+            _ => return Err(anyhow!("unsupported keystore entry type {}", tag)),
+        }
+    }
+    Ok(entries)
+}
+
+fn decrypt_key(info: &EncryptedPrivateKeyInfo, key_pass: &str) -> Result<Vec<u8>> {
+    let algorithm = &info.encryption_algorithm;
+    let data = info.encrypted_data.as_bytes();
+    if algorithm.oid == ID_JKS_KEY_PROTECTOR {
+        return jks_decrypt_key(data, key_pass);
+    }
+    let key = if algorithm.oid == ID_JCEKS_KEY_PROTECTOR {
+        jceks_decrypt_key(algorithm, data, key_pass)?
+    } else {
+        decrypt(algorithm, key_pass, data).map_err(|_| anyhow!("key password was incorrect"))?
+    };
+    // CBC 解密没有校验值, 密码错误时填充也可能碰巧合法
+    pkcs8::PrivateKeyInfo::from_der(&key).map_err(|_| anyhow!("key password was incorrect"))?;
+    Ok(key)
+}
+
+/// Sun JKS 私钥保护算法: 以 SHA-1 生成的密钥流异或, 末尾 20 字节为明文校验值.
+fn jks_decrypt_key(data: &[u8], key_pass: &str) -> Result<Vec<u8>> {
+    if data.len() < 40 {
+        return Err(anyhow!("protected key is truncated"));
+    }
+    let password = utf16_be(key_pass);
+    let (salt, rest) = data.split_at(20);
+    let (encrypted, check) = rest.split_at(rest.len() - 20);
+
+    let mut plain = Vec::with_capacity(encrypted.len());
+    let mut digest = salt.to_vec();
+    for chunk in encrypted.chunks(20) {
+        let mut hash = Sha1::new();
+        hash.update(&password);
+        hash.update(&digest);
+        digest = hash.finalize().to_vec();
+        plain.extend(chunk.iter().zip(&digest).map(|(a, b)| a ^ b));
+    }
+
+    let mut hash = Sha1::new();
+    hash.update(&password);
+    hash.update(&plain);
+    if hash.finalize().as_slice() != check {
+        return Err(anyhow!("key password was incorrect"));
+    }
+    Ok(plain)
+}
+
+/// JCEKS 私钥保护算法 PBEWithMD5AndTripleDES: 8 字节 salt 的两半分别与密码迭代 MD5,
+/// 拼接后前 24 字节为 3DES 密钥, 后 8 字节为 IV.
+fn jceks_decrypt_key(algorithm: &AlgorithmIdentifierOwned, data: &[u8], key_pass: &str) -> Result<Vec<u8>> {
+    let params = algorithm.parameters.as_ref()
+        .ok_or_else(|| anyhow!("missing PBE parameters"))?
+        .decode_as::<Pkcs12PbeParams>()?;
+    let mut salt = params.salt.as_bytes().to_vec();
+    if salt.len() != 8 {
+        return Err(anyhow!("invalid JCEKS salt length {}", salt.len()));
+    }
+    // 两半相同时反转前一半
+    if salt[..4] == salt[4..] {
+        salt[..4].reverse();
+    }
+
+    let mut derived = Vec::with_capacity(32);
+    for half in salt.chunks(4) {
+        let mut digest = half.to_vec();
+        for _ in 0..params.iterations {
+            let mut hash = Md5::new();
+            hash.update(&digest);
+            hash.update(key_pass.as_bytes());
+            digest = hash.finalize().to_vec();
+        }
+        derived.extend(digest);
+    }
+    let (key, iv) = derived.split_at(24);
+    cbc::Decryptor::<des::TdesEde3>::new_from_slices(key, iv)?
+        .decrypt_padded_vec_mut::<Pkcs7>(data)
+        .map_err(|_| anyhow!("key password was incorrect"))
+}
+
+fn read_pkcs12(data: &[u8], store_pass: &str) -> Result<Vec<Entry>> {
+    let pfx = Pfx::from_der(data).map_err(|e| anyhow!("keystore is neither JKS, JCEKS nor PKCS#12: {}", e))?;
+    if pfx.auth_safe.content_type != ID_DATA {
+        return Err(anyhow!("unsupported PKCS#12 content type {}", pfx.auth_safe.content_type));
+    }
+    let auth_safe = pfx.auth_safe.content.decode_as::<OctetString>()?;
+    if let Some(mac) = &pfx.mac_data {
+        verify_mac(mac, store_pass, auth_safe.as_bytes())?;
+    }
+
+    let mut keys = Vec::new();
+    let mut certificates = Vec::new();
+    for info in Vec::<ContentInfo>::from_der(auth_safe.as_bytes())? {
+        let contents = if info.content_type == ID_DATA {
+            info.content.decode_as::<OctetString>()?.as_bytes().to_vec()
+        } else if info.content_type == ID_ENCRYPTED_DATA {
+            let encrypted = info.content.decode_as::<EncryptedData>()?;
+            let content = encrypted.enc_content_info.encrypted_content
+                .ok_or_else(|| anyhow!("PKCS#12 encrypted data is empty"))?;
+            decrypt(&encrypted.enc_content_info.content_enc_alg, store_pass, content.as_bytes())
+                .map_err(|_| anyhow!("keystore password was incorrect"))?
+        } else {
+            continue;
+        };
+        for bag in SafeContents::from_der(&contents)? {
+            if bag.bag_id == pkcs12::PKCS_12_PKCS8_KEY_BAG_OID {
+                let info = ContextSpecific::<EncryptedPrivateKeyInfo>::from_der(&bag.bag_value)?.value;
+                keys.push((bag_name(&bag)?, bag_key_id(&bag)?, info));
+            } else if bag.bag_id == pkcs12::PKCS_12_CERT_BAG_OID {
+                let cert = ContextSpecific::<CertBag>::from_der(&bag.bag_value)?.value;
+                if cert.cert_id == pkcs12::PKCS_12_X509_CERT_OID {
+                    certificates.push((bag_name(&bag)?, bag_key_id(&bag)?, cert.cert_value.as_bytes().to_vec()));
+                }
+            }
+        }
+    }
+
+    let mut entries = Vec::new();
+    for (name, key_id, info) in keys {
+        // 签名证书排在最前, 其余证书作为证书链
+        let mut chain: Vec<_> = certificates.iter()
+            .filter(|(name, id, _)| key_id.is_none() || *id == key_id || (id.is_none() && name.is_none()))
+            .collect();
+        chain.sort_by_key(|(_, id, _)| id.is_none() || *id != key_id);
+        entries.push(Entry {
+            alias: name.unwrap_or_default(),
+            key: Some(info),
+            certificates: chain.into_iter().map(|(_, _, cert)| cert.clone()).collect(),
+        });
+    }
+    // 没有对应私钥的具名证书为受信任证书
+    for (name, id, cert) in &certificates {
+        if let (Some(name), None) = (name, id) {
+            entries.push(Entry { alias: name.clone(), key: None, certificates: vec![cert.clone()] });
+        }
+    }
+    Ok(entries)
+}
+
+fn verify_mac(mac: &MacData, password: &str, data: &[u8]) -> Result<()> {
+    let salt = mac.mac_salt.as_bytes();
+    let expected = mac.mac.digest.as_bytes();
+    let matched = match mac.mac.algorithm.oid {
+        oid if oid == ID_SHA1 => hmac_matches::<Sha1>(password, salt, mac.iterations, data, expected)?,
+        oid if oid == ID_SHA256 => hmac_matches::<Sha256>(password, salt, mac.iterations, data, expected)?,
+        oid => return Err(anyhow!("unsupported PKCS#12 mac algorithm {}", oid)),
+    };
+    if matched {
+        Ok(())
+    } else {
+        Err(anyhow!("keystore password was incorrect"))
+    }
+}
+
+fn hmac_matches<D>(password: &str, salt: &[u8], rounds: i32, data: &[u8], expected: &[u8]) -> Result<bool>
+where
+    D: Digest + FixedOutputReset + BlockSizeUser,
+{
+    let key = derive_key_utf8::<D>(password, salt, Pkcs12KeyType::Mac, rounds, <D as Digest>::output_size())?;
+    let mut mac = <SimpleHmac<D> as Mac>::new_from_slice(&key)?;
+    mac.update(data);
+    Ok(mac.verify_slice(expected).is_ok())
+}
+
+/// 解密 PKCS#12 中的 PBES2 或 PKCS#12 PBE (3DES, RC2-40) 加密内容.
+fn decrypt(algorithm: &AlgorithmIdentifierOwned, password: &str, data: &[u8]) -> Result<Vec<u8>> {
+    if algorithm.oid == ID_PBES2 {
+        let der = algorithm.to_der()?;
+        let scheme = pkcs5::EncryptionScheme::try_from(der.as_slice()).map_err(|e| anyhow!("{}", e))?;
+        return scheme.decrypt(password, data).map_err(|e| anyhow!("{}", e));
+    }
+
+    let params = algorithm.parameters.as_ref()
+        .ok_or_else(|| anyhow!("missing PBE parameters"))?
+        .decode_as::<Pkcs12PbeParams>()?;
+    let salt = params.salt.as_bytes();
+    let derive = |id, len| derive_key_utf8::<Sha1>(password, salt, id, params.iterations, len);
+    if algorithm.oid == pkcs12::PKCS_12_PBE_WITH_SHAAND3_KEY_TRIPLE_DES_CBC {
+        let key = derive(Pkcs12KeyType::EncryptionKey, 24)?;
+        let iv = derive(Pkcs12KeyType::Iv, 8)?;
+        cbc::Decryptor::<des::TdesEde3>::new_from_slices(&key, &iv)?
+            .decrypt_padded_vec_mut::<Pkcs7>(data)
+            .map_err(|e| anyhow!("{}", e))
+    } else if algorithm.oid == pkcs12::PKCS_12_PBEWITH_SHAAND40_BIT_RC2_CBC {
+        let key = derive(Pkcs12KeyType::EncryptionKey, 5)?;
+        let iv = derive(Pkcs12KeyType::Iv, 8)?;
+        let cipher = rc2::Rc2::new_with_eff_key_len(&key, 40);
+        cbc::Decryptor::<rc2::Rc2>::inner_iv_slice_init(cipher, &iv)?
+            .decrypt_padded_vec_mut::<Pkcs7>(data)
+            .map_err(|e| anyhow!("{}", e))
+    } else {
+        Err(anyhow!("unsupported keystore encryption {}", algorithm.oid))
+    }
+}
+
+fn bag_name(bag: &SafeBag) -> Result<Option<String>> {
+    match bag_attribute(bag, ID_FRIENDLY_NAME) {
+        Some(value) => Ok(Some(value.decode_as::<BmpString>()?.to_string())),
+        None => Ok(None),
+    }
+}
+
+fn bag_key_id(bag: &SafeBag) -> Result<Option<Vec<u8>>> {
+    match bag_attribute(bag, ID_LOCAL_KEY_ID) {
+        Some(value) => Ok(Some(value.decode_as::<OctetString>()?.as_bytes().to_vec())),
+        None => Ok(None),
+    }
+}
+
+fn bag_attribute(bag: &SafeBag, oid: ObjectIdentifier) -> Option<&der::Any> {
+    bag.bag_attributes.as_ref()?
+        .iter()
+        .find(|attribute| attribute.oid == oid)
+        .and_then(|attribute| attribute.values.iter().next())
+}
+
+fn utf16_be(value: &str) -> Vec<u8> {
+    value.encode_utf16().flat_map(|c| c.to_be_bytes()).collect()
+}
+
+/// 按 Java `DataInputStream` 的格式读取大端整数和字符串.
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Reader { data }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.data.len() < len {
+            return Err(anyhow!("keystore is truncated"));
+        }
+        let (head, tail) = self.data.split_at(len);
+        self.data = tail;
+        Ok(head)
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_be_bytes(self.take(2)?.try_into()?))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into()?))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_be_bytes(self.take(8)?.try_into()?))
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8]> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    fn utf(&mut self) -> Result<String> {
+        let len = self.u16()? as usize;
+        Ok(String::from_utf8_lossy(self.take(len)?).to_string())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::path::PathBuf;
+
+    use rsa::pkcs8::DecodePublicKey;
+    use signature::hazmat::PrehashVerifier;
+    use x509_cert::Certificate;
+
+    use super::*;
+
+    pub(crate) const STORE_PASS: &str = "123456";
+
+    /// 仓库自带的 PKCS#12 keystore, 别名 `abtool`, key 密码与 store 密码相同.
+    pub(crate) fn pkcs12_path() -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("../file/abtool.jks")
+    }
+
+    /// `testdata/` 中由 keytool 生成的 keystore: RSA 私钥 `abtool`(key 密码 `abtool-key`),
+    /// EC 私钥 `ec`(key 密码 `ec-key`) 和受信任证书 `ca`.
+    pub(crate) fn testdata(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata").join(name)
+    }
+
+    /// 用证书中的公钥验证 [`PrivateKey::sign`] 的签名.
+    pub(crate) fn verify(certificate: &[u8], digest: DigestAlgorithm, data: &[u8], signature: &[u8]) {
+        let spki = Certificate::from_der(certificate).unwrap().tbs_certificate.subject_public_key_info.to_der().unwrap();
+        let hash = digest.digest(data);
+        if let Ok(key) = rsa::RsaPublicKey::from_public_key_der(&spki) {
+            assert_eq!(digest, DigestAlgorithm::Sha256, "only SHA-256 is verified for RSA");
+            key.verify(Pkcs1v15Sign::new::<Sha256>(), &hash, signature).unwrap();
+        } else {
+            let key = p256::ecdsa::VerifyingKey::from_public_key_der(&spki).unwrap();
+            key.verify_prehash(&hash, &p256::ecdsa::Signature::from_der(signature).unwrap()).unwrap();
+        }
+    }
+
+    fn assert_signs(entry: &KeyEntry) {
+        let signature = entry.key.sign(DigestAlgorithm::Sha256, b"abtool").unwrap();
+        verify(&entry.certificates[0], DigestAlgorithm::Sha256, b"abtool", &signature);
+    }
+
+    #[test]
+    fn open_pkcs12() {
+        let keystore = Keystore::open(pkcs12_path().as_path(), STORE_PASS).unwrap();
+        assert_eq!(keystore.format, KeystoreFormat::Pkcs12);
+        assert_eq!(keystore.aliases(), ["abtool"]);
+        let entry = keystore.key("abtool", STORE_PASS).unwrap();
+        assert!(entry.key.is_rsa());
+        assert_eq!(entry.alias, "abtool");
+        assert_signs(&entry);
+    }
+
+    #[test]
+    fn open_jks() {
+        let keystore = Keystore::open(testdata("abtool.jks").as_path(), STORE_PASS).unwrap();
+        assert_eq!(keystore.format, KeystoreFormat::Jks);
+        let mut entries: Vec<_> = keystore.entries().collect();
+        entries.sort_by_key(|(alias, _)| *alias);
+        assert_eq!(entries, [("abtool", EntryKind::PrivateKey), ("ca", EntryKind::TrustedCertificate), ("ec", EntryKind::PrivateKey)]);
+
+        let rsa = keystore.key("ABTOOL", "abtool-key").unwrap();
+        assert!(rsa.key.is_rsa());
+        assert_eq!(rsa.alias, "abtool");
+        assert_signs(&rsa);
+        // 与 PKCS#12 中导入前的证书一致
+        let original = load(pkcs12_path().as_path(), STORE_PASS, "abtool", STORE_PASS).unwrap();
+        assert_eq!(rsa.certificates, original.certificates);
+
+        let ec = keystore.key("ec", "ec-key").unwrap();
+        assert_eq!(ec.key.algorithm_name(), "ECDSA");
+        assert_signs(&ec);
+    }
+
+    #[test]
+    fn open_jceks() {
+        let keystore = Keystore::open(testdata("abtool.jceks").as_path(), STORE_PASS).unwrap();
+        assert_eq!(keystore.format, KeystoreFormat::Jceks);
+        let mut aliases = keystore.aliases();
+        aliases.sort();
+        assert_eq!(aliases, ["abtool", "ec"]);
+        let rsa = keystore.key("abtool", "abtool-key").unwrap();
+        assert_signs(&rsa);
+        assert_signs(&keystore.key("ec", "ec-key").unwrap());
+    }
+
+    #[test]
+    fn jceks_password_errors() {
+        let path = testdata("abtool.jceks");
+        let e = Keystore::open(path.as_path(), "654321").err().unwrap();
+        assert_eq!(e.to_string(), "keystore password was incorrect");
+        // key 密码与 store 密码不同, 误用 store 密码时报告 key 密码错误
+        let keystore = Keystore::open(path.as_path(), STORE_PASS).unwrap();
+        let e = keystore.key("abtool", STORE_PASS).err().unwrap();
+        assert_eq!(e.to_string(), "key password was incorrect");
+        let e = keystore.key("ec", "abtool-key").err().unwrap();
+        assert_eq!(e.to_string(), "key password was incorrect");
+    }
+
+    #[test]
+    fn reject_wrong_store_password() {
+        for path in [pkcs12_path(), testdata("abtool.jks")] {
+            let e = Keystore::open(path.as_path(), "654321").err().unwrap();
+            assert_eq!(e.to_string(), "keystore password was incorrect", "{}", path.to_string_lossy());
+        }
+    }
+
+    #[test]
+    fn reject_wrong_key_password() {
+        let e = load(pkcs12_path().as_path(), STORE_PASS, "abtool", "654321").err().unwrap();
+        assert_eq!(e.to_string(), "key password was incorrect");
+        let e = load(testdata("abtool.jks").as_path(), STORE_PASS, "abtool", STORE_PASS).err().unwrap();
+        assert_eq!(e.to_string(), "key password was incorrect");
+    }
+
+    #[test]
+    fn reject_unknown_alias() {
+        let keystore = Keystore::open(testdata("abtool.jks").as_path(), STORE_PASS).unwrap();
+        let e = keystore.key("ca", STORE_PASS).err().unwrap();
+        assert!(e.to_string().starts_with("alias `ca` not found, keystore has: "), "{}", e);
+    }
+
+    #[test]
+    fn reject_truncated_keystore() {
+        assert!(read_jks(&JKS_MAGIC.to_be_bytes(), STORE_PASS).is_err());
+        assert!(read_pkcs12(b"not a keystore", STORE_PASS).is_err());
+    }
+
+    #[test]
+    fn parse_digest_algorithm() {
+        assert_eq!("sha-256".parse::<DigestAlgorithm>().unwrap(), DigestAlgorithm::Sha256);
+        assert_eq!("SHA1".parse::<DigestAlgorithm>().unwrap(), DigestAlgorithm::Sha1);
+        assert!("MD5".parse::<DigestAlgorithm>().is_err());
+    }
+}
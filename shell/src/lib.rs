@@ -1,116 +1,373 @@
-#![feature(once_cell_try)]
-
 use std::{fs, io};
 use std::fs::File;
 use std::io::{Read, Seek, Write};
-use std::path::Path;
-use std::process::{Command, Stdio};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::str::FromStr;
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context, Result};
 use md5::digest::FixedOutput;
 use md5::Md5;
-use tracing::{debug, info, trace};
+use tracing::{debug, info, trace, warn};
 use walkdir::{DirEntry, WalkDir};
 use zip::write::FileOptions;
 use zip::ZipArchive;
 
-use config::Config;
-
+use apktool::{ApktoolInfo, Versions};
+use file_path::Module;
+use jar_signer::JarAlgorithms;
+
+pub use build::{BuildOptions, BuildOutput, SigningInfo};
+pub use error::Error;
+pub use config::{Apk, ApksMode, AssetPack, AssetPackDelivery, BuildApk, Config, ConfigInfo, FeatureModule, Jar, ModuleDelivery, Secret, Sign};
+pub use file_path::{BuildContext, BuildLayout};
+pub use pipeline::{CommandStage, Flow, Pipeline, Stage, StageTiming};
+pub use step::{Step, StepRange};
+
+/// `.so` 默认按 4 KiB 页对齐.
+pub const DEFAULT_PAGE_SIZE: u32 = 4;
+
+mod apk_set;
+mod apk_signer;
+mod apktool;
+mod asset_pack;
+mod build;
+mod bundle_config;
+mod cache;
 mod config;
+mod error;
 mod file_path;
+mod jar_signer;
+mod keystore;
+mod manifest;
+mod pipeline;
+mod protobuf;
+mod resources;
+mod split_apks;
+mod step;
+#[cfg(test)]
+pub(crate) mod test_util;
+mod tool;
+mod universal_apk;
+mod validate;
+mod zipalign;
 
 pub fn build_apk(config: String, time: &str) -> Result<String> {
-    let config = read_config(config)?;
-    apktool_rm_cache(&config)?;
-    apktool_build(&config, time)?;
-    zipalign(&config, time)?;
-    let apk_name = apksigner(&config, time)?;
-    if config.config.install {
-        install_apk(&config, time)?;
-        if config.config.launch {
-            launch_app(&config)?;
-        }
-    }
+    let config = read_config(config.as_str())?;
+    run_apk(&config, time, &StepRange::all())
+}
 
-    Ok(apk_name)
+/// 返回签名后的 apk 路径, 区间内的步骤没有生成 apk 时为空字符串.
+pub fn run_apk(config: &Config, time: &str, range: &StepRange) -> Result<String> {
+    let output = BuildOptions::apk(time).range(range.clone()).build(config)?;
+    Ok(output.artifact.map(|artifact| artifact.to_string_lossy().to_string()).unwrap_or_default())
 }
 
 pub fn build_aab(config: String, time: &str) -> Result<String> {
+    let config = read_config(config.as_str())?;
+    run_aab(&config, time, &StepRange::all())
+}
+
+/// 返回 aab 路径, 区间内的步骤没有生成 aab 时为空字符串.
+pub fn run_aab(config: &Config, time: &str, range: &StepRange) -> Result<String> {
     // let filtered_env : HashMap<String, String> =
     //     env::vars().filter(|&(ref k, _)|
     //         k == "TERM" || k == "TZ" || k == "LANG" || k == "PATH"
     //     ).collect();
 
-    let config = read_config(config)?;
-
-    let outdir = Path::new(config.apk.apk_outdir.as_str());
+    let output = BuildOptions::aab(time).range(range.clone()).build(config)?;
+    Ok(output.artifact.map(|artifact| artifact.to_string_lossy().to_string()).unwrap_or_default())
+}
 
-    if !outdir.exists() {
-        decode_apk(&config)?;
+/// 执行流水线, 返回本次构建的上下文和每个阶段的耗时.
+fn run_flow<'a>(config: &'a Config, time: &str, range: &StepRange, flow: Flow) -> Result<(BuildContext<'a>, Vec<StageTiming>)> {
+    validate::check(config, Some(flow))?;
+    let pipeline = Pipeline::for_flow(config, flow)?;
+    let names = pipeline.ordered(&BuildContext::new(config, time))?;
+    let selected: Vec<Option<Step>> = range.select(&names)?.iter().map(|i| Step::from_str(names[*i].as_str()).ok()).collect();
+    let time = resume_time(config, time, selected.first().copied().flatten())?;
+    if selected.iter().any(|step| matches!(step, Some(Step::SignAppBundle | Step::BuildApks | Step::Apksigner))) {
+        check_keystore(config)?;
     }
+    let ctx = BuildContext::new(config, time.as_str());
+    let stages = pipeline.run(&ctx, range)?;
+    Ok((ctx, stages))
+}
 
-    compile_resources(&config)?;
-    link_resources(&config)?;
-    unzip_apk(&config)?;
-    copy_resources(&config)?;
-    zip_resources(&config)?;
-    compile_app_bundle(&config, time)?;
-    let aab_name = sign_app_bundle(&config, time)?;
+/// 在执行任何外部工具前确认 keystore 密码、别名和 key 密码正确.
+fn check_keystore(config: &Config) -> Result<()> {
+    let sign = &config.sign;
+    keystore::load(Path::new(sign.keystore.as_str()), sign.keystore_pass.expose(),
+                   sign.keystore_key_alias.as_str(), sign.keystore_key_pass.expose())
+        .with_context(|| Error::SigningFailed(format!("check keystore {}", sign.keystore)))?;
+    if let Some(previous) = &sign.previous {
+        keystore::load(Path::new(previous.keystore.as_str()), previous.keystore_pass.expose(),
+                       previous.keystore_key_alias.as_str(), previous.keystore_key_pass.expose())
+            .with_context(|| Error::SigningFailed(format!("check keystore {}", previous.keystore)))?;
+    }
+    Ok(())
+}
 
-    if config.config.install {
-        build_apks(&config, time)?;
-        install_apks(&config)?;
-        if config.config.launch {
-            launch_app(&config)?;
+/// 从中间步骤开始时, 沿用上一次构建产物的时间戳.
+fn resume_time(config: &Config, time: &str, first: Option<Step>) -> Result<String> {
+    let layout = BuildLayout::new(config);
+    let (dir, suffix) = match first {
+        Some(Step::SignAppBundle) | Some(Step::BuildApks) => (&layout.root, format!("{}.aab", config.apk.app_name)),
+        Some(Step::Zipalign) => (&layout.apk_dist, format!("_{}-unsign.apk", config.apk.app_name)),
+        Some(Step::Apksigner) => (&layout.apk_dist, format!("_{}-zip.apk", config.apk.app_name)),
+        Some(Step::InstallApk) => (&layout.apk_dist, format!("_{}-sign.apk", config.apk.app_name)),
+        _ => return Ok(time.to_string()),
+    };
+    match file_path::find_latest_time(dir, suffix.as_str())? {
+        Some(latest) => {
+            debug!("resume from previous build {}", latest);
+            Ok(latest)
         }
+        None => Err(anyhow!("no previous *{} found in {}", suffix, dir.to_string_lossy())),
     }
-
-    Ok(aab_name)
 }
 
-fn read_config(config: String) -> Result<Config> {
+pub fn read_config(config: &str) -> Result<Config> {
     debug!("read config");
     let cfg_file = fs::read_to_string(config)?;
-    debug!(cfg_file);
-    let config: Config = toml::from_str(cfg_file.as_str())?;
-    debug!("read config success");
+    let config = Config::from_toml(cfg_file.as_str()).with_context(|| Error::ConfigInvalid(config.to_string()))?;
+    debug!("read config success: {:?}", config);
     Ok(config)
 }
 
-fn install_apks(config: &Config) -> Result<()> {
+/// 检查配置中的全部路径、版本号、`main_activity` 以及 keystore 密码, 一次报告所有问题.
+pub fn validate(config: &Config) -> Result<()> {
+    let config = config.resolved()?;
+    let config = config.as_ref();
+    let mut problems = validate::problems(config, None);
+    // keystore 路径有误时不再重复报告读取失败
+    if !problems.iter().any(|problem| problem.starts_with("[sign")) {
+        if let Err(e) = check_keystore(config) {
+            problems.push(format!("{:#}", e));
+        }
+    }
+    validate::report(problems)?;
+    debug!("config is valid");
+    Ok(())
+}
+
+/// 反编译 `apk_path` 到 `apk_outdir`, 以及 `[[module]]` 中的 feature 模块.
+/// `force` 为 true 时先删除已有的输出目录.
+pub fn decode(config: &Config, force: bool) -> Result<()> {
+    let ctx = BuildContext::new(config, "");
+    for module in &ctx.modules {
+        let outdir = &module.layout.root;
+        if outdir.exists() {
+            if !force {
+                return Err(anyhow!("{} already exists, use --force to overwrite", outdir.to_string_lossy()));
+            }
+            fs::remove_dir_all(outdir)?;
+        }
+    }
+    ctx.modules.iter().try_for_each(|module| decode_apk(config, module))
+}
+
+/// 签名 `.aab` 或 `.apk`, 返回签名后的文件路径.
+///
+/// `.aab` 原地签名(指定 `output` 时先拷贝), `.apk` 默认输出到同目录的 `*-sign.apk`.
+pub fn sign(config: &Config, input: &Path, output: Option<&Path>) -> Result<PathBuf> {
+    let config = config.resolved()?;
+    let config = config.as_ref();
+    match file_kind(input)? {
+        FileKind::Aab => {
+            let aab_path = match output {
+                Some(output) => {
+                    fs::copy(input, output)?;
+                    output.to_path_buf()
+                }
+                None => input.to_path_buf(),
+            };
+            jarsigner(config, aab_path.as_path())?;
+            Ok(aab_path)
+        }
+        FileKind::Apk => {
+            let apk_sign_path = match output {
+                Some(output) => output.to_path_buf(),
+                None => sibling_with_suffix(input, "-sign")?,
+            };
+            apksigner_file(config, input, apk_sign_path.as_path())?;
+            Ok(apk_sign_path)
+        }
+        FileKind::Apks => Err(anyhow!("{} is an apk set, sign the aab instead", input.to_string_lossy())),
+    }
+}
+
+/// 安装 `.apk`, `.apks` 或 `.aab` 到已连接的设备.
+pub fn install(config: &Config, input: &Path) -> Result<()> {
+    let config = config.resolved()?;
+    let config = config.as_ref();
+    match file_kind(input)? {
+        FileKind::Apk => adb_install(input),
+        FileKind::Apks => bundletool_install_apks(config, input),
+        FileKind::Aab => {
+            let apks_path = BuildLayout::new(config).apks;
+            check_keystore(config)?;
+            bundletool_build_apks(config, input, apks_path.as_path())?;
+            bundletool_install_apks(config, apks_path.as_path())
+        }
+    }
+}
+
+/// 启动 app, `activity` 为 None 时使用配置中的 `main_activity`.
+pub fn launch(config: &Config, activity: Option<&str>) -> Result<()> {
+    adb_start(activity.unwrap_or(config.config.main_activity.as_str()))
+}
+
+/// 校验 `.apk` 或 `.aab` 的签名.
+pub fn verify(input: &Path) -> Result<()> {
+    match file_kind(input)? {
+        FileKind::Apk => {
+            check_alignment(input, DEFAULT_PAGE_SIZE)?;
+            apksigner_verify(input)
+        }
+        FileKind::Aab => jarsigner_verify(input),
+        FileKind::Apks => Err(anyhow!("{} is an apk set, verify the aab instead", input.to_string_lossy())),
+    }
+}
+
+/// 检查 `.apk` 的 zip 对齐, 相当于 `zipalign -c -p 4`. `page_size` 为 `.so` 的页大小(KiB).
+pub fn check_alignment(input: &Path, page_size: u32) -> Result<()> {
+    debug!("check alignment");
+    if !matches!(file_kind(input)?, FileKind::Apk) {
+        return Err(anyhow!("{} is not an apk", input.to_string_lossy()));
+    }
+    let misaligned = zipalign::check(input, page_size)?;
+    if misaligned.is_empty() {
+        debug!("check alignment success");
+        Ok(())
+    } else {
+        Err(anyhow!("{} entries are not aligned: {}", misaligned.len(), misaligned.join(", ")))
+    }
+}
+
+/// `[sign]` 中 keystore 的格式和条目.
+#[derive(Debug, Clone)]
+pub struct KeystoreInfo {
+    /// `JKS`, `JCEKS` 或 `PKCS12`.
+    pub format: String,
+    /// `(别名, 类型)`, 类型与 keytool 一致: `PrivateKeyEntry` 或 `trustedCertEntry`.
+    pub entries: Vec<(String, String)>,
+}
+
+/// 读取 `[sign]` 中 keystore 的条目, 并校验 store 密码、别名和 key 密码.
+pub fn list_keystore(config: &Config) -> Result<KeystoreInfo> {
+    let config = config.resolved()?;
+    let config = config.as_ref();
+    let sign = &config.sign;
+    let keystore = keystore::Keystore::open(Path::new(sign.keystore.as_str()), sign.keystore_pass.expose())?;
+    let signer = keystore.key(sign.keystore_key_alias.as_str(), sign.keystore_key_pass.expose())?;
+    info!("key `{}` is a {} key, {} certificates", signer.alias, signer.key.algorithm_name(), signer.certificates.len());
+    Ok(KeystoreInfo {
+        format: keystore.format.name().to_string(),
+        entries: keystore.entries().map(|(alias, kind)| (alias.to_string(), kind.name().to_string())).collect(),
+    })
+}
+
+/// 打印 `.apk` 或 `.aab` 的包信息, `.aab` 需要配置中的 bundletool.
+pub fn info(config: Option<&Config>, input: &Path) -> Result<()> {
+    match file_kind(input)? {
+        FileKind::Apk => aapt2_dump_badging(input),
+        FileKind::Aab => {
+            let config = config.ok_or_else(|| anyhow!("reading an aab needs bundletool, pass a config file"))?;
+            bundletool_dump_manifest(config, input)
+        }
+        FileKind::Apks => Err(anyhow!("{} is an apk set, inspect the aab instead", input.to_string_lossy())),
+    }
+}
+
+/// 从 `.apks` 中解压设备需要的 apk 到 `output`, 返回解压出的文件.
+///
+/// `device_spec` 为 `bundletool get-device-spec` 格式的 JSON, 为 None 时解压 universal apk.
+/// 默认只包含 base 和安装时下发的模块, `modules` 中的模块会一并解压.
+pub fn extract_apks(input: &Path, device_spec: Option<&Path>, modules: &[String], output: &Path) -> Result<Vec<PathBuf>> {
+    if !matches!(file_kind(input)?, FileKind::Apks) {
+        return Err(anyhow!("{} is not an apk set", input.to_string_lossy()));
+    }
+    let device = device_spec.map(apk_set::DeviceSpec::read).transpose()?;
+    apk_set::extract(input, device.as_ref(), modules, output)
+}
+
+/// 不经过 bundletool, 把 `.aab` 的 base 模块和安装时分发的资源包转为可以直接安装的 universal apk, 返回签名后的 apk 路径.
+///
+/// 资源通过 `aapt2 convert` 转回二进制格式, 之后与 apk 流程一样对齐并签名. 默认输出到同目录的 `*.apk`.
+pub fn aab_to_apk(config: &Config, input: &Path, output: Option<&Path>) -> Result<PathBuf> {
+    if !matches!(file_kind(input)?, FileKind::Aab) {
+        return Err(anyhow!("{} is not an aab", input.to_string_lossy()));
+    }
+    let output = output.map(Path::to_path_buf).unwrap_or_else(|| input.with_extension("apk"));
+    let config = config.resolved()?;
+    let config = config.as_ref();
+    check_keystore(config)?;
+    universal_apk::convert(config, input, output.as_path())?;
+    debug!("aab to apk success, path: {}", output.to_string_lossy());
+    Ok(output)
+}
+
+enum FileKind {
+    Apk,
+    Aab,
+    Apks,
+}
+
+fn file_kind(path: &Path) -> Result<FileKind> {
+    if !path.is_file() {
+        return Err(anyhow!("{} not exists", path.to_string_lossy()));
+    }
+    match path.extension().map(|ext| ext.to_string_lossy().to_lowercase()).as_deref() {
+        Some("apk") => Ok(FileKind::Apk),
+        Some("aab") => Ok(FileKind::Aab),
+        Some("apks") => Ok(FileKind::Apks),
+        _ => Err(anyhow!("unsupported file type: {}", path.to_string_lossy())),
+    }
+}
+
+fn sibling_with_suffix(path: &Path, suffix: &str) -> Result<PathBuf> {
+    let stem = path.file_stem().ok_or_else(|| anyhow!("invalid file name: {}", path.to_string_lossy()))?;
+    let ext = path.extension().map(|ext| format!(".{}", ext.to_string_lossy())).unwrap_or_default();
+    Ok(path.with_file_name(format!("{}{}{}", stem.to_string_lossy(), suffix, ext)))
+}
+
+fn install_apks(ctx: &BuildContext) -> Result<()> {
+    let config = ctx.config;
+    let apks_path = &ctx.layout.apks;
+    bundletool_install_apks(config, apks_path.as_path())
+}
+
+fn bundletool_install_apks(config: &Config, apks_path: &Path) -> Result<()> {
     debug!("install apks");
-    let apks_path = file_path::get_apks_path(config)?;
     info!("exec command: java -jar {} install-apks --apks {}", config.jar.bundletool_path, apks_path.to_string_lossy());
 
-    let child = Command::new("java")
-        .stderr(Stdio::inherit())
-        .stdout(Stdio::inherit())
+    tool::exec("install apks", Command::new("java")
         .arg("-jar")
         .arg(config.jar.bundletool_path.as_str())
         .arg("install-apks")
         .arg("--apks")
-        .arg(apks_path.to_string_lossy().to_string())
-        .output()?;
-    if child.status.success() {
-        debug!("install apks success");
-        Ok(())
-    } else {
-        Err(anyhow!("install apks failed"))
-    }
+        .arg(apks_path.to_string_lossy().to_string()))?;
+    debug!("install apks success");
+    Ok(())
 }
 
-fn build_apks(config: &Config, time: &str) -> Result<()> {
+fn build_apks(ctx: &BuildContext) -> Result<()> {
+    let config = ctx.config;
+    let apks_path = &ctx.layout.apks;
+    let aab_path = &ctx.aab;
+    bundletool_build_apks(config, aab_path.as_path(), apks_path.as_path())
+}
+
+fn bundletool_build_apks(config: &Config, aab_path: &Path, apks_path: &Path) -> Result<()> {
     debug!("build apks");
-    let apks_path = file_path::get_apks_path(config)?;
-    let aab_path = file_path::get_aab_path(config, time)?;
-    info!("exec command: java -jar {} build-apks --bundle {} --output {} --ks {} --ks-pass pass:{} --ks-key-alias {} --key-pass pass:{}", config.jar.bundletool_path, aab_path.to_string_lossy(), apks_path.to_string_lossy(), config.sign.keystore, config.sign.keystore_pass, config.sign.keystore_key_alias, config.sign.keystore_key_pass);
+    let options = build_apks_args(config);
+    info!("exec command: java -jar {} build-apks --bundle {} --output {} --ks {} --ks-pass pass:{} --ks-key-alias {} --key-pass pass:{} {}", config.jar.bundletool_path, aab_path.to_string_lossy(), apks_path.to_string_lossy(), config.sign.keystore, config.sign.keystore_pass, config.sign.keystore_key_alias, config.sign.keystore_key_pass, options.join(" "));
 
     if apks_path.exists() {
         fs::remove_file(apks_path)?;
     }
-    let child = Command::new("java")
-        .stderr(Stdio::inherit())
-        .stdout(Stdio::inherit())
+    tool::exec("build apks", Command::new("java")
         .arg("-jar")
         .arg(config.jar.bundletool_path.as_str())
         .arg("build-apks")
@@ -121,293 +378,354 @@ fn build_apks(config: &Config, time: &str) -> Result<()> {
         .arg("--ks")
         .arg(config.sign.keystore.as_str())
         .arg("--ks-pass")
-        .arg(format!("pass:{}", config.sign.keystore_pass).as_str())
+        .arg(format!("pass:{}", config.sign.keystore_pass.expose()).as_str())
         .arg("--ks-key-alias")
         .arg(config.sign.keystore_key_alias.as_str())
-        .arg(format!("--key-pass=pass:{}", config.sign.keystore_key_pass).as_str())
-        .output()?;
+        .arg(format!("--key-pass=pass:{}", config.sign.keystore_key_pass.expose()).as_str())
+        .args(options))
+        .map_err(|e| e.redact(config))?;
+    debug!("build apks success");
+    Ok(())
+}
 
-    if child.status.success() {
-        debug!("build apks success");
-        Ok(())
-    } else {
-        Err(anyhow!("build apks failed"))
+/// `[build_apks]` 对应的 `build-apks` 参数.
+fn build_apks_args(config: &Config) -> Vec<String> {
+    let options = &config.build_apks;
+    let mut args = Vec::new();
+    if options.mode != ApksMode::Default {
+        args.push(format!("--mode={}", options.mode.name()));
     }
+    if options.connected_device {
+        args.push("--connected-device".to_string());
+    }
+    if let Some(device_spec) = &options.device_spec {
+        args.push(format!("--device-spec={}", device_spec));
+    }
+    if options.local_testing {
+        args.push("--local-testing".to_string());
+    }
+    if let Some(device_tier) = options.device_tier {
+        args.push(format!("--device-tier={}", device_tier));
+    }
+    args
+}
+
+fn sign_app_bundle(ctx: &BuildContext) -> Result<String> {
+    let config = ctx.config;
+    let aab_path = &ctx.aab;
+    jarsigner(config, aab_path.as_path())?;
+    Ok(aab_path.to_string_lossy().to_string())
 }
 
-fn sign_app_bundle(config: &Config, time: &str) -> Result<String> {
+fn jarsigner(config: &Config, aab_path: &Path) -> Result<()> {
     debug!("sign app bundle");
-    let aab_path = file_path::get_aab_path(config, time)?;
-    info!("exec command: jarsigner -digestalg SHA1 -sigalg SHA1withRSA -keystore {} -storepass {} -keypass {} {} {}", config.sign.keystore, config.sign.keystore_pass, config.sign.keystore_key_pass, aab_path.to_string_lossy(), config.sign.keystore_key_alias);
-    let child = Command::new("jarsigner")
-        .stderr(Stdio::inherit())
-        .stdout(Stdio::inherit())
-        .arg("-digestalg")
-        .arg("SHA1")
-        .arg("-sigalg")
-        .arg("SHA1withRSA")
-        .arg("-keystore")
-        .arg(config.sign.keystore.as_str())
-        .arg("-storepass")
-        .arg(config.sign.keystore_pass.as_str())
-        .arg("-keypass")
-        .arg(config.sign.keystore_key_pass.as_str())
-        .arg(aab_path.to_string_lossy().to_string())
-        .arg(config.sign.keystore_key_alias.as_str())
-        .output()?;
-    if child.status.success() {
-        debug!("sign app bundle success");
-        Ok(aab_path.to_string_lossy().to_string())
-    } else {
-        Err(anyhow!("sign app bundle failed"))
-    }
+    let sign = &config.sign;
+    let failed = || Error::SigningFailed(format!("sign {}", aab_path.to_string_lossy()));
+    let signer = keystore::load(Path::new(sign.keystore.as_str()), sign.keystore_pass.expose(),
+                                sign.keystore_key_alias.as_str(), sign.keystore_key_pass.expose()).with_context(failed)?;
+    let algorithms = JarAlgorithms::from_config(sign, &signer.key).with_context(failed)?;
+    info!("sign {} with {} digest, {}with{}, alias {}", aab_path.to_string_lossy(), algorithms.digest.name(),
+          algorithms.signature.name().replace('-', ""), signer.key.algorithm_name(), signer.alias);
+    jar_signer::sign_file(aab_path, aab_path, &signer, algorithms).with_context(failed)?;
+    debug!("sign app bundle success");
+    Ok(())
 }
 
-fn compile_app_bundle(config: &Config, time: &str) -> Result<()> {
-    debug!("compile app bundle");
-    let aab_path = file_path::get_aab_path(config, time)?;
-    let zip_path = file_path::get_base_zip_path(config)?;
+fn jarsigner_verify(aab_path: &Path) -> Result<()> {
+    debug!("verify app bundle");
+    info!("exec command: jarsigner -verify -verbose {}", aab_path.to_string_lossy());
+    tool::exec("verify app bundle", Command::new("jarsigner")
+        .arg("-verify")
+        .arg("-verbose")
+        .arg(aab_path.to_string_lossy().to_string()))?;
+    debug!("verify app bundle success");
+    Ok(())
+}
+
+fn bundletool_dump_manifest(config: &Config, aab_path: &Path) -> Result<()> {
+    debug!("dump manifest");
+    info!("exec command: java -jar {} dump manifest --bundle {}", config.jar.bundletool_path, aab_path.to_string_lossy());
+    tool::exec("dump manifest", Command::new("java")
+        .arg("-jar")
+        .arg(config.jar.bundletool_path.as_str())
+        .arg("dump")
+        .arg("manifest")
+        .arg("--bundle")
+        .arg(aab_path.to_string_lossy().to_string()))?;
+    debug!("dump manifest success");
+    Ok(())
+}
 
+fn compile_app_bundle(ctx: &BuildContext) -> Result<()> {
+    let config = ctx.config;
+    debug!("compile app bundle");
+    let aab_path = &ctx.aab;
+    let modules = ctx.modules.iter().map(|module| module.layout.base_zip.clone())
+        .chain(config.asset_pack.iter().map(|pack| asset_pack::PackLayout::new(&ctx.layout, pack).zip))
+        .map(|zip| zip.to_string_lossy().to_string())
+        .collect::<Vec<_>>().join(",");
 
     if aab_path.exists() {
         fs::remove_file(aab_path)?;
     }
-    let child: std::process::Output;
-    if config.config.bundletool_config_path.is_empty() {
-        info!("exec command: java -jar {} build-bundle --modules {} --output {}", config.jar.bundletool_path, zip_path.to_string_lossy(), aab_path.to_string_lossy());
-        child = Command::new("java")
-            .stderr(Stdio::inherit())
-            .stdout(Stdio::inherit())
-            .arg("-jar")
-            .arg(config.jar.bundletool_path.as_str())
-            .arg("build-bundle")
-            .arg("--modules")
-            .arg(zip_path.to_string_lossy().to_string())
-            .arg("--output")
-            .arg(aab_path.to_string_lossy().to_string())
-            .output()?;
-    } else {
-        info!("exec command: java -jar {} build-bundle --modules {} --output {} --config={}", config.jar.bundletool_path, zip_path.to_string_lossy(), aab_path.to_string_lossy(), config.config.bundletool_config_path);
-        child = Command::new("java")
-            .stderr(Stdio::inherit())
-            .stdout(Stdio::inherit())
-            .arg("-jar")
-            .arg(config.jar.bundletool_path.as_str())
-            .arg("build-bundle")
-            .arg("--modules")
-            .arg(zip_path.to_string_lossy().to_string())
-            .arg("--output")
-            .arg(aab_path.to_string_lossy().to_string())
-            .arg(format!("--config={}", config.config.bundletool_config_path).as_str())
-            .output()?;
-    }
-
-
-    if child.status.success() {
-        debug!("compile app bundle success");
-        Ok(())
-    } else {
-        Err(anyhow!("compile app bundle failed"))
+    let mut command = Command::new("java");
+    command.arg("-jar")
+        .arg(config.jar.bundletool_path.as_str())
+        .arg("build-bundle")
+        .arg("--modules")
+        .arg(modules.as_str())
+        .arg("--output")
+        .arg(aab_path.to_string_lossy().to_string());
+    match bundle_config::write(ctx)? {
+        None => info!("exec command: java -jar {} build-bundle --modules {} --output {}", config.jar.bundletool_path, modules, aab_path.to_string_lossy()),
+        Some(bundle_config) => {
+            info!("exec command: java -jar {} build-bundle --modules {} --output {} --config={}", config.jar.bundletool_path, modules, aab_path.to_string_lossy(), bundle_config.to_string_lossy());
+            command.arg(format!("--config={}", bundle_config.to_string_lossy()).as_str());
+        }
     }
+    tool::exec("compile app bundle", &mut command)?;
+    debug!("compile app bundle success");
+    Ok(())
 }
 
-fn zip_resources(config: &Config) -> Result<()> {
-    debug!("zip resources");
+fn zip_resources(module: &Module) -> Result<()> {
+    debug!("zip resources of {}", module.name());
+    let layout = &module.layout;
 
-    let zip_path = file_path::get_base_zip_path(config)?;
+    let zip_path = &layout.base_zip;
 
     if zip_path.exists() {
         fs::remove_file(zip_path.as_path())?;
     }
     let file = File::create(zip_path.as_path())?;
 
-    let base_path = file_path::get_base_dir_path(config)?;
+    let base_path = &layout.base_dir;
 
     let walkdir = WalkDir::new(Path::new(base_path.as_path()));
     let it = walkdir.into_iter();
 
-    zip_dir(&mut it.filter_map(|e| e.ok()), base_path.to_string_lossy().to_string().as_str(), file, zip::CompressionMethod::Deflated)?;
+    // 原 apk 中未压缩的文件保持不压缩
+    let info = ApktoolInfo::read(layout.root.as_path())?;
+    zip_dir(&mut it.filter_map(|e| e.ok()), base_path.to_string_lossy().to_string().as_str(), file, &|name| info.is_uncompressed(name))?;
     debug!("zip resources success");
     Ok(())
 }
 
-fn copy_resources(config: &Config) -> Result<()> {
-    debug!("copy resources");
-    let root_path = file_path::get_root_path(config)?;
-    let base_path = file_path::get_base_dir_path(config)?;
+fn copy_resources(ctx: &BuildContext, module: &Module) -> Result<()> {
+    debug!("copy resources of {}", module.name());
+    let layout = &module.layout;
+    let root_path = &layout.root;
+    let base_path = &layout.base_dir;
     //创建 base/manifest
-    let manifest_path = file_path::get_manifest_path(config)?;
-    let _manifest = fs::create_dir_all(&manifest_path)?;
-    cut_file(base_path.join("AndroidManifest.xml").to_string_lossy().to_string().as_str(), manifest_path.join("AndroidManifest.xml").to_string_lossy().to_string().as_str())?;
+    let manifest_path = &layout.manifest;
+    fs::create_dir_all(manifest_path)?;
+    let base_manifest = base_path.join("AndroidManifest.xml");
+    if base_manifest.exists() || !manifest_path.join("AndroidManifest.xml").exists() {
+        cut_file(base_manifest.to_string_lossy().to_string().as_str(), manifest_path.join("AndroidManifest.xml").to_string_lossy().to_string().as_str())?;
+    }
     //拷贝assets
-    let assets_path = file_path::get_assets_path(config)?;
+    let assets_path = &layout.assets;
 
     if assets_path.exists() {
-        let new_assets_path = file_path::get_new_assets_path(config)?;
+        let new_assets_path = &layout.new_assets;
 
-        let _assets = fs::create_dir_all(&new_assets_path)?;
+        fs::create_dir_all(new_assets_path)?;
         copy_dir(assets_path.as_path(), new_assets_path.as_path())?;
+        // 放入资源包的目录不再留在 base 中
+        if module.feature.is_none() {
+            for directory in ctx.config.asset_pack.iter().flat_map(|pack| &pack.directories) {
+                let path = new_assets_path.join(directory);
+                if path.exists() {
+                    fs::remove_dir_all(path)?;
+                }
+            }
+        }
     }
 
     //拷贝lib
-    let lib_path = file_path::get_lib_path(config)?;
+    let lib_path = &layout.lib;
 
     if lib_path.exists() {
-        let new_lib_path = file_path::get_new_lib_path(config)?;
+        let new_lib_path = &layout.new_lib;
 
-        let _lib = fs::create_dir_all(&new_lib_path)?;
+        fs::create_dir_all(new_lib_path)?;
 
         copy_dir(lib_path.as_path(), new_lib_path.as_path())?;
     }
 
-    let base_root = file_path::get_base_root_path(config)?;
-    let _root = fs::create_dir_all(&base_root)?;
+    let base_root = &layout.base_root;
+    fs::create_dir_all(base_root)?;
 
-    //拷贝unknown
-    let unknown_path = file_path::get_unknown_path(config)?;
+    //拷贝unknown, apktool.yml 中记录了这些文件在原 apk 中的路径, 放回模块的 root/ 下
+    let unknown_path = &layout.unknown;
     if unknown_path.exists() {
-        let base_root_unknown = file_path::get_new_unknown_path(config)?;
-        let _unknown = fs::create_dir_all(base_root_unknown.as_path())?;
-
-        copy_dir(unknown_path.as_path(), base_root_unknown.as_path())?;
+        let info = ApktoolInfo::read(root_path.as_path())?;
+        if info.unknown_files.is_empty() {
+            let base_root_unknown = &layout.new_unknown;
+            fs::create_dir_all(base_root_unknown.as_path())?;
+            copy_dir(unknown_path.as_path(), base_root_unknown.as_path())?;
+        }
+        for (name, _) in &info.unknown_files {
+            if let Some(value) = info.invalid(format!("unknownFiles.{}", name).as_str()) {
+                warn!("compression `{}` of unknown file {} is not an integer, keep it compressed", value, name);
+            }
+            let source = unknown_path.join(name);
+            if !source.is_file() {
+                warn!("unknown file {} listed in apktool.yml is missing", name);
+                continue;
+            }
+            let target = base_root.join(name);
+            if let Some(parent) = target.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::copy(source, target)?;
+        }
     }
     //拷贝kotlin
-    let kotlin_path = file_path::get_kotlin_path(config)?;
+    let kotlin_path = &layout.kotlin;
     if kotlin_path.exists() {
-        let new_kotlin_path = file_path::get_new_kotlin_path(config)?;
-        let _kotlin = fs::create_dir_all(new_kotlin_path.as_path())?;
+        let new_kotlin_path = &layout.new_kotlin;
+        fs::create_dir_all(new_kotlin_path.as_path())?;
         copy_dir(kotlin_path.as_path(), new_kotlin_path.as_path())?;
     }
     //拷贝META-INF
 
-    let meta_path = file_path::get_meta_path(config)?;
+    let meta_path = &layout.meta;
 
     if meta_path.exists() {
-        let new_meta_path = file_path::get_new_meta_path(config)?;
-        let _meta = fs::create_dir_all(new_meta_path.as_path())?;
+        let new_meta_path = &layout.new_meta;
+        fs::create_dir_all(new_meta_path.as_path())?;
         copy_dir(meta_path.as_path(), new_meta_path.as_path())?;
         for entry in fs::read_dir(new_meta_path.as_path())? {
             let entry = entry?;
 
-            if !entry.file_type()?.is_dir() {
-                if entry.file_name().to_string_lossy().contains(".RSA") || entry.file_name().to_string_lossy().contains(".MF") || entry.file_name().to_string_lossy().contains(".SF") {
-                    fs::remove_file(entry.path())?;
-                }
+            if !entry.file_type()?.is_dir() && (entry.file_name().to_string_lossy().contains(".RSA") || entry.file_name().to_string_lossy().contains(".MF") || entry.file_name().to_string_lossy().contains(".SF")) {
+                fs::remove_file(entry.path())?;
             }
         }
     }
 
     //dex 文件夹
-    let dex_path = file_path::get_dex_path(config)?;
-    let _dex = fs::create_dir_all(&dex_path)?;
+    let dex_path = &layout.dex;
+    fs::create_dir_all(dex_path)?;
     //拷贝classes.dex
     for entry in fs::read_dir(root_path)? {
         let entry = entry?;
-        if !entry.file_type()?.is_dir() {
-            if entry.file_name().to_string_lossy().contains(".dex") {
-                fs::copy(entry.path(), dex_path.join(entry.file_name().to_string_lossy().to_string()))?;
-            }
+        if !entry.file_type()?.is_dir() && entry.file_name().to_string_lossy().contains(".dex") {
+            fs::copy(entry.path(), dex_path.join(entry.file_name().to_string_lossy().to_string()))?;
         }
     }
     debug!("copy resources success");
     Ok(())
 }
 
-fn unzip_apk(config: &Config) -> Result<()> {
-    debug!("unzip apk");
-    let base_apk_path = file_path::get_base_apk_path(config)?;
-    let base_path = file_path::get_base_dir_path(config)?;
+fn unzip_apk(module: &Module) -> Result<()> {
+    debug!("unzip apk of {}", module.name());
+    let base_apk_path = &module.layout.base_apk;
+    let base_path = &module.layout.base_dir;
 
     unzip(base_apk_path.to_string_lossy().to_string().as_str(), base_path.to_string_lossy().to_string().as_str())?;
     debug!("unzip apk success");
     Ok(())
 }
 
-fn link_resources(config: &Config) -> Result<()> {
-    debug!("link resources");
-    let res_zip_path = file_path::get_resources_zip_path(config)?;
-    let base_apk_path = file_path::get_base_apk_path(config)?;
-    let manifest_path = Path::new(config.apk.apk_outdir.as_str()).join("AndroidManifest.xml");
-    info!("exec command: aapt2 link --proto-format -o {} -I {} --min-sdk-version {} --target-sdk-version {} --version-code {} --version-name {} --manifest {} -R {} --auto-add-overlay",base_apk_path.to_string_lossy(), config.jar.android_jar_path, config.apk.min_sdk_version, config.apk.target_sdk_version, config.apk.version_code, config.apk.version_name,manifest_path.to_string_lossy(),res_zip_path.to_string_lossy());
+fn link_resources(ctx: &BuildContext, module: &Module) -> Result<()> {
+    let config = ctx.config;
+    debug!("link resources of {}", module.name());
+    let layout = &module.layout;
+    let flat_list_path = &layout.flat_list;
+    let base_apk_path = &layout.base_apk;
+    let mut manifest_path = layout.root.join("AndroidManifest.xml");
+    // 版本信息与 base 一致
+    let mut args = Versions::resolve(config, &ctx.layout)?.args();
+    if let Some(feature) = module.feature {
+        // feature 的资源引用 base 中的资源, 且使用单独的 package id
+        let content = fs::read_to_string(manifest_path.as_path()).with_context(|| format!("read {}", manifest_path.to_string_lossy()))?;
+        fs::create_dir_all(layout.cache_dir.as_path())?;
+        fs::write(layout.feature_manifest.as_path(), manifest::feature(content.as_str(), feature)?)?;
+        manifest_path = layout.feature_manifest.clone();
+        let package_id = feature_package_id(ctx, module)?;
+        let base = ctx.original_base_apk();
+        if !base.is_file() {
+            return Err(anyhow!("{} not found, decode {} again with --force", base.to_string_lossy(), config.apk.apk_path));
+        }
+        args.extend(["-I".to_string(), base.to_string_lossy().to_string(), "--package-id".to_string(), format!("0x{:02x}", package_id)]);
+        if package_id < apktool::BASE_PACKAGE_ID {
+            args.push("--allow-reserved-package-id".to_string());
+        }
+    }
+    info!("exec command: aapt2 link --proto-format -o {} -I {} {} --manifest {} -R @{} --auto-add-overlay",base_apk_path.to_string_lossy(), config.jar.android_jar_path, args.join(" "), manifest_path.to_string_lossy(),flat_list_path.to_string_lossy());
     if base_apk_path.exists() {
         fs::remove_file(base_apk_path.as_path())?;
     }
 
-    let child = Command::new("aapt2")
+    tool::exec("link resources", Command::new("aapt2")
         .arg("link")
         .arg("--proto-format")
         .arg("-o")
         .arg(base_apk_path.to_string_lossy().to_string().as_str())
         .arg("-I")
-        .arg(format!("{}", config.jar.android_jar_path).as_str())
-        .arg("--min-sdk-version")
-        .arg(format!("{}", config.apk.min_sdk_version).as_str())
-        .arg("--target-sdk-version")
-        .arg(format!("{}", config.apk.target_sdk_version).as_str())
-        .arg("--version-code")
-        .arg(format!("{}", config.apk.version_code).as_str())
-        .arg("--version-name")
-        .arg(format!("{}", config.apk.version_name).as_str())
+        .arg(config.jar.android_jar_path.as_str())
+        .args(args)
         .arg("--manifest")
         .arg(manifest_path.to_string_lossy().to_string().as_str())
         .arg("-R")
-        .arg(res_zip_path.to_string_lossy().to_string().as_str())
-        .arg("--auto-add-overlay")
-        .stderr(Stdio::inherit())
-        .stdout(Stdio::inherit())
-        .output()
-        .expect("failed to execute process");
-    if child.status.success() {
-        debug!("link resources success");
-        Ok(())
-    } else {
-        Err(anyhow!("link resources failed"))
-    }
+        .arg(format!("@{}", flat_list_path.to_string_lossy()).as_str())
+        .arg("--auto-add-overlay"))?;
+    debug!("link resources success");
+    Ok(())
 }
 
-fn compile_resources(config: &Config) -> Result<()> {
-    debug!("compile resources");
-    let res_path = file_path::get_res_path(config)?;
-    let res_zip_path = file_path::get_resources_zip_path(config)?;
-
-    info!("exec command: aapt2 compile --dir {} -o {}", res_path.to_string_lossy().to_string(), res_zip_path.to_string_lossy().to_string());
-
-    if res_zip_path.exists() {
-        fs::remove_file(res_zip_path.as_path())?;
+/// feature 模块资源的 package id, 见 [`apktool::package_ids`].
+fn feature_package_id(ctx: &BuildContext, module: &Module) -> Result<u32> {
+    let features: Vec<&Module> = ctx.modules.iter().filter(|other| other.feature.is_some()).collect();
+    let index = features.iter().position(|other| other.name() == module.name())
+        .ok_or_else(|| anyhow!("{} is not a feature module", module.name()))?;
+    let mut forced = Vec::with_capacity(features.len());
+    let mut invalid = None;
+    for (i, feature) in features.iter().enumerate() {
+        let info = ApktoolInfo::read(feature.layout.root.as_path())?;
+        if i == index {
+            invalid = info.invalid("packageInfo.forcedPackageId").map(str::to_string);
+        }
+        forced.push(info.package_id);
     }
-    let child = Command::new("aapt2")
-        .arg("compile")
-        .arg("--dir")
-        .arg(res_path.to_string_lossy().to_string())
-        .arg("-o")
-        .arg(res_zip_path.to_string_lossy().to_string())
-        .stderr(Stdio::inherit())
-        .stdout(Stdio::inherit())
-        .output()?;
-    // .expect("failed to execute process");
-    if child.status.success() {
-        debug!("compile resources success");
-        Ok(())
-    } else {
-        Err(anyhow!("compile resources failed"))
+    let package_id = apktool::package_ids(forced.as_slice())?[index];
+    if let Some(value) = invalid {
+        warn!("forcedPackageId `{}` of {} is not an integer, use 0x{:02x}", value, module.name(), package_id);
     }
+    Ok(package_id)
+}
+
+fn compile_resources(module: &Module) -> Result<()> {
+    debug!("compile resources of {}", module.name());
+    resources::compile_changed(&module.layout)?;
+    debug!("compile resources success");
+    Ok(())
 }
 
 //decode apk
-fn decode_apk(config: &Config) -> Result<()> {
-    debug!("decode apk");
-    info!("exec command: java -jar {} d {} -s -o {}", config.jar.apktool_path, config.apk.apk_path, config.apk.apk_outdir);
-    let child = Command::new("java")
-        .args(&["-jar", format!("{}", config.jar.apktool_path).as_str(), "d", format!("{}", config.apk.apk_path).as_str(), "-s", "-o", format!("{}", config.apk.apk_outdir).as_str()])
-        .stderr(Stdio::inherit())
-        .stdout(Stdio::inherit())
-        .output()?;
-    if child.status.success() {
-        debug!("decode apk success");
-        Ok(())
+fn decode_apk(config: &Config, module: &Module) -> Result<()> {
+    debug!("decode apk of {}", module.name());
+    let apk_path = Path::new(module.apk_path(config));
+    if module.feature.is_none() && split_apks::is_split_set(apk_path) {
+        split_apks::decode(config, apk_path, module.layout.root.as_path(), module.layout.splits.as_path())?;
     } else {
-        Err(anyhow!("decode apk failed"))
+        apktool_decode(config, apk_path, module.layout.root.as_path())?;
     }
+    debug!("decode apk success");
+    Ok(())
+}
+
+fn apktool_decode(config: &Config, apk_path: &Path, outdir: &Path) -> Result<()> {
+    info!("exec command: java -jar {} d {} -s -o {}", config.jar.apktool_path, apk_path.to_string_lossy(), outdir.to_string_lossy());
+    tool::exec("decode apk", Command::new("java")
+        .args(["-jar", config.jar.apktool_path.as_str(), "d"])
+        .arg(apk_path)
+        .arg("-s")
+        .arg("-o")
+        .arg(outdir))?;
+    Ok(())
 }
 
 
@@ -415,7 +733,7 @@ fn zip_dir<T>(
     it: &mut dyn Iterator<Item=DirEntry>,
     prefix: &str,
     writer: T,
-    method: zip::CompressionMethod,
+    stored: &dyn Fn(&str) -> bool,
 ) -> Result<()>
     where
         T: Write + Seek,
@@ -423,7 +741,7 @@ fn zip_dir<T>(
     debug!("zip dir {}", prefix);
     let mut zip = zip::ZipWriter::new(writer);
     let options = FileOptions::default()
-        .compression_method(method)
+        .compression_method(zip::CompressionMethod::Deflated)
         .unix_permissions(0o755);
 
     let mut buffer = Vec::new();
@@ -434,7 +752,9 @@ fn zip_dir<T>(
         // Write file or directory explicitly
         // Some unzip tools unzip files with directory paths correctly, some do not!
         if path.is_file() {
-            zip.start_file(path_to_string(name), options)?;
+            let name = path_to_string(name);
+            let method = if stored(name.as_str()) { zip::CompressionMethod::Stored } else { zip::CompressionMethod::Deflated };
+            zip.start_file(name, options.compression_method(method))?;
             let mut f = File::open(path)?;
 
             f.read_to_end(&mut buffer)?;
@@ -611,11 +931,11 @@ fn copy_dir_content(src: &Path, dst: &Path) -> Result<()> {
         let entry = entry?;
 
         if entry.file_type()?.is_dir() {
-            let _size = copy_dir(&entry.path(), &dst.join(entry.file_name()))?;
+            copy_dir(&entry.path(), &dst.join(entry.file_name()))?;
             // copied += size;
         } else {
             let _size = entry.metadata()?.len();
-            fs::copy(&entry.path(), &dst.join(entry.file_name()))?;
+            fs::copy(entry.path(), dst.join(entry.file_name()))?;
             // copied += size;
         }
 
@@ -626,132 +946,160 @@ fn copy_dir_content(src: &Path, dst: &Path) -> Result<()> {
 }
 
 fn launch_app(config: &Config) -> Result<()> {
+    adb_start(config.config.main_activity.as_str())
+}
+
+fn adb_start(activity: &str) -> Result<()> {
     debug!("launch app");
-    info!("exec command: adb shell am start -n {}", config.config.main_activity);
-    let child = Command::new("adb")
+    info!("exec command: adb shell am start -n {}", activity);
+    tool::exec("launch app", Command::new("adb")
         .arg("shell")
         .arg("am")
         .arg("start")
         .arg("-n")
-        .arg(format!("{}", config.config.main_activity))
-        .stderr(Stdio::inherit())
-        .stdout(Stdio::inherit())
-        .output()?;
-    if child.status.success() {
-        debug!("launch app success");
-        Ok(())
-    } else {
-        Err(anyhow!("launch app failed"))
-    }
+        .arg(activity))?;
+    debug!("launch app success");
+    Ok(())
 }
 
-fn apktool_rm_cache(config: &Config) -> Result<()> {
+fn apktool_rm_cache(ctx: &BuildContext) -> Result<()> {
     debug!("apktool rm cache");
-    let cache_path = file_path::get_apk_build_path(config)?;
+    let cache_path = &ctx.layout.apk_build;
     if cache_path.exists() {
-        fs::remove_dir_all(&cache_path)?;
+        fs::remove_dir_all(cache_path)?;
     }
 
-    let dist_path = file_path::get_apk_dist_path(config)?;
+    let dist_path = &ctx.layout.apk_dist;
     if dist_path.exists() {
-        fs::remove_dir_all(&dist_path)?;
+        fs::remove_dir_all(dist_path)?;
     }
     debug!("apktool rm cache success");
     Ok(())
 }
 
 
-fn apktool_build(config: &Config, time: &str) -> Result<()> {
+fn apktool_build(ctx: &BuildContext) -> Result<()> {
+    let config = ctx.config;
     debug!("apktool build");
-    let apk_unsign_path = file_path::get_apk_un_sign_path(config, time)?;
+    let apk_unsign_path = &ctx.apk_un_sign;
     info!("exec command: java -jar {} b {} -o {}", config.jar.apktool_path, config.build_apk.app_path, apk_unsign_path.to_string_lossy().to_string());
-    let child = Command::new("java")
+    tool::exec("apktool build", Command::new("java")
         .arg("-jar")
         .arg(config.jar.apktool_path.as_str())
         .arg("b")
         .arg(config.build_apk.app_path.as_str())
         .arg("-o")
-        .arg(apk_unsign_path.to_string_lossy().to_string())
-        .stderr(Stdio::inherit())
-        .stdout(Stdio::inherit())
-        .output()?;
-    if child.status.success() {
-        debug!("apktool build success");
-        Ok(())
-    } else {
-        Err(anyhow!("apktool build failed"))
-    }
+        .arg(apk_unsign_path.to_string_lossy().to_string()))?;
+    debug!("apktool build success");
+    Ok(())
 }
 
-fn zipalign(config: &Config, time: &str) -> Result<()> {
+fn zipalign(ctx: &BuildContext) -> Result<()> {
+    let config = ctx.config;
     debug!("zipalign");
-    let apk_unsign_path = file_path::get_apk_un_sign_path(config, time)?;
-    let apk_zipalign_path = file_path::get_apk_zipalign_path(config, time)?;
-    info!("exec command: zipalign -v -p 4 {} {}", apk_unsign_path.to_string_lossy().to_string(), apk_zipalign_path.to_string_lossy().to_string());
-    let child = Command::new("zipalign")
-        .arg("-v")
-        .arg("-p")
-        .arg("4")
-        .arg(apk_unsign_path.to_string_lossy().to_string())
-        .arg(apk_zipalign_path.to_string_lossy().to_string())
-        .stderr(Stdio::inherit())
-        .stdout(Stdio::inherit())
-        .output()?;
-    if child.status.success() {
-        debug!("zipalign success");
-        Ok(())
-    } else {
-        Err(anyhow!("zipalign failed"))
-    }
+    let apk_unsign_path = &ctx.apk_un_sign;
+    let apk_zipalign_path = &ctx.apk_zipalign;
+    let page_size = config.build_apk.zipalign_page_size;
+    info!("zipalign {} -> {}, page size {}KiB", apk_unsign_path.to_string_lossy(), apk_zipalign_path.to_string_lossy(), page_size);
+    zipalign::align(apk_unsign_path.as_path(), apk_zipalign_path.as_path(), page_size)?;
+    debug!("zipalign success");
+    Ok(())
 }
 
-fn apksigner(config: &Config, time: &str) -> Result<String> {
+fn apksigner(ctx: &BuildContext) -> Result<String> {
+    let config = ctx.config;
+    let apk_zipalign_path = &ctx.apk_zipalign;
+
+    let apk_sign_path = &ctx.apk_sign;
+
+    apksigner_file(config, apk_zipalign_path.as_path(), apk_sign_path.as_path())?;
+    Ok(apk_sign_path.to_string_lossy().to_string())
+}
+
+fn apksigner_file(config: &Config, apk_path: &Path, apk_sign_path: &Path) -> Result<()> {
     debug!("apksigner");
-    let apk_zipalign_path = file_path::get_apk_zipalign_path(config, time)?;
+    info!("sign {} -> {} with v1, v2, v3, alias {}", apk_path.to_string_lossy(), apk_sign_path.to_string_lossy(), config.sign.keystore_key_alias);
+    apk_signer::sign(config, apk_path, apk_sign_path)
+        .with_context(|| Error::SigningFailed(format!("sign {}", apk_path.to_string_lossy())))?;
+    debug!("apksigner success");
+    Ok(())
+}
 
-    let apk_sign_path = file_path::get_apk_sign_path(config, time)?;
+fn apksigner_verify(apk_path: &Path) -> Result<()> {
+    debug!("apksigner verify");
+    info!("exec command: apksigner verify --verbose {}", apk_path.to_string_lossy());
+    tool::exec("apksigner verify", Command::new("apksigner")
+        .arg("verify")
+        .arg("--verbose")
+        .arg(apk_path.to_string_lossy().to_string()))?;
+    debug!("apksigner verify success");
+    Ok(())
+}
 
-    info!("exec command: apksigner sign --ks {} --ks-pass pass:{} --out {} {}", config.sign.keystore, config.sign.keystore_pass, apk_sign_path.to_string_lossy().to_string(), apk_zipalign_path.to_string_lossy().to_string());
-    let child = Command::new("apksigner")
-        .arg("sign")
-        .arg("--ks")
-        .arg(config.sign.keystore.as_str())
-        .arg("--ks-pass")
-        .arg(format!("pass:{}", config.sign.keystore_pass))
-        .arg("--out")
-        .arg(apk_sign_path.to_string_lossy().to_string())
-        .arg(apk_zipalign_path.to_string_lossy().to_string())
-        .stderr(Stdio::inherit())
-        .stdout(Stdio::inherit())
-        .output()?;
-    if child.status.success() {
-        debug!("apksigner success");
-        Ok(apk_sign_path.to_string_lossy().to_string())
-    } else {
-        Err(anyhow!("apksigner failed"))
-    }
+fn aapt2_dump_badging(apk_path: &Path) -> Result<()> {
+    debug!("dump badging");
+    info!("exec command: aapt2 dump badging {}", apk_path.to_string_lossy());
+    tool::exec("dump badging", Command::new("aapt2")
+        .arg("dump")
+        .arg("badging")
+        .arg(apk_path.to_string_lossy().to_string()))?;
+    debug!("dump badging success");
+    Ok(())
 }
 
-fn install_apk(config: &Config, time: &str) -> Result<()> {
+fn install_apk(ctx: &BuildContext) -> Result<()> {
+    let apk_sign_path = &ctx.apk_sign;
+    adb_install(apk_sign_path.as_path())
+}
+
+fn adb_install(apk_path: &Path) -> Result<()> {
     debug!("install apk");
-    let apk_sign_path = file_path::get_apk_sign_path(config, time)?;
-    info!("exec command: adb install -r {}", apk_sign_path.to_string_lossy().to_string());
-    let child = Command::new("adb")
+    info!("exec command: adb install -r {}", apk_path.to_string_lossy().to_string());
+    tool::exec("install apk", Command::new("adb")
         .arg("install")
         .arg("-r")
-        .arg(apk_sign_path.to_string_lossy().to_string())
-        .stderr(Stdio::inherit())
-        .stdout(Stdio::inherit())
-        .output()?;
-    if child.status.success() {
-        debug!("install apk success");
-        Ok(())
-    } else {
-        Err(anyhow!("install apk failed"))
-    }
+        .arg(apk_path.to_string_lossy().to_string()))?;
+    debug!("install apk success");
+    Ok(())
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::BuildApksConfig;
+    use crate::keystore::tests::{testdata, STORE_PASS};
 
+    fn args(build_apks: &str) -> Vec<String> {
+        let config = Config { build_apks: toml::from_str::<BuildApksConfig>(build_apks).unwrap(), ..test_util::config() };
+        build_apks_args(&config)
+    }
 
+    #[test]
+    fn build_apks_options() {
+        assert!(args("").is_empty());
+        assert!(args(r#"mode = "default""#).is_empty());
+        assert_eq!(args(r#"mode = "universal""#), ["--mode=universal"]);
+        assert_eq!(args("connected_device = true\nlocal_testing = true"), ["--connected-device", "--local-testing"]);
+        assert_eq!(args(r#"mode = "system"
+device_spec = "spec.json"
+device_tier = 1"#), ["--mode=system", "--device-spec=spec.json", "--device-tier=1"]);
+    }
 
-
+    #[test]
+    fn keystore_entries() {
+        let mut config = test_util::config();
+        config.sign = Sign::new(testdata("abtool.jks").to_string_lossy(), Secret::new(STORE_PASS), "ec", Secret::new("ec-key"));
+        let info = list_keystore(&config).unwrap();
+        assert_eq!(info.format, "JKS");
+        let mut entries = info.entries;
+        entries.sort();
+        assert_eq!(entries, [
+            ("abtool".to_string(), "PrivateKeyEntry".to_string()),
+            ("ca".to_string(), "trustedCertEntry".to_string()),
+            ("ec".to_string(), "PrivateKeyEntry".to_string()),
+        ]);
+
+        config.sign.keystore_key_pass = Secret::new("wrong");
+        assert!(list_keystore(&config).is_err());
+    }
+}
@@ -0,0 +1,184 @@
+use anyhow::{anyhow, Result};
+
+use crate::config::{AssetPack, AssetPackDelivery, FeatureModule, ModuleDelivery};
+
+pub(crate) const DIST_NAMESPACE: &str = "http://schemas.android.com/apk/distribution";
+
+/// 设置 feature 模块 manifest 的 `split` 属性, 并用配置生成的 `<dist:module>` 替换原有的声明.
+pub(crate) fn feature(content: &str, module: &FeatureModule) -> Result<String> {
+    let start = content.find("<manifest").ok_or_else(|| anyhow!("<manifest> not found"))?;
+    let end = tag_end(content, start).ok_or_else(|| anyhow!("<manifest> is not closed"))?;
+    let self_closing = content[..end].ends_with('/');
+    let tag_end = if self_closing { end - 1 } else { end };
+
+    let mut tag = content[start..tag_end].trim_end().to_string();
+    if !tag.contains("xmlns:dist=") {
+        tag.push_str(format!(" xmlns:dist=\"{}\"", DIST_NAMESPACE).as_str());
+    }
+    match attribute_range(tag.as_str(), "split") {
+        Some(range) => tag.replace_range(range, module.name.as_str()),
+        None => tag.push_str(format!(" split=\"{}\"", module.name).as_str()),
+    }
+
+    let mut body = if self_closing { String::from("\n</manifest>") } else { content[end + 1..].to_string() };
+    let mut title = module.title.clone();
+    if let Some((range, old)) = dist_module(body.as_str()) {
+        if title.is_none() {
+            title = attribute_range(old.as_str(), "dist:title").map(|range| old[range].to_string());
+        }
+        body.replace_range(range, "");
+    }
+
+    let delivery = match module.delivery {
+        ModuleDelivery::InstallTime => "install-time",
+        ModuleDelivery::OnDemand => "on-demand",
+    };
+    let title = title.map(|title| format!(" dist:title=\"{}\"", title)).unwrap_or_default();
+    let element = format!(
+        "\n    <dist:module dist:instant=\"false\"{}>\n        <dist:delivery>\n            <dist:{}/>\n        </dist:delivery>\n        <dist:fusing dist:include=\"{}\"/>\n    </dist:module>",
+        title, delivery, module.fusing,
+    );
+    Ok(format!("{}{}>{}{}", &content[..start], tag, element, body))
+}
+
+/// manifest 的 `package` 属性.
+pub(crate) fn package(content: &str) -> Option<String> {
+    let start = content.find("<manifest")?;
+    let tag = &content[start..tag_end(content, start)?];
+    attribute_range(tag, "package").map(|range| tag[range].to_string())
+}
+
+/// 资源包的 manifest, `package` 必须与 base 一致.
+pub(crate) fn asset_pack(package: &str, pack: &AssetPack) -> String {
+    let delivery = match pack.delivery {
+        AssetPackDelivery::InstallTime => "install-time",
+        AssetPackDelivery::FastFollow => "fast-follow",
+        AssetPackDelivery::OnDemand => "on-demand",
+    };
+    format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<manifest xmlns:android=\"http://schemas.android.com/apk/res/android\" xmlns:dist=\"{}\" package=\"{}\" split=\"{}\">\n    <dist:module dist:type=\"asset-pack\">\n        <dist:fusing dist:include=\"true\"/>\n        <dist:delivery>\n            <dist:{}/>\n        </dist:delivery>\n    </dist:module>\n</manifest>\n",
+        DIST_NAMESPACE, package, pack.name, delivery,
+    )
+}
+
+/// 合并 config split 后, base 不再依赖它们: 去掉 split 安装相关的属性和 Play 写入的 meta-data.
+pub(crate) fn merged_base(content: &str) -> String {
+    let mut content = content.to_string();
+    for name in ["android:isSplitRequired", "android:requiredSplitTypes", "android:splitTypes"] {
+        while let Some(range) = attribute_range(content.as_str(), name) {
+            // 连同属性名和前面的空白一起删除
+            let start = content[..range.start - name.len() - 2].trim_end().len();
+            content.replace_range(start..range.end + 1, "");
+        }
+    }
+    for name in ["com.android.vending.splits.required", "com.android.vending.splits"] {
+        let pattern = format!("android:name=\"{}\"", name);
+        while let Some(at) = content.find(pattern.as_str()) {
+            let Some(start) = content[..at].rfind("<meta-data") else { break };
+            let Some(end) = tag_end(content.as_str(), start) else { break };
+            let start = content[..start].trim_end().len();
+            content.replace_range(start..=end, "");
+        }
+    }
+    content
+}
+
+/// 从 `start` 处的 `<` 开始, 跳过引号中的内容, 返回标签结束的 `>` 的位置.
+fn tag_end(content: &str, start: usize) -> Option<usize> {
+    let mut quote = None;
+    for (i, c) in content[start..].char_indices() {
+        match (quote, c) {
+            (None, '"' | '\'') => quote = Some(c),
+            (Some(q), c) if q == c => quote = None,
+            (None, '>') => return Some(start + i),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// 标签中属性值的位置, 属性名需完整匹配(`split` 不会匹配 `android:isFeatureSplit`).
+fn attribute_range(tag: &str, name: &str) -> Option<std::ops::Range<usize>> {
+    let pattern = format!("{}=\"", name);
+    let mut from = 0;
+    while let Some(found) = tag[from..].find(pattern.as_str()) {
+        let at = from + found;
+        if tag[..at].ends_with(char::is_whitespace) {
+            let value = at + pattern.len();
+            return tag[value..].find('"').map(|len| value..value + len);
+        }
+        from = at + pattern.len();
+    }
+    None
+}
+
+/// 原有的 `<dist:module>` 元素(含前面的空白)及其开始标签.
+fn dist_module(body: &str) -> Option<(std::ops::Range<usize>, String)> {
+    let start = body.find("<dist:module")?;
+    let end = tag_end(body, start)?;
+    let open = body[start..=end].to_string();
+    let end = if open.ends_with("/>") {
+        end + 1
+    } else {
+        let close = "</dist:module>";
+        body[end..].find(close).map(|i| end + i + close.len())?
+    };
+    let start = body[..start].trim_end().len();
+    Some((start..end, open))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DIST: &str = "xmlns:dist=\"http://schemas.android.com/apk/distribution\"";
+
+    fn module_element(title: &str, delivery: &str) -> String {
+        format!(
+            "\n    <dist:module dist:instant=\"false\"{}>\n        <dist:delivery>\n            <dist:{}/>\n        </dist:delivery>\n        <dist:fusing dist:include=\"true\"/>\n    </dist:module>",
+            title, delivery,
+        )
+    }
+
+    #[test]
+    fn feature_replaces_existing_split() {
+        let content = "<?xml version=\"1.0\"?>\n<manifest xmlns:android=\"http://schemas.android.com/apk/res/android\" android:isFeatureSplit=\"true\" package=\"com.example\" split=\"config.camera\">\n    <application android:hasCode=\"false\"/>\n</manifest>\n";
+        let module = FeatureModule::new("camera", "camera.apk");
+        let manifest = feature(content, &module).unwrap();
+        assert_eq!(manifest, format!(
+            "<?xml version=\"1.0\"?>\n<manifest xmlns:android=\"http://schemas.android.com/apk/res/android\" android:isFeatureSplit=\"true\" package=\"com.example\" split=\"camera\" {}>{}\n    <application android:hasCode=\"false\"/>\n</manifest>\n",
+            DIST, module_element("", "install-time"),
+        ));
+        // 再次处理结果不变
+        assert_eq!(feature(manifest.as_str(), &module).unwrap(), manifest);
+    }
+
+    #[test]
+    fn feature_keeps_existing_title() {
+        let content = format!(
+            "<manifest {} package=\"com.example\" split=\"camera\">\n    <dist:module dist:onDemand=\"true\" dist:title=\"@string/camera\">\n        <dist:fusing dist:include=\"false\"/>\n    </dist:module>\n    <application/>\n</manifest>",
+            DIST,
+        );
+        let module = FeatureModule::new("camera", "camera.apk").with_delivery(ModuleDelivery::OnDemand);
+        assert_eq!(feature(content.as_str(), &module).unwrap(), format!(
+            "<manifest {} package=\"com.example\" split=\"camera\">{}\n    <application/>\n</manifest>",
+            DIST, module_element(" dist:title=\"@string/camera\"", "on-demand"),
+        ));
+        // 配置中的 title 优先
+        let titled = feature(content.as_str(), &module.with_title("@string/title_camera")).unwrap();
+        assert!(titled.contains("dist:title=\"@string/title_camera\""));
+        assert!(!titled.contains("@string/camera\""));
+        assert_eq!(titled.matches("<dist:module").count(), 1);
+    }
+
+    #[test]
+    fn feature_of_self_closing_manifest() {
+        let module = FeatureModule::new("camera", "camera.apk");
+        assert_eq!(feature("<manifest package=\"com.example\"/>", &module).unwrap(), format!(
+            "<manifest package=\"com.example\" {} split=\"camera\">{}\n</manifest>",
+            DIST, module_element("", "install-time"),
+        ));
+        assert_eq!(feature("<application/>", &module).unwrap_err().to_string(), "<manifest> not found");
+        assert_eq!(feature("<manifest package=\"a>b", &module).unwrap_err().to_string(), "<manifest> is not closed");
+    }
+}
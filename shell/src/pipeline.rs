@@ -0,0 +1,512 @@
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::process::Command;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Context, Result};
+use tracing::{debug, info};
+
+use crate::cache::{self, BuildCache};
+use crate::config::{CommandStageConfig, Config};
+use crate::file_path::BuildContext;
+use crate::step::{Step, StepRange};
+use crate::tool;
+
+/// 流水线中的一个阶段, 通过声明输入输出路径确定执行顺序.
+pub trait Stage {
+    fn name(&self) -> String;
+
+    /// 阶段读取的文件或目录.
+    fn inputs(&self, ctx: &BuildContext) -> Result<Vec<PathBuf>>;
+
+    /// 阶段写入的文件或目录.
+    fn outputs(&self, ctx: &BuildContext) -> Result<Vec<PathBuf>>;
+
+    /// 返回 false 时默认跳过, 除非通过 `--only` 显式指定.
+    fn enabled(&self, _ctx: &BuildContext) -> bool {
+        true
+    }
+
+    /// 增量构建时参与指纹计算的附加信息(工具版本, 相关配置).
+    /// 返回 None 表示该阶段每次都执行.
+    fn cache_key(&self, _ctx: &BuildContext) -> Result<Option<String>> {
+        Ok(None)
+    }
+
+    fn run(&self, ctx: &BuildContext) -> Result<()>;
+}
+
+/// 阶段的执行结果和耗时.
+#[derive(Debug, Clone)]
+pub struct StageTiming {
+    pub name: String,
+    /// 未启用或输入未变化而跳过时为 true.
+    pub skipped: bool,
+    pub duration: Duration,
+}
+
+/// 按添加顺序保存阶段, 执行前根据输入输出做拓扑排序.
+#[derive(Default)]
+pub struct Pipeline {
+    stages: Vec<Box<dyn Stage>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flow {
+    Aab,
+    Apk,
+}
+
+impl Flow {
+    fn name(&self) -> &'static str {
+        match self {
+            Flow::Aab => "aab",
+            Flow::Apk => "apk",
+        }
+    }
+
+    fn steps(&self) -> &'static [Step] {
+        match self {
+            Flow::Aab => &Step::AAB,
+            Flow::Apk => &Step::APK,
+        }
+    }
+}
+
+impl Pipeline {
+    pub fn new() -> Self {
+        Pipeline::default()
+    }
+
+    /// 根据配置中的 `[pipeline]` 构建 aab 或 apk 流水线.
+    pub fn for_flow(config: &Config, flow: Flow) -> Result<Self> {
+        let pipeline_config = &config.pipeline;
+        let custom: Vec<&CommandStageConfig> = pipeline_config.stage.iter().collect();
+        let names = match flow {
+            Flow::Aab => &pipeline_config.aab,
+            Flow::Apk => &pipeline_config.apk,
+        };
+
+        let mut pipeline = Pipeline::new();
+        if names.is_empty() {
+            for step in flow.steps() {
+                pipeline.add(Box::new(*step));
+            }
+            for stage in custom.iter().filter(|stage| stage.flow.as_deref().is_none_or(|f| f == flow.name())) {
+                pipeline.add(Box::new(CommandStage::new((*stage).clone())));
+            }
+        } else {
+            for name in names {
+                match custom.iter().find(|stage| stage.name == *name) {
+                    Some(stage) => pipeline.add(Box::new(CommandStage::new((*stage).clone()))),
+                    None => pipeline.add(Box::new(Step::from_str(name)?)),
+                };
+            }
+        }
+
+        // 不属于当前流程的阶段忽略, 拼错的名称报错
+        for name in &pipeline_config.skip {
+            let name = match custom.iter().find(|stage| stage.name == *name) {
+                Some(stage) => stage.name.clone(),
+                None => Step::from_str(name).context("invalid [pipeline] skip")?.name().to_string(),
+            };
+            if pipeline.contains(name.as_str()) {
+                pipeline.skip(name.as_str());
+            }
+        }
+        Ok(pipeline)
+    }
+
+    pub fn add(&mut self, stage: Box<dyn Stage>) -> &mut Self {
+        self.stages.push(stage);
+        self
+    }
+
+    pub fn skip(&mut self, name: &str) -> &mut Self {
+        self.stages.retain(|stage| stage.name() != name);
+        self
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.stages.iter().any(|stage| stage.name() == name)
+    }
+
+    /// 拓扑排序后的阶段名称. 没有依赖关系的阶段保持添加顺序.
+    pub fn ordered(&self, ctx: &BuildContext) -> Result<Vec<String>> {
+        Ok(self.sort(ctx)?.into_iter().map(|i| self.stages[i].name()).collect())
+    }
+
+    /// 执行区间内的阶段, 返回每个阶段的耗时.
+    pub fn run(&self, ctx: &BuildContext, range: &StepRange) -> Result<Vec<StageTiming>> {
+        let order = self.sort(ctx)?;
+        let names: Vec<String> = order.iter().map(|i| self.stages[*i].name()).collect();
+        debug!("pipeline: {}", names.join(" -> "));
+
+        let selected = range.select(&names)?;
+        let mut cache = BuildCache::load(&ctx.layout)?;
+        let ignore = ctx.layout.cache_dir.as_path();
+        let tools = if ctx.config.config.incremental { cache::tool_versions(ctx.config) } else { String::new() };
+        let mut timings = Vec::with_capacity(selected.len());
+        for i in selected {
+            let stage = &self.stages[order[i]];
+            let name = stage.name();
+            let start = Instant::now();
+            if !stage.enabled(ctx) && !range.is_only(name.as_str()) {
+                debug!("skip {}", name);
+                timings.push(StageTiming { name, skipped: true, duration: start.elapsed() });
+                continue;
+            }
+
+            let key = if ctx.config.config.incremental && !range.is_only(name.as_str()) {
+                stage.cache_key(ctx)?.map(|key| format!("{}\0{}", tools, key))
+            } else {
+                None
+            };
+            if let Some(key) = &key {
+                let inputs = stage.inputs(ctx)?;
+                let outputs = stage.outputs(ctx)?;
+                let fingerprint = cache::fingerprint(name.as_str(), key, &inputs, ignore)?;
+                if cache.is_fresh(name.as_str(), fingerprint.as_str()) && outputs.iter().all(|output| output.exists()) {
+                    info!("skip {}, inputs unchanged", name);
+                    timings.push(StageTiming { name, skipped: true, duration: start.elapsed() });
+                    continue;
+                }
+            }
+
+            if let Err(e) = stage.run(ctx) {
+                cache.update(name.as_str(), None)?;
+                return Err(e);
+            }
+
+            // 记录执行后的指纹, 阶段自身改动输入(如移动文件)时下次也能命中
+            let fingerprint = match &key {
+                Some(key) => Some(cache::fingerprint(name.as_str(), key, &stage.inputs(ctx)?, ignore)?),
+                None => None,
+            };
+            cache.update(name.as_str(), fingerprint)?;
+            timings.push(StageTiming { name, skipped: false, duration: start.elapsed() });
+        }
+        Ok(timings)
+    }
+
+    /// 稳定的拓扑排序: 按添加顺序依次输出阶段, 输出前先输出它依赖的阶段.
+    fn sort(&self, ctx: &BuildContext) -> Result<Vec<usize>> {
+        let mut names = HashSet::new();
+        for stage in &self.stages {
+            if !names.insert(stage.name()) {
+                return Err(anyhow!("duplicate stage `{}` in pipeline", stage.name()));
+            }
+        }
+
+        let mut inputs = Vec::with_capacity(self.stages.len());
+        let mut outputs = Vec::with_capacity(self.stages.len());
+        for stage in &self.stages {
+            inputs.push(stage.inputs(ctx)?);
+            outputs.push(stage.outputs(ctx)?);
+        }
+
+        let len = self.stages.len();
+        let deps: Vec<Vec<usize>> = (0..len)
+            .map(|b| (0..len).filter(|a| *a != b && depends(*a, b, &inputs, &outputs)).collect())
+            .collect();
+
+        let mut order = Vec::with_capacity(len);
+        let mut state = vec![Visit::New; len];
+        for i in 0..len {
+            self.visit(i, &deps, &mut state, &mut order)?;
+        }
+        Ok(order)
+    }
+
+    fn visit(&self, i: usize, deps: &[Vec<usize>], state: &mut [Visit], order: &mut Vec<usize>) -> Result<()> {
+        match state[i] {
+            Visit::Done => return Ok(()),
+            Visit::Visiting => return Err(anyhow!("pipeline has a dependency cycle at stage `{}`", self.stages[i].name())),
+            Visit::New => {}
+        }
+        state[i] = Visit::Visiting;
+        for dep in &deps[i] {
+            self.visit(*dep, deps, state, order)?;
+        }
+        state[i] = Visit::Done;
+        order.push(i);
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Visit {
+    New,
+    Visiting,
+    Done,
+}
+
+/// b 读取 a 写入的路径(或其子路径)时, a 必须在 b 之前执行.
+/// a 写入 b 读取的目录内部时也要求 a 先执行, 但 b 自己也往里写的目录(如 apktool 工程目录)除外.
+fn depends(a: usize, b: usize, inputs: &[Vec<PathBuf>], outputs: &[Vec<PathBuf>]) -> bool {
+    let reads = |reader: usize, writer: usize| {
+        inputs[reader].iter().any(|input| outputs[writer].iter().any(|output| input.starts_with(output)))
+    };
+    let writes_into = |writer: usize, reader: usize| {
+        inputs[reader].iter()
+            .filter(|input| !outputs[reader].iter().any(|own| own.starts_with(input)))
+            .any(|input| outputs[writer].iter().any(|output| output.starts_with(input)))
+    };
+    reads(b, a) || (writes_into(a, b) && !reads(a, b))
+}
+
+/// 配置文件中 `[[pipeline.stage]]` 定义的外部命令阶段.
+pub struct CommandStage {
+    config: CommandStageConfig,
+}
+
+impl CommandStage {
+    pub(crate) fn new(config: CommandStageConfig) -> Self {
+        CommandStage { config }
+    }
+
+    fn paths(&self, paths: &[String], ctx: &BuildContext) -> Vec<PathBuf> {
+        paths.iter().map(|path| PathBuf::from(expand(path, ctx))).collect()
+    }
+}
+
+impl Stage for CommandStage {
+    fn name(&self) -> String {
+        self.config.name.clone()
+    }
+
+    fn inputs(&self, ctx: &BuildContext) -> Result<Vec<PathBuf>> {
+        Ok(self.paths(&self.config.inputs, ctx))
+    }
+
+    fn outputs(&self, ctx: &BuildContext) -> Result<Vec<PathBuf>> {
+        Ok(self.paths(&self.config.outputs, ctx))
+    }
+
+    fn cache_key(&self, _ctx: &BuildContext) -> Result<Option<String>> {
+        Ok(self.config.cache.then(|| self.config.command.join("\0")))
+    }
+
+    fn run(&self, ctx: &BuildContext) -> Result<()> {
+        debug!("{}", self.config.name);
+        let args: Vec<String> = self.config.command.iter().map(|arg| expand(arg, ctx)).collect();
+        let (program, args) = args.split_first().ok_or_else(|| anyhow!("stage `{}` has an empty command", self.config.name))?;
+        let shown: Vec<String> = args.iter().map(|arg| ctx.config.redact_arg(arg)).collect();
+        info!("exec command: {} {}", program, shown.join(" "));
+        tool::exec(self.config.name.as_str(), Command::new(program).args(args))
+            .map_err(|e| e.redact(ctx.config))?;
+        debug!("{} success", self.config.name);
+        Ok(())
+    }
+}
+
+/// 替换 `{apk_outdir}`, `{app_path}`, `{app_name}`, `{time}` 占位符.
+fn expand(value: &str, ctx: &BuildContext) -> String {
+    value.replace("{apk_outdir}", ctx.config.apk.apk_outdir.as_str())
+        .replace("{app_path}", ctx.config.build_apk.app_path.as_str())
+        .replace("{app_name}", ctx.config.apk.app_name.as_str())
+        .replace("{time}", ctx.time.as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::test_util::{config, config_in, TempDir};
+
+    /// 输入输出固定的测试阶段, 执行时记录名称.
+    struct TestStage {
+        name: &'static str,
+        inputs: Vec<&'static str>,
+        outputs: Vec<&'static str>,
+        enabled: bool,
+        runs: Rc<RefCell<Vec<String>>>,
+    }
+
+    impl Stage for TestStage {
+        fn name(&self) -> String {
+            self.name.to_string()
+        }
+
+        fn inputs(&self, _ctx: &BuildContext) -> Result<Vec<PathBuf>> {
+            Ok(self.inputs.iter().map(PathBuf::from).collect())
+        }
+
+        fn outputs(&self, _ctx: &BuildContext) -> Result<Vec<PathBuf>> {
+            Ok(self.outputs.iter().map(PathBuf::from).collect())
+        }
+
+        fn enabled(&self, _ctx: &BuildContext) -> bool {
+            self.enabled
+        }
+
+        /// 输入固定, 开启增量构建时第二次执行会被跳过.
+        fn cache_key(&self, _ctx: &BuildContext) -> Result<Option<String>> {
+            Ok(Some(String::new()))
+        }
+
+        fn run(&self, _ctx: &BuildContext) -> Result<()> {
+            self.runs.borrow_mut().push(self.name.to_string());
+            Ok(())
+        }
+    }
+
+    struct Stages {
+        pipeline: Pipeline,
+        runs: Rc<RefCell<Vec<String>>>,
+    }
+
+    impl Stages {
+        fn new() -> Self {
+            Stages { pipeline: Pipeline::new(), runs: Rc::default() }
+        }
+
+        fn add(mut self, name: &'static str, inputs: &[&'static str], outputs: &[&'static str]) -> Self {
+            self.add_stage(name, inputs, outputs, true);
+            self
+        }
+
+        fn disabled(mut self, name: &'static str) -> Self {
+            self.add_stage(name, &[], &[], false);
+            self
+        }
+
+        fn add_stage(&mut self, name: &'static str, inputs: &[&'static str], outputs: &[&'static str], enabled: bool) {
+            self.pipeline.add(Box::new(TestStage {
+                name,
+                inputs: inputs.to_vec(),
+                outputs: outputs.to_vec(),
+                enabled,
+                runs: self.runs.clone(),
+            }));
+        }
+    }
+
+    #[test]
+    fn order_by_inputs_and_outputs() {
+        let config = config();
+        let ctx = BuildContext::new(&config, "t");
+        let stages = Stages::new()
+            .add("zip", &["out/base"], &["out/base.zip"])
+            .add("report", &[], &[])
+            .add("unzip", &["out/base.apk"], &["out/base"])
+            .add("link", &["out/res"], &["out/base.apk"]);
+        assert_eq!(stages.pipeline.ordered(&ctx).unwrap(), ["link", "unzip", "zip", "report"]);
+    }
+
+    #[test]
+    fn keep_added_order_without_dependencies() {
+        let config = config();
+        let ctx = BuildContext::new(&config, "t");
+        let stages = Stages::new().add("b", &["x"], &[]).add("a", &["y"], &[]).add("c", &[], &["z"]);
+        assert_eq!(stages.pipeline.ordered(&ctx).unwrap(), ["b", "a", "c"]);
+    }
+
+    #[test]
+    fn write_into_input_directory_runs_first() {
+        let config = config();
+        let ctx = BuildContext::new(&config, "t");
+        // copy 写入 zip 读取的目录内部, apktool 工程这类自己也写入的目录不算依赖
+        let stages = Stages::new()
+            .add("zip", &["out/base"], &["out/base.zip"])
+            .add("copy", &["out/assets"], &["out/base/assets"])
+            .add("build", &["app"], &["app/dist"])
+            .add("clean", &[], &["app/build"]);
+        assert_eq!(stages.pipeline.ordered(&ctx).unwrap(), ["copy", "zip", "build", "clean"]);
+    }
+
+    #[test]
+    fn reject_cycles_and_duplicates() {
+        let config = config();
+        let ctx = BuildContext::new(&config, "t");
+        let cycle = Stages::new().add("a", &["b.out"], &["a.out"]).add("b", &["a.out"], &["b.out"]);
+        assert!(cycle.pipeline.ordered(&ctx).unwrap_err().to_string().contains("dependency cycle"));
+        let duplicate = Stages::new().add("a", &[], &[]).add("a", &[], &[]);
+        assert_eq!(duplicate.pipeline.ordered(&ctx).unwrap_err().to_string(), "duplicate stage `a` in pipeline");
+    }
+
+    #[test]
+    fn reordered_pipeline_config_keeps_dependency_order() {
+        let default_order = {
+            let config = config();
+            let ctx = BuildContext::new(&config, "t");
+            Pipeline::for_flow(&config, Flow::Aab).unwrap().ordered(&ctx).unwrap()
+        };
+        let mut config = config();
+        config.pipeline.aab = Step::AAB.iter().rev().map(|step| step.name().to_string()).collect();
+        let ctx = BuildContext::new(&config, "t");
+        let ordered = Pipeline::for_flow(&config, Flow::Aab).unwrap().ordered(&ctx).unwrap();
+        let position = |order: &[String], name: &str| order.iter().position(|n| n == name).unwrap();
+        for order in [&default_order, &ordered] {
+            for pair in Step::AAB[..8].windows(2) {
+                assert!(position(order, pair[0].name()) < position(order, pair[1].name()), "{:?}", order);
+            }
+        }
+        assert_eq!(ordered, Pipeline::for_flow(&config, Flow::Aab).unwrap().ordered(&ctx).unwrap());
+    }
+
+    #[test]
+    fn skip_disabled_stages_unless_only() {
+        let dir = TempDir::new("pipeline-only");
+        let mut config = config_in(dir.path());
+        config.set_incremental(false);
+        let ctx = BuildContext::new(&config, "t");
+        let stages = Stages::new().add("build", &[], &[]).disabled("install").add("launch", &[], &[]);
+
+        let timings = stages.pipeline.run(&ctx, &StepRange::all()).unwrap();
+        assert_eq!(*stages.runs.borrow(), ["build", "launch"]);
+        assert!(timings[1].skipped);
+
+        stages.runs.borrow_mut().clear();
+        let timings = stages.pipeline.run(&ctx, &StepRange::only("install")).unwrap();
+        assert_eq!(*stages.runs.borrow(), ["install"]);
+        assert_eq!(timings.len(), 1);
+        assert!(!timings[0].skipped);
+
+        stages.runs.borrow_mut().clear();
+        stages.pipeline.run(&ctx, &StepRange::new(Some("install".into()), None, None)).unwrap();
+        assert_eq!(*stages.runs.borrow(), ["launch"]);
+    }
+
+    #[test]
+    fn skip_names_are_checked() {
+        let mut config = config();
+        config.pipeline.skip = vec!["install-apks".to_string(), "apktool_build".to_string()];
+        let ctx = BuildContext::new(&config, "t");
+        let names = Pipeline::for_flow(&config, Flow::Aab).unwrap().ordered(&ctx).unwrap();
+        assert!(!names.contains(&"install_apks".to_string()));
+        assert!(names.contains(&"build_apks".to_string()));
+
+        config.pipeline.skip = vec!["instal_apks".to_string()];
+        let e = Pipeline::for_flow(&config, Flow::Aab).err().unwrap();
+        assert_eq!(e.to_string(), "invalid [pipeline] skip");
+        assert!(format!("{:#}", e).contains("unknown step `instal_apks`"), "{:#}", e);
+    }
+
+    #[test]
+    fn only_accepts_dashed_name_of_disabled_stage() {
+        let dir = TempDir::new("pipeline-only-dashed");
+        let config = config_in(dir.path());
+        let ctx = BuildContext::new(&config, "t");
+        let stages = Stages::new().add("build_apks", &[], &[]).disabled("install_apks");
+
+        let range = StepRange::only(" install-apks ");
+        assert!(range.is_only("install_apks"));
+        let timings = stages.pipeline.run(&ctx, &range).unwrap();
+        assert_eq!(*stages.runs.borrow(), ["install_apks"]);
+        assert!(!timings[0].skipped);
+
+        // `--only` 总是执行, 即使输入没有变化
+        stages.pipeline.run(&ctx, &range).unwrap();
+        assert_eq!(*stages.runs.borrow(), ["install_apks", "install_apks"]);
+        stages.runs.borrow_mut().clear();
+        stages.pipeline.run(&ctx, &StepRange::all()).unwrap();
+        stages.pipeline.run(&ctx, &StepRange::all()).unwrap();
+        assert_eq!(*stages.runs.borrow(), ["build_apks"]);
+        stages.pipeline.run(&ctx, &StepRange::only("build-apks")).unwrap();
+        assert_eq!(*stages.runs.borrow(), ["build_apks", "build_apks"]);
+    }
+}
@@ -0,0 +1,178 @@
+use anyhow::{anyhow, Result};
+
+/// protobuf 编码中的一个字段值, 嵌套消息和字符串都是 `Bytes`.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Value<'a> {
+    Varint(u64),
+    Fixed64(u64),
+    Bytes(&'a [u8]),
+    Fixed32(u32),
+}
+
+impl<'a> Value<'a> {
+    pub(crate) fn as_u64(&self) -> Result<u64> {
+        match self {
+            Value::Varint(v) | Value::Fixed64(v) => Ok(*v),
+            Value::Fixed32(v) => Ok(u64::from(*v)),
+            Value::Bytes(_) => Err(anyhow!("expected a number, found bytes")),
+        }
+    }
+
+    /// int32 负数按 10 字节 varint 编码, 截断即可还原.
+    pub(crate) fn as_i32(&self) -> Result<i32> {
+        Ok(self.as_u64()? as i32)
+    }
+
+    pub(crate) fn as_bytes(&self) -> Result<&'a [u8]> {
+        match self {
+            Value::Bytes(bytes) => Ok(bytes),
+            _ => Err(anyhow!("expected bytes, found a number")),
+        }
+    }
+
+    pub(crate) fn as_str(&self) -> Result<String> {
+        Ok(String::from_utf8(self.as_bytes()?.to_vec())?)
+    }
+}
+
+/// 按顺序读取消息中的字段, 不认识的字段由调用方忽略即可.
+pub(crate) fn fields(data: &[u8]) -> Fields<'_> {
+    Fields { data, pos: 0 }
+}
+
+pub(crate) struct Fields<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Fields<'a> {
+    fn varint(&mut self) -> Result<u64> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = *self.data.get(self.pos).ok_or_else(|| anyhow!("truncated varint"))?;
+            self.pos += 1;
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(anyhow!("varint is too long"))
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        let end = self.pos.checked_add(len).filter(|end| *end <= self.data.len())
+            .ok_or_else(|| anyhow!("truncated field"))?;
+        let bytes = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn field(&mut self) -> Result<(u32, Value<'a>)> {
+        let key = self.varint()?;
+        let number = u32::try_from(key >> 3)?;
+        let value = match key & 7 {
+            0 => Value::Varint(self.varint()?),
+            1 => Value::Fixed64(u64::from_le_bytes(self.take(8)?.try_into()?)),
+            2 => {
+                let len = usize::try_from(self.varint()?)?;
+                Value::Bytes(self.take(len)?)
+            }
+            5 => Value::Fixed32(u32::from_le_bytes(self.take(4)?.try_into()?)),
+            wire => return Err(anyhow!("unsupported wire type {} for field {}", wire, number)),
+        };
+        Ok((number, value))
+    }
+}
+
+impl<'a> Iterator for Fields<'a> {
+    type Item = Result<(u32, Value<'a>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.pos >= self.data.len() {
+            return None;
+        }
+        let field = self.field();
+        if field.is_err() {
+            self.pos = self.data.len();
+        }
+        Some(field)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    pub(crate) fn varint(mut value: u64) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        loop {
+            let byte = (value & 0x7f) as u8;
+            value >>= 7;
+            if value == 0 {
+                bytes.push(byte);
+                return bytes;
+            }
+            bytes.push(byte | 0x80);
+        }
+    }
+
+    /// 编码 varint 字段.
+    pub(crate) fn number(field: u32, value: u64) -> Vec<u8> {
+        let mut bytes = varint(u64::from(field) << 3);
+        bytes.extend(varint(value));
+        bytes
+    }
+
+    /// 编码字符串或嵌套消息字段.
+    pub(crate) fn message(field: u32, value: &[u8]) -> Vec<u8> {
+        let mut bytes = varint(u64::from(field) << 3 | 2);
+        bytes.extend(varint(value.len() as u64));
+        bytes.extend_from_slice(value);
+        bytes
+    }
+
+    #[test]
+    fn decode_all_wire_types() {
+        let mut data = number(1, 300);
+        data.extend(message(2, b"base"));
+        data.extend(varint(3 << 3 | 1));
+        data.extend(0x0102_0304_0506_0708u64.to_le_bytes());
+        data.extend(varint(4 << 3 | 5));
+        data.extend(7u32.to_le_bytes());
+        data.extend(number(5, (-2i64) as u64));
+
+        let fields: Vec<(u32, Value)> = fields(data.as_slice()).collect::<Result<_>>().unwrap();
+        assert_eq!(fields.len(), 5);
+        assert_eq!(fields[0].0, 1);
+        assert_eq!(fields[0].1.as_u64().unwrap(), 300);
+        assert_eq!(fields[1].1.as_str().unwrap(), "base");
+        assert!(fields[1].1.as_u64().is_err());
+        assert_eq!(fields[2].1.as_u64().unwrap(), 0x0102_0304_0506_0708);
+        assert_eq!(fields[3].1.as_u64().unwrap(), 7);
+        assert!(fields[3].1.as_bytes().is_err());
+        // 负数 int32 编码为 10 字节
+        assert_eq!(number(5, (-2i64) as u64).len(), 11);
+        assert_eq!(fields[4].1.as_i32().unwrap(), -2);
+    }
+
+    #[test]
+    fn nested_message() {
+        let inner = message(1, b"arm64-v8a");
+        let outer = message(2, inner.as_slice());
+        let (number, value) = fields(outer.as_slice()).next().unwrap().unwrap();
+        assert_eq!(number, 2);
+        let (number, value) = fields(value.as_bytes().unwrap()).next().unwrap().unwrap();
+        assert_eq!((number, value.as_str().unwrap()), (1, "arm64-v8a".to_string()));
+    }
+
+    #[test]
+    fn reject_malformed_data() {
+        let error = |data: &[u8]| fields(data).find_map(|field| field.err()).map(|e| e.to_string());
+        assert_eq!(error(&[0x08, 0x80]).as_deref(), Some("truncated varint"));
+        assert_eq!(error(&[0x12, 0x05, b'a']).as_deref(), Some("truncated field"));
+        assert_eq!(error(&[0x0b]).as_deref(), Some("unsupported wire type 3 for field 1"));
+        assert_eq!(error(&[0x08, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]).as_deref(), Some("varint is too long"));
+        // 出错后停止迭代
+        assert_eq!(fields(&[0x0b, 0x08, 0x01]).count(), 1);
+    }
+}
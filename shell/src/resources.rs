@@ -0,0 +1,191 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::UNIX_EPOCH;
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, warn};
+use walkdir::WalkDir;
+
+use crate::cache;
+use crate::file_path::BuildLayout;
+use crate::tool;
+
+/// 单次 `aapt2 compile` 最多传入的文件数, 避免命令行过长.
+const COMPILE_BATCH: usize = 256;
+
+/// `res/` 下每个文件对应的 `.flat` 以及编译时的文件状态.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct FlatIndex {
+    #[serde(default)]
+    aapt2: String,
+    #[serde(default)]
+    files: BTreeMap<String, FlatEntry>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct FlatEntry {
+    size: u64,
+    modified_secs: u64,
+    modified_nanos: u32,
+    md5: String,
+    flat: String,
+}
+
+/// 只重新编译 mtime 或内容发生变化的资源文件, 删除已不存在的资源对应的 `.flat`.
+pub(crate) fn compile_changed(layout: &BuildLayout) -> Result<()> {
+    let res_path = layout.res.as_path();
+    let flat_dir = layout.flat_dir.as_path();
+    let index_path = layout.flat_index.as_path();
+
+    let mut index = read_index(index_path)?;
+    let aapt2 = cache::aapt2_version();
+    if index.aapt2 != aapt2 {
+        debug!("aapt2 version changed, recompile all resources");
+        if flat_dir.exists() {
+            fs::remove_dir_all(flat_dir)?;
+        }
+        index = FlatIndex { aapt2: aapt2.to_string(), files: BTreeMap::new() };
+    }
+    fs::create_dir_all(flat_dir)?;
+
+    let mut current = BTreeMap::new();
+    let mut changed = Vec::new();
+    // 只有代码的 feature 模块没有 res/
+    if res_path.is_dir() {
+        for entry in WalkDir::new(res_path).min_depth(2).max_depth(2).sort_by_file_name() {
+            let entry = entry?;
+            if !entry.file_type().is_file() || entry.file_name().to_string_lossy().starts_with('.') {
+                continue;
+            }
+            let relative = entry.path().strip_prefix(res_path)?.to_string_lossy().replace('\\', "/");
+            let metadata = entry.metadata()?;
+            let modified = metadata.modified()?.duration_since(UNIX_EPOCH)?;
+            let flat = flat_name(relative.as_str())?;
+
+            let previous = index.files.get(relative.as_str());
+            let unchanged_stat = previous.is_some_and(|p| {
+                p.size == metadata.len() && p.modified_secs == modified.as_secs() && p.modified_nanos == modified.subsec_nanos()
+            });
+            let md5 = match previous {
+                Some(p) if unchanged_stat => p.md5.clone(),
+                _ => cache::hex(&cache::file_md5(entry.path())?),
+            };
+            if previous.is_none_or(|p| p.md5 != md5) || !flat_dir.join(flat.as_str()).exists() {
+                changed.push(entry.path().to_path_buf());
+            }
+            current.insert(relative, FlatEntry {
+                size: metadata.len(),
+                modified_secs: modified.as_secs(),
+                modified_nanos: modified.subsec_nanos(),
+                md5,
+                flat,
+            });
+        }
+    }
+
+    for (relative, entry) in &index.files {
+        if !current.contains_key(relative) {
+            debug!("remove {}", entry.flat);
+            let flat = flat_dir.join(entry.flat.as_str());
+            if flat.exists() {
+                fs::remove_file(flat)?;
+            }
+        }
+    }
+
+    info!("compile {} of {} resources", changed.len(), current.len());
+    for batch in changed.chunks(COMPILE_BATCH) {
+        if let Err(e) = aapt2_compile(batch, flat_dir) {
+            // 部分文件可能已更新, 下次全部重新比较
+            index.files.clear();
+            write_index(index_path, &index)?;
+            return Err(e);
+        }
+    }
+
+    index.files = current;
+    write_index(index_path, &index)?;
+    write_flat_list(layout, &index)?;
+    Ok(())
+}
+
+fn aapt2_compile(files: &[PathBuf], flat_dir: &Path) -> Result<()> {
+    info!("exec command: aapt2 compile {} -o {}", files.iter().map(|f| f.to_string_lossy()).collect::<Vec<_>>().join(" "), flat_dir.to_string_lossy());
+    tool::exec("compile resources", Command::new("aapt2")
+        .arg("compile")
+        .args(files)
+        .arg("-o")
+        .arg(flat_dir))?;
+    Ok(())
+}
+
+/// 与 aapt2 的命名规则一致: `values/strings.xml` -> `values_strings.arsc.flat`,
+/// `drawable-hdpi/icon.png` -> `drawable-hdpi_icon.png.flat`.
+fn flat_name(relative: &str) -> Result<String> {
+    let (dir, file) = relative.split_once('/').ok_or_else(|| anyhow!("invalid resource path {}", relative))?;
+    if dir == "values" || dir.starts_with("values-") {
+        let stem = file.strip_suffix(".xml").ok_or_else(|| anyhow!("values resource {} is not xml", relative))?;
+        Ok(format!("{}_{}.arsc.flat", dir, stem))
+    } else {
+        Ok(format!("{}_{}.flat", dir, file))
+    }
+}
+
+/// 写入 `aapt2 link -R @file` 使用的参数文件.
+fn write_flat_list(layout: &BuildLayout, index: &FlatIndex) -> Result<()> {
+    let flat_dir = layout.flat_dir.as_path();
+    let list: Vec<String> = index.files.values()
+        .map(|entry| flat_dir.join(entry.flat.as_str()).to_string_lossy().to_string())
+        .collect();
+    if list.iter().any(|path| path.contains(char::is_whitespace)) {
+        warn!("flat file paths contain whitespace, aapt2 may not read {}", layout.flat_list.to_string_lossy());
+    }
+    fs::write(layout.flat_list.as_path(), list.join("\n"))?;
+    Ok(())
+}
+
+fn read_index(path: &Path) -> Result<FlatIndex> {
+    match fs::read_to_string(path) {
+        Ok(content) => Ok(toml::from_str(content.as_str()).unwrap_or_else(|_| {
+            warn!("ignore invalid resource index {}", path.to_string_lossy());
+            FlatIndex::default()
+        })),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(FlatIndex::default()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn write_index(path: &Path, index: &FlatIndex) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, toml::to_string(index)?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flat_name_of_values() {
+        assert_eq!(flat_name("values/strings.xml").unwrap(), "values_strings.arsc.flat");
+        assert_eq!(flat_name("values-v21/styles.xml").unwrap(), "values-v21_styles.arsc.flat");
+        assert_eq!(flat_name("values-zh-rCN/strings.xml").unwrap(), "values-zh-rCN_strings.arsc.flat");
+        assert!(flat_name("values/strings.txt").is_err());
+    }
+
+    #[test]
+    fn flat_name_of_files() {
+        assert_eq!(flat_name("drawable/bg.9.png").unwrap(), "drawable_bg.9.png.flat");
+        assert_eq!(flat_name("drawable-hdpi/icon.png").unwrap(), "drawable-hdpi_icon.png.flat");
+        assert_eq!(flat_name("layout/activity_main.xml").unwrap(), "layout_activity_main.xml.flat");
+        // 只有 `values` 限定符目录编译为 arsc, `valuesx` 之类的目录按普通文件处理
+        assert_eq!(flat_name("valuesx/a.xml").unwrap(), "valuesx_a.xml.flat");
+        assert!(flat_name("strings.xml").is_err());
+    }
+}
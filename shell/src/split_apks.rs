@@ -0,0 +1,262 @@
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use tracing::{debug, info, warn};
+use walkdir::WalkDir;
+use zip::ZipArchive;
+
+use crate::config::Config;
+use crate::manifest;
+
+/// split apk 集合的压缩包格式: bundletool/SAI 的 `.apks`, APKPure 的 `.xapk`, APKMirror 的 `.apkm`.
+const ARCHIVE_EXTENSIONS: [&str; 3] = ["apks", "xapk", "apkm"];
+
+/// ABI split 的后缀, 如 `split_config.arm64_v8a.apk`.
+const ABIS: [&str; 8] = ["armeabi", "armeabi_v7a", "arm64_v8a", "x86", "x86_64", "mips", "mips64", "riscv64"];
+
+/// `apk_path` 是否为 split apk 集合: 目录或 `.apks`/`.xapk`/`.apkm` 压缩包.
+pub(crate) fn is_split_set(path: &Path) -> bool {
+    path.is_dir() || path.extension().is_some_and(|ext| ARCHIVE_EXTENSIONS.contains(&ext.to_string_lossy().to_lowercase().as_str()))
+}
+
+#[derive(Debug, PartialEq)]
+enum SplitKind {
+    Base,
+    /// `lib/` 下的 so.
+    Abi,
+    /// 屏幕密度或语言的资源.
+    Resources,
+    /// feature 模块等无法合并的 split.
+    Other,
+}
+
+/// 按文件名区分 base 和 config split, 支持 `split_config.xxhdpi.apk`(设备导出),
+/// `config.xxhdpi.apk`(xapk) 和 `base-xxhdpi.apk`(bundletool) 三种命名.
+fn classify(file_name: &str) -> SplitKind {
+    let stem = file_name.strip_suffix(".apk").unwrap_or(file_name);
+    if stem == "base" || stem == "base-master" {
+        return SplitKind::Base;
+    }
+    let qualifier = ["split_config.", "config.", "base-"].iter().find_map(|prefix| stem.strip_prefix(prefix));
+    match qualifier {
+        Some(qualifier) if ABIS.contains(&qualifier.replace('-', "_").as_str()) => SplitKind::Abi,
+        Some(_) => SplitKind::Resources,
+        None => SplitKind::Other,
+    }
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name().unwrap_or_default().to_string_lossy().to_string()
+}
+
+/// 从 `files` 中取出 base apk. xapk 中 base 以包名命名, 如 `com.example.apk`,
+/// 此时唯一一个既不是 config split 也不以 `split_` 开头的 apk 即为 base.
+fn take_base(files: &mut Vec<PathBuf>) -> Option<PathBuf> {
+    if let Some(i) = files.iter().position(|path| classify(file_name(path).as_str()) == SplitKind::Base) {
+        return Some(files.remove(i));
+    }
+    let candidates: Vec<usize> = files.iter().enumerate()
+        .filter(|(_, path)| {
+            let name = file_name(path);
+            classify(name.as_str()) == SplitKind::Other && !name.starts_with("split_")
+        })
+        .map(|(i, _)| i)
+        .collect();
+    match candidates.as_slice() {
+        [i] => Some(files.remove(*i)),
+        _ => None,
+    }
+}
+
+/// `decode` 保存的 base split, feature 模块链接资源时引用.
+pub(crate) fn base_copy(work: &Path) -> PathBuf {
+    work.join("base.apk")
+}
+
+/// 反编译 split apk 集合到 `outdir`: 反编译 base, 再把 config split 中的 `lib/`, `res/` 和 `assets/` 合并进来.
+/// 解压和反编译 split 的中间文件保存在 `work`(`<outdir>.splits/`)下, feature split 可以在 `[[module]]` 中引用.
+pub(crate) fn decode(config: &Config, set: &Path, outdir: &Path, work: &Path) -> Result<()> {
+    let apks = if set.is_dir() {
+        set.to_path_buf()
+    } else {
+        let apks = work.join("apks");
+        extract(set, apks.as_path())?;
+        apks
+    };
+
+    let mut files: Vec<PathBuf> = WalkDir::new(apks.as_path()).sort_by_file_name().into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_file() && entry.file_name().to_string_lossy().ends_with(".apk"))
+        .map(|entry| entry.into_path())
+        .collect();
+    let base = take_base(&mut files).ok_or_else(|| anyhow!("can not find the base apk in {}", set.to_string_lossy()))?;
+    info!("decode split apks in {}, base: {}", set.to_string_lossy(), base.to_string_lossy());
+    crate::apktool_decode(config, base.as_path(), outdir)?;
+    fs::create_dir_all(work)?;
+    fs::copy(base.as_path(), base_copy(work))?;
+
+    for split in &files {
+        let name = file_name(split);
+        match classify(name.as_str()) {
+            SplitKind::Abi => {
+                debug!("merge native libraries of {}", name);
+                extract_libs(split.as_path(), outdir)?;
+            }
+            SplitKind::Resources => {
+                let decoded = work.join("decoded").join(name.trim_end_matches(".apk"));
+                if decoded.exists() {
+                    fs::remove_dir_all(decoded.as_path())?;
+                }
+                crate::apktool_decode(config, split.as_path(), decoded.as_path())?;
+                let merged = ["res", "assets", "lib"].iter()
+                    .map(|dir| merge_dir(decoded.join(dir).as_path(), outdir.join(dir).as_path()))
+                    .sum::<Result<usize>>()?;
+                debug!("merge {} files of {}", merged, name);
+            }
+            SplitKind::Base | SplitKind::Other => {
+                warn!("skip {}, add it as a [[module]] if it is a feature split", split.to_string_lossy());
+            }
+        }
+    }
+
+    let manifest_path = outdir.join("AndroidManifest.xml");
+    let content = fs::read_to_string(manifest_path.as_path()).with_context(|| format!("read {}", manifest_path.to_string_lossy()))?;
+    fs::write(manifest_path.as_path(), manifest::merged_base(content.as_str()))?;
+    Ok(())
+}
+
+/// 解压压缩包中的全部 apk.
+fn extract(archive: &Path, dest: &Path) -> Result<()> {
+    debug!("extract {} to {}", archive.to_string_lossy(), dest.to_string_lossy());
+    if dest.exists() {
+        fs::remove_dir_all(dest)?;
+    }
+    let mut zip = ZipArchive::new(File::open(archive).with_context(|| format!("open {}", archive.to_string_lossy()))?)
+        .with_context(|| format!("{} is not a zip archive", archive.to_string_lossy()))?;
+    for i in 0..zip.len() {
+        let mut entry = zip.by_index(i)?;
+        let path = match entry.enclosed_name() {
+            Some(path) if path.extension().is_some_and(|ext| ext == "apk") => dest.join(path),
+            _ => continue,
+        };
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        io::copy(&mut entry, &mut File::create(path)?)?;
+    }
+    Ok(())
+}
+
+/// ABI split 只包含 so, 直接解压 `lib/` 而不经过 apktool.
+fn extract_libs(apk: &Path, outdir: &Path) -> Result<()> {
+    let mut zip = ZipArchive::new(File::open(apk)?)?;
+    for i in 0..zip.len() {
+        let mut entry = zip.by_index(i)?;
+        let path = match entry.enclosed_name() {
+            Some(path) if path.starts_with("lib") && !entry.is_dir() => outdir.join(path),
+            _ => continue,
+        };
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        io::copy(&mut entry, &mut File::create(path)?)?;
+    }
+    Ok(())
+}
+
+/// 把 `src` 中 base 没有的文件拷贝到 `dst`, 已存在的文件以 base 为准. 返回拷贝的文件数.
+fn merge_dir(src: &Path, dst: &Path) -> Result<usize> {
+    if !src.is_dir() {
+        return Ok(0);
+    }
+    let mut merged = 0;
+    for entry in WalkDir::new(src) {
+        let entry = entry?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let target = dst.join(entry.path().strip_prefix(src)?);
+        if target.exists() {
+            continue;
+        }
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::copy(entry.path(), target)?;
+        merged += 1;
+    }
+    Ok(merged)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn classify_split_names() {
+        assert_eq!(classify("base.apk"), SplitKind::Base);
+        assert_eq!(classify("base-master.apk"), SplitKind::Base);
+
+        // 设备导出
+        assert_eq!(classify("split_config.arm64_v8a.apk"), SplitKind::Abi);
+        assert_eq!(classify("split_config.xxhdpi.apk"), SplitKind::Resources);
+        assert_eq!(classify("split_config.zh.apk"), SplitKind::Resources);
+        // bundletool
+        assert_eq!(classify("base-armeabi_v7a.apk"), SplitKind::Abi);
+        assert_eq!(classify("base-x86_64.apk"), SplitKind::Abi);
+        assert_eq!(classify("base-hdpi.apk"), SplitKind::Resources);
+        assert_eq!(classify("base-en.apk"), SplitKind::Resources);
+        // xapk
+        assert_eq!(classify("config.arm64-v8a.apk"), SplitKind::Abi);
+        assert_eq!(classify("config.xxxhdpi.apk"), SplitKind::Resources);
+        assert_eq!(classify("config.fr.apk"), SplitKind::Resources);
+
+        assert_eq!(classify("split_camera.apk"), SplitKind::Other);
+        assert_eq!(classify("com.example.apk"), SplitKind::Other);
+        assert_eq!(classify("camera-master.apk"), SplitKind::Other);
+    }
+
+    fn paths(names: &[&str]) -> Vec<PathBuf> {
+        names.iter().map(|name| Path::new("apks").join(name)).collect()
+    }
+
+    #[test]
+    fn find_base_apk() {
+        let mut files = paths(&["base-xxhdpi.apk", "base-master.apk", "camera-master.apk"]);
+        assert_eq!(take_base(&mut files), Some(PathBuf::from("apks/base-master.apk")));
+        assert_eq!(files, paths(&["base-xxhdpi.apk", "camera-master.apk"]));
+
+        // xapk 以包名命名 base
+        let mut files = paths(&["com.example.apk", "config.arm64_v8a.apk", "config.zh.apk", "split_camera.apk"]);
+        assert_eq!(take_base(&mut files), Some(PathBuf::from("apks/com.example.apk")));
+        assert_eq!(files, paths(&["config.arm64_v8a.apk", "config.zh.apk", "split_camera.apk"]));
+
+        // 无法确定时不猜测
+        let mut files = paths(&["com.example.apk", "com.example.camera.apk", "config.zh.apk"]);
+        assert_eq!(take_base(&mut files), None);
+        assert_eq!(files.len(), 3);
+        assert_eq!(take_base(&mut paths(&["split_camera.apk", "config.zh.apk"])), None);
+    }
+
+    #[test]
+    fn merge_keeps_base_files() {
+        let dir = TempDir::new("split-apks-merge");
+        let (src, dst) = (dir.join("split/res"), dir.join("base/res"));
+        fs::create_dir_all(src.join("values-zh")).unwrap();
+        fs::create_dir_all(src.join("values")).unwrap();
+        fs::create_dir_all(dst.join("values")).unwrap();
+        fs::write(src.join("values/strings.xml"), "split").unwrap();
+        fs::write(src.join("values-zh/strings.xml"), "zh").unwrap();
+        fs::write(dst.join("values/strings.xml"), "base").unwrap();
+
+        assert_eq!(merge_dir(src.as_path(), dst.as_path()).unwrap(), 1);
+        assert_eq!(fs::read_to_string(dst.join("values/strings.xml")).unwrap(), "base");
+        assert_eq!(fs::read_to_string(dst.join("values-zh/strings.xml")).unwrap(), "zh");
+        assert_eq!(merge_dir(src.as_path(), dst.as_path()).unwrap(), 0);
+        assert_eq!(merge_dir(dir.join("split/assets").as_path(), dir.join("base/assets").as_path()).unwrap(), 0);
+        assert!(!dir.join("base/assets").exists());
+    }
+}
@@ -0,0 +1,469 @@
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+use tracing::debug;
+
+use crate::apktool::Versions;
+use crate::asset_pack::{self, PackLayout};
+use crate::cache;
+use crate::config::AssetPack;
+use crate::file_path::{BuildContext, BuildLayout, Module};
+use crate::pipeline::Stage;
+
+/// 流水线中的单个步骤, 名称与 `--from`/`--to`/`--only` 参数一致.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Step {
+    DecodeApk,
+    CompileResources,
+    LinkResources,
+    UnzipApk,
+    CopyResources,
+    ZipResources,
+    CompileAppBundle,
+    SignAppBundle,
+    BuildApks,
+    InstallApks,
+    ApktoolRmCache,
+    ApktoolBuild,
+    Zipalign,
+    Apksigner,
+    InstallApk,
+    LaunchApp,
+}
+
+impl Step {
+    /// apk 转 aab 的步骤顺序.
+    pub const AAB: [Step; 11] = [
+        Step::DecodeApk,
+        Step::CompileResources,
+        Step::LinkResources,
+        Step::UnzipApk,
+        Step::CopyResources,
+        Step::ZipResources,
+        Step::CompileAppBundle,
+        Step::SignAppBundle,
+        Step::BuildApks,
+        Step::InstallApks,
+        Step::LaunchApp,
+    ];
+
+    /// smali 工程编译 apk 的步骤顺序.
+    pub const APK: [Step; 6] = [
+        Step::ApktoolRmCache,
+        Step::ApktoolBuild,
+        Step::Zipalign,
+        Step::Apksigner,
+        Step::InstallApk,
+        Step::LaunchApp,
+    ];
+
+    const ALL: [Step; 16] = [
+        Step::DecodeApk,
+        Step::CompileResources,
+        Step::LinkResources,
+        Step::UnzipApk,
+        Step::CopyResources,
+        Step::ZipResources,
+        Step::CompileAppBundle,
+        Step::SignAppBundle,
+        Step::BuildApks,
+        Step::InstallApks,
+        Step::ApktoolRmCache,
+        Step::ApktoolBuild,
+        Step::Zipalign,
+        Step::Apksigner,
+        Step::InstallApk,
+        Step::LaunchApp,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Step::DecodeApk => "decode_apk",
+            Step::CompileResources => "compile_resources",
+            Step::LinkResources => "link_resources",
+            Step::UnzipApk => "unzip_apk",
+            Step::CopyResources => "copy_resources",
+            Step::ZipResources => "zip_resources",
+            Step::CompileAppBundle => "compile_app_bundle",
+            Step::SignAppBundle => "sign_app_bundle",
+            Step::BuildApks => "build_apks",
+            Step::InstallApks => "install_apks",
+            Step::ApktoolRmCache => "apktool_rm_cache",
+            Step::ApktoolBuild => "apktool_build",
+            Step::Zipalign => "zipalign",
+            Step::Apksigner => "apksigner",
+            Step::InstallApk => "install_apk",
+            Step::LaunchApp => "launch_app",
+        }
+    }
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Step {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let name = normalize(s);
+        Step::ALL.iter()
+            .find(|step| step.name() == name)
+            .copied()
+            .ok_or_else(|| {
+                let names: Vec<&str> = Step::ALL.iter().map(|step| step.name()).collect();
+                anyhow!("unknown step `{}`, expected one of: {}", s, names.join(", "))
+            })
+    }
+}
+
+/// 命令行中的阶段名: 去掉两侧空白, `-` 视为 `_`, 如 `install-apks` 即 `install_apks`.
+fn normalize(name: &str) -> String {
+    name.trim().replace('-', "_")
+}
+
+/// 选择要执行的阶段区间, 默认执行全部.
+#[derive(Debug, Clone, Default)]
+pub struct StepRange {
+    from: Option<String>,
+    to: Option<String>,
+    only: Option<String>,
+}
+
+impl StepRange {
+    pub fn all() -> Self {
+        StepRange::default()
+    }
+
+    pub fn new(from: Option<String>, to: Option<String>, only: Option<String>) -> Self {
+        StepRange { from, to, only }
+    }
+
+    pub fn only(name: &str) -> Self {
+        StepRange { only: Some(name.to_string()), ..StepRange::default() }
+    }
+
+    /// 用户是否通过 `--only` 显式指定了该阶段.
+    pub(crate) fn is_only(&self, name: &str) -> bool {
+        self.only.as_deref().is_some_and(|only| normalize(only) == name)
+    }
+
+    /// 返回区间内阶段在 `names` 中的下标.
+    pub(crate) fn select(&self, names: &[String]) -> Result<Vec<usize>> {
+        let position = |name: &str| {
+            let name = normalize(name);
+            names.iter()
+                .position(|n| *n == name)
+                .ok_or_else(|| anyhow!("stage `{}` is not part of this pipeline, expected one of: {}", name, names.join(", ")))
+        };
+
+        if let Some(only) = &self.only {
+            if self.from.is_some() || self.to.is_some() {
+                return Err(anyhow!("--only can not be combined with --from or --to"));
+            }
+            return Ok(vec![position(only)?]);
+        }
+
+        if names.is_empty() && self.from.is_none() && self.to.is_none() {
+            return Ok(Vec::new());
+        }
+        let start = match &self.from {
+            Some(from) => position(from)?,
+            None => 0,
+        };
+        let end = match &self.to {
+            Some(to) => position(to)?,
+            None => names.len().saturating_sub(1),
+        };
+        if start > end {
+            return Err(anyhow!("--from {} comes after --to {}", names[start], names[end]));
+        }
+        Ok((start..=end).collect())
+    }
+}
+
+impl Stage for Step {
+    fn name(&self) -> String {
+        Step::name(self).to_string()
+    }
+
+    fn inputs(&self, ctx: &BuildContext) -> Result<Vec<PathBuf>> {
+        let config = ctx.config;
+        let layout = &ctx.layout;
+        let inputs = match self {
+            Step::DecodeApk | Step::CompileResources | Step::LinkResources | Step::UnzipApk | Step::CopyResources | Step::ZipResources => {
+                let mut inputs: Vec<PathBuf> = ctx.modules.iter().flat_map(|module| self.module_inputs(ctx, module)).collect();
+                inputs.extend(config.asset_pack.iter().flat_map(|pack| self.asset_pack_inputs(ctx, pack)));
+                inputs
+            }
+            Step::CompileAppBundle => {
+                let mut inputs: Vec<PathBuf> = ctx.modules.iter()
+                    .flat_map(|module| [module.layout.base_zip.clone(), module.layout.root.join("apktool.yml")])
+                    .chain(config.asset_pack.iter().map(|pack| PackLayout::new(layout, pack).zip))
+                    .collect();
+                if !config.config.bundletool_config_path.is_empty() {
+                    inputs.push(PathBuf::from(config.config.bundletool_config_path.as_str()));
+                }
+                inputs
+            }
+            Step::SignAppBundle => vec![ctx.aab.clone()],
+            Step::BuildApks => {
+                let mut inputs = vec![ctx.aab.clone()];
+                inputs.extend(config.build_apks.device_spec.iter().map(PathBuf::from));
+                inputs
+            }
+            Step::InstallApks => vec![layout.apks.clone()],
+            Step::ApktoolRmCache | Step::LaunchApp => vec![],
+            Step::ApktoolBuild => apktool_project_inputs(layout)?,
+            Step::Zipalign => vec![ctx.apk_un_sign.clone()],
+            Step::Apksigner => vec![ctx.apk_zipalign.clone()],
+            Step::InstallApk => vec![ctx.apk_sign.clone()],
+        };
+        Ok(inputs)
+    }
+
+    fn outputs(&self, ctx: &BuildContext) -> Result<Vec<PathBuf>> {
+        let layout = &ctx.layout;
+        let outputs = match self {
+            Step::DecodeApk | Step::CompileResources | Step::LinkResources | Step::UnzipApk | Step::CopyResources | Step::ZipResources => {
+                let mut outputs: Vec<PathBuf> = ctx.modules.iter().flat_map(|module| self.module_outputs(module)).collect();
+                outputs.extend(ctx.config.asset_pack.iter().filter_map(|pack| {
+                    let paths = PackLayout::new(layout, pack);
+                    match self {
+                        Step::LinkResources => Some(paths.apk),
+                        Step::CopyResources => Some(paths.module),
+                        Step::ZipResources => Some(paths.zip),
+                        _ => None,
+                    }
+                }));
+                outputs
+            }
+            Step::CompileAppBundle | Step::SignAppBundle => vec![ctx.aab.clone()],
+            Step::BuildApks => vec![layout.apks.clone()],
+            Step::InstallApks | Step::InstallApk | Step::LaunchApp => vec![],
+            Step::ApktoolRmCache => vec![
+                layout.apk_build.clone(),
+                layout.apk_dist.clone(),
+            ],
+            Step::ApktoolBuild => vec![ctx.apk_un_sign.clone()],
+            Step::Zipalign => vec![ctx.apk_zipalign.clone()],
+            Step::Apksigner => vec![ctx.apk_sign.clone()],
+        };
+        Ok(outputs)
+    }
+
+    fn enabled(&self, ctx: &BuildContext) -> bool {
+        let config = ctx.config;
+        match self {
+            Step::BuildApks => config.config.install || config.build_apks.is_requested(),
+            Step::InstallApks | Step::InstallApk => config.config.install,
+            Step::LaunchApp => config.config.install && config.config.launch,
+            _ => true,
+        }
+    }
+
+    fn cache_key(&self, ctx: &BuildContext) -> Result<Option<String>> {
+        let config = ctx.config;
+        let key = match self {
+            Step::CompileResources => Some(format!("aapt2={}", cache::aapt2_version())),
+            Step::LinkResources => Some(format!("aapt2={} {} {:?} {:?}", cache::aapt2_version(), Versions::resolve(config, &ctx.layout)?.args().join(" "), config.module, config.asset_pack)),
+            Step::CopyResources => Some(format!("{:?}", config.asset_pack)),
+            Step::UnzipApk | Step::ZipResources => Some(String::new()),
+            _ => None,
+        };
+        Ok(key)
+    }
+
+    fn run(&self, ctx: &BuildContext) -> Result<()> {
+        match self {
+            Step::DecodeApk => ctx.modules.iter().try_for_each(|module| {
+                let outdir = &module.layout.root;
+                if outdir.exists() {
+                    debug!("skip decode apk, {} exists", outdir.to_string_lossy());
+                    Ok(())
+                } else {
+                    crate::decode_apk(ctx.config, module)
+                }
+            }),
+            Step::CompileResources => ctx.modules.iter().try_for_each(crate::compile_resources),
+            Step::LinkResources => {
+                ctx.modules.iter().try_for_each(|module| crate::link_resources(ctx, module))?;
+                ctx.config.asset_pack.iter().try_for_each(|pack| asset_pack::link(ctx, pack))
+            }
+            Step::UnzipApk => ctx.modules.iter().try_for_each(crate::unzip_apk),
+            Step::CopyResources => {
+                ctx.modules.iter().try_for_each(|module| crate::copy_resources(ctx, module))?;
+                ctx.config.asset_pack.iter().try_for_each(|pack| asset_pack::copy(ctx, pack))
+            }
+            Step::ZipResources => {
+                ctx.modules.iter().try_for_each(crate::zip_resources)?;
+                ctx.config.asset_pack.iter().try_for_each(|pack| asset_pack::zip(ctx, pack))
+            }
+            Step::CompileAppBundle => crate::compile_app_bundle(ctx),
+            Step::SignAppBundle => crate::sign_app_bundle(ctx).map(|_| ()),
+            Step::BuildApks => crate::build_apks(ctx),
+            Step::InstallApks => crate::install_apks(ctx),
+            Step::ApktoolRmCache => crate::apktool_rm_cache(ctx),
+            Step::ApktoolBuild => crate::apktool_build(ctx),
+            Step::Zipalign => crate::zipalign(ctx),
+            Step::Apksigner => crate::apksigner(ctx).map(|_| ()),
+            Step::InstallApk => crate::install_apk(ctx),
+            Step::LaunchApp => crate::launch_app(ctx.config),
+        }
+    }
+}
+
+impl Step {
+    /// 对每个模块分别执行的步骤的输入.
+    fn module_inputs(&self, ctx: &BuildContext, module: &Module) -> Vec<PathBuf> {
+        let config = ctx.config;
+        let layout = &module.layout;
+        let root = &layout.root;
+        match self {
+            Step::DecodeApk => vec![PathBuf::from(module.apk_path(config))],
+            Step::CompileResources => vec![layout.res.clone()],
+            Step::LinkResources => {
+                let mut inputs = vec![
+                    layout.flat_dir.clone(),
+                    root.join("AndroidManifest.xml"),
+                    root.join("apktool.yml"),
+                    PathBuf::from(config.jar.android_jar_path.as_str()),
+                ];
+                if module.feature.is_some() {
+                    // 版本信息取自 base, 并链接 base 的资源
+                    inputs.extend([ctx.layout.root.join("apktool.yml"), ctx.original_base_apk()]);
+                }
+                inputs
+            }
+            Step::UnzipApk => vec![layout.base_apk.clone()],
+            Step::CopyResources => vec![
+                layout.base_dir.join("AndroidManifest.xml"),
+                layout.assets.clone(),
+                layout.lib.clone(),
+                layout.unknown.clone(),
+                layout.kotlin.clone(),
+                layout.meta.clone(),
+                root.join("classes.dex"),
+                root.join("apktool.yml"),
+            ],
+            Step::ZipResources => vec![layout.base_dir.clone(), root.join("apktool.yml")],
+            _ => vec![],
+        }
+    }
+
+    /// 资源包的输入, 生成的 manifest 依赖 base 的 `package`.
+    fn asset_pack_inputs(&self, ctx: &BuildContext, pack: &AssetPack) -> Vec<PathBuf> {
+        let layout = &ctx.layout;
+        match self {
+            Step::LinkResources => vec![layout.root.join("AndroidManifest.xml")],
+            Step::CopyResources => {
+                let mut inputs = vec![PackLayout::new(layout, pack).apk];
+                inputs.extend(pack.directories.iter().map(|directory| layout.assets.join(directory)));
+                inputs
+            }
+            Step::ZipResources => vec![PackLayout::new(layout, pack).module],
+            _ => vec![],
+        }
+    }
+
+    fn module_outputs(&self, module: &Module) -> Vec<PathBuf> {
+        let layout = &module.layout;
+        match self {
+            Step::DecodeApk => vec![layout.root.clone()],
+            Step::CompileResources => vec![layout.flat_dir.clone()],
+            Step::LinkResources => vec![layout.base_apk.clone()],
+            Step::UnzipApk => vec![layout.base_dir.clone()],
+            Step::CopyResources => vec![
+                layout.manifest.clone(),
+                layout.new_assets.clone(),
+                layout.new_lib.clone(),
+                layout.base_root.clone(),
+                layout.dex.clone(),
+            ],
+            Step::ZipResources => vec![layout.base_zip.clone()],
+            _ => vec![],
+        }
+    }
+}
+
+/// apktool 工程目录下除 `build`, `dist` 和缓存目录之外的内容.
+fn apktool_project_inputs(layout: &BuildLayout) -> Result<Vec<PathBuf>> {
+    let app_path = &layout.app;
+    if !app_path.is_dir() {
+        return Ok(vec![app_path.clone()]);
+    }
+    let excludes = [&layout.apk_build, &layout.apk_dist, &layout.cache_dir];
+    let mut inputs = Vec::new();
+    for entry in fs::read_dir(app_path)? {
+        let path = entry?.path();
+        if !excludes.iter().any(|exclude| path == **exclude) {
+            inputs.push(path);
+        }
+    }
+    inputs.sort();
+    Ok(inputs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names() -> Vec<String> {
+        Step::AAB.iter().map(|step| step.name().to_string()).collect()
+    }
+
+    fn select(from: Option<&str>, to: Option<&str>, only: Option<&str>) -> Result<Vec<usize>> {
+        StepRange::new(from.map(String::from), to.map(String::from), only.map(String::from)).select(&names())
+    }
+
+    #[test]
+    fn select_all_by_default() {
+        assert_eq!(StepRange::all().select(&names()).unwrap(), (0..Step::AAB.len()).collect::<Vec<_>>());
+        assert!(StepRange::all().select(&[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn select_from_to() {
+        assert_eq!(select(Some("link_resources"), Some("zip-resources"), None).unwrap(), vec![2, 3, 4, 5]);
+        assert_eq!(select(Some("sign_app_bundle"), None, None).unwrap(), vec![7, 8, 9, 10]);
+        assert_eq!(select(None, Some("decode_apk"), None).unwrap(), vec![0]);
+        assert_eq!(select(Some("unzip_apk"), Some("unzip_apk"), None).unwrap(), vec![3]);
+    }
+
+    #[test]
+    fn reject_from_after_to() {
+        let e = select(Some("zip_resources"), Some("link_resources"), None).unwrap_err();
+        assert_eq!(e.to_string(), "--from zip_resources comes after --to link_resources");
+    }
+
+    #[test]
+    fn select_only() {
+        assert_eq!(select(None, None, Some(" build-apks")).unwrap(), vec![8]);
+        assert!(StepRange::only("install_apks").is_only("install_apks"));
+        assert!(!StepRange::only("install_apks").is_only("build_apks"));
+        assert!(select(Some("decode_apk"), None, Some("build_apks")).is_err());
+        assert!(select(None, Some("decode_apk"), Some("build_apks")).is_err());
+    }
+
+    #[test]
+    fn reject_stage_outside_pipeline() {
+        let e = select(None, None, Some("zipalign")).unwrap_err();
+        assert!(e.to_string().starts_with("stage `zipalign` is not part of this pipeline"), "{}", e);
+        assert!(select(Some("unknown"), None, None).is_err());
+    }
+
+    #[test]
+    fn parse_step_names() {
+        assert_eq!("compile-app-bundle".parse::<Step>().unwrap(), Step::CompileAppBundle);
+        assert_eq!(" apksigner ".parse::<Step>().unwrap(), Step::Apksigner);
+        assert!("sign".parse::<Step>().is_err());
+        for step in Step::ALL {
+            assert_eq!(step.name().parse::<Step>().unwrap(), step);
+        }
+    }
+}
@@ -0,0 +1,56 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::config::{Apk, BuildApk, Config, ConfigInfo, Jar, Secret, Sign};
+
+/// 测试用的 `[sign]`, keystore 为相对路径 `abtool.jks`.
+pub(crate) fn sign(keystore_pass: &str, keystore_key_pass: &str) -> Sign {
+    Sign::new("abtool.jks", Secret::new(keystore_pass), "abtool", Secret::new(keystore_key_pass))
+}
+
+/// 测试用的最小配置, `apk_outdir` 为 `out`, 其余字段按需修改.
+pub(crate) fn config() -> Config {
+    Config::new(
+        sign("android", "android"),
+        Apk::new("app.apk", "out", "demo"),
+        Jar::new("apktool.jar", "bundletool.jar", "android.jar"),
+        ConfigInfo::new("com.example.MainActivity"),
+        BuildApk::new("app"),
+    )
+}
+
+/// 以 `out` 为 `apk_outdir` 的 [`config`].
+pub(crate) fn config_in(out: &Path) -> Config {
+    let mut config = config();
+    config.apk.apk_outdir = out.to_string_lossy().to_string();
+    config
+}
+
+/// 测试用的临时目录, 创建时清空, drop 时删除, 断言失败也不会留下文件.
+pub(crate) struct TempDir(PathBuf);
+
+impl TempDir {
+    /// `name` 在同一进程的测试之间需唯一.
+    pub(crate) fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("abtool-{}-{}", name, std::process::id()));
+        if dir.exists() {
+            fs::remove_dir_all(dir.as_path()).unwrap();
+        }
+        fs::create_dir_all(dir.as_path()).unwrap();
+        TempDir(dir)
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        self.0.as_path()
+    }
+
+    pub(crate) fn join(&self, path: impl AsRef<Path>) -> PathBuf {
+        self.0.join(path)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(self.0.as_path());
+    }
+}
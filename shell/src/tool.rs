@@ -0,0 +1,88 @@
+use std::collections::VecDeque;
+use std::io::{self, BufRead, BufReader, Write};
+use std::process::{Command, Stdio};
+
+use crate::error::Error;
+
+/// 错误信息中保留的 stderr 行数.
+const STDERR_TAIL: usize = 20;
+
+/// 执行外部工具, stdout 直接输出; stderr 同样输出到终端, 并保留最后几行用于错误信息.
+pub(crate) fn exec(stage: &str, command: &mut Command) -> Result<(), Error> {
+    let program = command.get_program().to_string_lossy().to_string();
+    let mut child = command
+        .stdout(Stdio::inherit())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound => Error::ToolNotFound { program: program.clone() },
+            _ => Error::Io(e),
+        })?;
+
+    let mut tail = VecDeque::with_capacity(STDERR_TAIL);
+    if let Some(stderr) = child.stderr.take() {
+        let mut reader = BufReader::new(stderr);
+        let mut line = Vec::new();
+        while reader.read_until(b'\n', &mut line)? > 0 {
+            io::stderr().write_all(&line)?;
+            if tail.len() == STDERR_TAIL {
+                tail.pop_front();
+            }
+            tail.push_back(String::from_utf8_lossy(&line).trim_end().to_string());
+            line.clear();
+        }
+    }
+
+    let status = child.wait()?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(Error::ToolFailed {
+            stage: stage.to_string(),
+            program,
+            args: command.get_args().map(|arg| arg.to_string_lossy().to_string()).collect(),
+            status: status.code(),
+            stderr_tail: tail.into_iter().filter(|line| !line.is_empty()).collect(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_program() {
+        let e = exec("build", &mut Command::new("abtool-missing-tool")).unwrap_err();
+        assert!(matches!(&e, Error::ToolNotFound { program } if program == "abtool-missing-tool"), "{:?}", e);
+    }
+
+    #[test]
+    fn failed_program_keeps_status_and_stderr() {
+        let e = exec("link resources", Command::new("sh").args(["-c", "echo out; echo x >&2; echo >&2; exit 3"])).unwrap_err();
+        match e {
+            Error::ToolFailed { stage, program, args, status, stderr_tail } => {
+                assert_eq!(stage, "link resources");
+                assert_eq!(program, "sh");
+                assert_eq!(args, ["-c", "echo out; echo x >&2; echo >&2; exit 3"]);
+                assert_eq!(status, Some(3));
+                assert_eq!(stderr_tail, ["x"]);
+            }
+            e => panic!("unexpected error {:?}", e),
+        }
+        exec("ok", Command::new("sh").args(["-c", "exit 0"])).unwrap();
+    }
+
+    #[test]
+    fn stderr_tail_is_bounded() {
+        let e = exec("noisy", Command::new("sh").args(["-c", "for i in $(seq 1 30); do echo line$i >&2; done; exit 1"])).unwrap_err();
+        match e {
+            Error::ToolFailed { stderr_tail, .. } => {
+                assert_eq!(stderr_tail.len(), STDERR_TAIL);
+                assert_eq!(stderr_tail.first().map(String::as_str), Some("line11"));
+                assert_eq!(stderr_tail.last().map(String::as_str), Some("line30"));
+            }
+            e => panic!("unexpected error {:?}", e),
+        }
+    }
+}
@@ -0,0 +1,409 @@
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::{anyhow, Result};
+use tracing::{debug, info, warn};
+use zip::write::FileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+
+use crate::config::Config;
+use crate::manifest::DIST_NAMESPACE;
+use crate::protobuf;
+use crate::{tool, zipalign};
+
+/// aab 顶层不属于模块的条目.
+const BUNDLE_ENTRIES: [&str; 3] = ["BundleConfig.pb", "BUNDLE-METADATA", "META-INF"];
+
+/// 转换过程中的中间文件, 位于输出 apk 旁的 `<name>.aab2apk/` 下, drop 时删除.
+struct Paths {
+    dir: PathBuf,
+    /// base 模块的 manifest, `resources.pb` 和 `res/`, 即 proto 格式的 apk.
+    proto: PathBuf,
+    /// `aapt2 convert` 输出的二进制资源.
+    binary: PathBuf,
+    unsigned: PathBuf,
+    aligned: PathBuf,
+}
+
+impl Paths {
+    fn new(output: &Path) -> Self {
+        let dir = output.with_extension("aab2apk");
+        Paths {
+            proto: dir.join("proto.apk"),
+            binary: dir.join("binary.apk"),
+            unsigned: dir.join("unsigned.apk"),
+            aligned: dir.join("aligned.apk"),
+            dir,
+        }
+    }
+}
+
+impl Drop for Paths {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_dir_all(self.dir.as_path()) {
+            if e.kind() != io::ErrorKind::NotFound {
+                warn!("remove {} failed: {}", self.dir.to_string_lossy(), e);
+            }
+        }
+    }
+}
+
+/// 模块 manifest 中 `<dist:module>` 声明的类型与分发方式.
+#[derive(Debug, Default, PartialEq)]
+struct Delivery {
+    asset_pack: bool,
+    install_time: bool,
+}
+
+/// 把 `aab` 的 base 模块和安装时分发的资源包转为 universal apk 并签名写入 `output`.
+/// 安装时分发的功能模块需要合并资源表, 只能交给 bundletool, 遇到时报错.
+pub(crate) fn convert(config: &Config, aab: &Path, output: &Path) -> Result<()> {
+    let mut archive = ZipArchive::new(File::open(aab)?)?;
+    let mut packs = Vec::new();
+    for module in modules(&archive) {
+        if module == "base" {
+            continue;
+        }
+        let delivery = delivery(&mut archive, module.as_str())?;
+        match delivery {
+            Delivery { asset_pack: true, install_time: true } => packs.push(module),
+            Delivery { asset_pack: false, install_time: true } => {
+                return Err(anyhow!("module {} is delivered at install time and can not be merged without bundletool, build the universal apk with `bundletool build-apks --mode universal` instead", module));
+            }
+            Delivery { asset_pack, install_time: false } => {
+                warn!("{} {} is not delivered at install time, it is not included", if asset_pack { "asset pack" } else { "module" }, module);
+            }
+        }
+    }
+
+    let paths = Paths::new(output);
+    fs::create_dir_all(paths.dir.as_path())?;
+    write_proto_apk(&mut archive, paths.proto.as_path())?;
+    info!("exec command: aapt2 convert --output-format binary -o {} {}", paths.binary.to_string_lossy(), paths.proto.to_string_lossy());
+    tool::exec("convert resources", Command::new("aapt2")
+        .args(["convert", "--output-format", "binary", "-o"])
+        .arg(paths.binary.as_path())
+        .arg(paths.proto.as_path()))?;
+
+    merge(&mut archive, paths.binary.as_path(), &packs, paths.unsigned.as_path())?;
+    let page_size = config.build_apk.zipalign_page_size;
+    info!("zipalign {} -> {}, page size {}KiB", paths.unsigned.to_string_lossy(), paths.aligned.to_string_lossy(), page_size);
+    zipalign::align(paths.unsigned.as_path(), paths.aligned.as_path(), page_size)?;
+    crate::apksigner_file(config, paths.aligned.as_path(), output)?;
+    Ok(())
+}
+
+/// aab 中的模块名, 即顶层目录.
+fn modules<R: Read + Seek>(archive: &ZipArchive<R>) -> Vec<String> {
+    let mut modules: Vec<String> = archive.file_names()
+        .filter_map(|name| name.split_once('/').map(|(module, _)| module.to_string()))
+        .filter(|module| !BUNDLE_ENTRIES.contains(&module.as_str()))
+        .collect();
+    modules.sort();
+    modules.dedup();
+    modules
+}
+
+/// 读取 `<module>/manifest/AndroidManifest.xml`(proto 格式的 XmlNode) 中的 `<dist:module>`.
+/// 没有 `<dist:delivery>` 时按旧写法的 `dist:onDemand` 属性判断, 缺省为安装时分发.
+fn delivery<R: Read + Seek>(archive: &mut ZipArchive<R>, module: &str) -> Result<Delivery> {
+    let name = format!("{}/manifest/AndroidManifest.xml", module);
+    let mut data = Vec::new();
+    archive.by_name(name.as_str()).map_err(|_| anyhow!("{} not found", name))?.read_to_end(&mut data)?;
+    let manifest = element(data.as_slice())?.ok_or_else(|| anyhow!("{} has no root element", name))?;
+    let dist = match manifest.children.iter().find(|child| child.is_dist("module")) {
+        Some(dist) => dist,
+        None => return Ok(Delivery { asset_pack: false, install_time: true }),
+    };
+    let asset_pack = dist.dist_attribute("type") == Some("asset-pack");
+    let install_time = match dist.children.iter().find(|child| child.is_dist("delivery")) {
+        Some(delivery) => delivery.children.iter().any(|child| child.is_dist("install-time")),
+        None => dist.dist_attribute("onDemand") != Some("true"),
+    };
+    Ok(Delivery { asset_pack, install_time })
+}
+
+/// proto XmlElement 中判断分发方式需要的部分.
+#[derive(Debug, Default)]
+struct Element {
+    namespace: String,
+    name: String,
+    /// `(namespace, name, value)`.
+    attributes: Vec<(String, String, String)>,
+    children: Vec<Element>,
+}
+
+impl Element {
+    fn is_dist(&self, name: &str) -> bool {
+        self.namespace == DIST_NAMESPACE && self.name == name
+    }
+
+    fn dist_attribute(&self, name: &str) -> Option<&str> {
+        self.attributes.iter()
+            .find(|(namespace, attribute, _)| namespace == DIST_NAMESPACE && attribute == name)
+            .map(|(_, _, value)| value.as_str())
+    }
+}
+
+/// 解码 XmlNode, 文本节点返回 None.
+fn element(node: &[u8]) -> Result<Option<Element>> {
+    for field in protobuf::fields(node) {
+        if let (1, data) = field? {
+            let mut element = Element::default();
+            for field in protobuf::fields(data.as_bytes()?) {
+                match field? {
+                    (2, namespace) => element.namespace = namespace.as_str()?,
+                    (3, name) => element.name = name.as_str()?,
+                    (4, attribute) => {
+                        let (mut namespace, mut name, mut value) = (String::new(), String::new(), String::new());
+                        for field in protobuf::fields(attribute.as_bytes()?) {
+                            match field? {
+                                (1, v) => namespace = v.as_str()?,
+                                (2, v) => name = v.as_str()?,
+                                (3, v) => value = v.as_str()?,
+                                _ => {}
+                            }
+                        }
+                        element.attributes.push((namespace, name, value));
+                    }
+                    (5, child) => element.children.extend(self::element(child.as_bytes()?)?),
+                    _ => {}
+                }
+            }
+            return Ok(Some(element));
+        }
+    }
+    Ok(None)
+}
+
+fn write_proto_apk<R: Read + Seek>(archive: &mut ZipArchive<R>, proto: &Path) -> Result<()> {
+    debug!("write proto apk {}", proto.to_string_lossy());
+    let mut writer = ZipWriter::new(File::create(proto)?);
+    let mut manifest = false;
+    for i in 0..archive.len() {
+        let file = archive.by_index(i)?;
+        let name = match file.name().strip_prefix("base/") {
+            Some(name) if !file.is_dir() => name.to_string(),
+            _ => continue,
+        };
+        let target = if name == "manifest/AndroidManifest.xml" {
+            manifest = true;
+            "AndroidManifest.xml".to_string()
+        } else if name == "resources.pb" || name.starts_with("res/") {
+            name
+        } else {
+            continue;
+        };
+        writer.raw_copy_file_rename(file, target)?;
+    }
+    writer.finish()?;
+    if !manifest {
+        return Err(anyhow!("base/manifest/AndroidManifest.xml not found, is it an app bundle?"));
+    }
+    Ok(())
+}
+
+/// apk 中的路径: `dex/` 和 `root/` 放在根目录, `lib/` 与 `assets/` 保持不变.
+/// manifest 和资源来自 `aapt2 convert` 的结果, 返回 None.
+fn apk_path(module_path: &str) -> Option<String> {
+    if let Some(dex) = module_path.strip_prefix("dex/") {
+        Some(dex.to_string())
+    } else if let Some(root) = module_path.strip_prefix("root/") {
+        Some(root.to_string())
+    } else if module_path.starts_with("lib/") || module_path.starts_with("assets/") {
+        Some(module_path.to_string())
+    } else {
+        None
+    }
+}
+
+/// 资源包只有 `assets/` 合并到 apk 中, 路径不变.
+fn pack_path(module_path: &str) -> Option<String> {
+    module_path.starts_with("assets/").then(|| module_path.to_string())
+}
+
+/// 合并二进制资源, base 模块的 dex, lib, assets, root 以及资源包 `packs` 的 assets.
+/// 路径相同时先写入的优先: 二进制资源, base, 资源包按顺序.
+/// `.so` 不压缩以便按页对齐后直接加载, 其余条目保持 aab 中的压缩方式.
+fn merge<R: Read + Seek>(archive: &mut ZipArchive<R>, binary: &Path, packs: &[String], unsigned: &Path) -> Result<()> {
+    debug!("merge {} into {}", binary.to_string_lossy(), unsigned.to_string_lossy());
+    let mut writer = ZipWriter::new(File::create(unsigned)?);
+    let mut names = HashSet::new();
+    let mut resources = ZipArchive::new(File::open(binary)?)?;
+    for i in 0..resources.len() {
+        let file = resources.by_index(i)?;
+        names.insert(file.name().to_string());
+        writer.raw_copy_file(file)?;
+    }
+
+    let modules = std::iter::once("base").chain(packs.iter().map(String::as_str));
+    for module in modules {
+        let prefix = format!("{}/", module);
+        for i in 0..archive.len() {
+            let mut file = archive.by_index(i)?;
+            let path = match file.name().strip_prefix(prefix.as_str()) {
+                Some(path) if !file.is_dir() => path,
+                _ => continue,
+            };
+            let name = if module == "base" { apk_path(path) } else { pack_path(path) };
+            let name = match name {
+                Some(name) => name,
+                None => continue,
+            };
+            if !names.insert(name.clone()) {
+                warn!("skip duplicate entry {} of {}", name, module);
+                continue;
+            }
+            if name.ends_with(".so") && file.compression() != CompressionMethod::Stored {
+                writer.start_file(name, FileOptions::default().compression_method(CompressionMethod::Stored))?;
+                io::copy(&mut file, &mut writer)?;
+            } else {
+                writer.raw_copy_file_rename(file, name)?;
+            }
+        }
+    }
+    writer.finish()?.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::protobuf::tests::message;
+    use crate::test_util::TempDir;
+
+    fn zip(entries: &[(&str, &[u8], CompressionMethod)]) -> Vec<u8> {
+        let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, data, compression) in entries {
+            writer.start_file(*name, FileOptions::default().compression_method(*compression)).unwrap();
+            writer.write_all(data).unwrap();
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    fn node(namespace: &str, name: &str, attributes: &[(&str, &str)], children: &[Vec<u8>]) -> Vec<u8> {
+        let mut element = [message(2, namespace.as_bytes()), message(3, name.as_bytes())].concat();
+        for (name, value) in attributes {
+            let attribute = [message(1, DIST_NAMESPACE.as_bytes()), message(2, name.as_bytes()), message(3, value.as_bytes())].concat();
+            element.extend(message(4, attribute.as_slice()));
+        }
+        for child in children {
+            element.extend(message(5, child.as_slice()));
+        }
+        message(1, element.as_slice())
+    }
+
+    fn manifest(attributes: &[(&str, &str)], delivery: Option<&str>) -> Vec<u8> {
+        let delivery = delivery.map(|delivery| node(DIST_NAMESPACE, "delivery", &[], &[node(DIST_NAMESPACE, delivery, &[], &[])]));
+        let module = node(DIST_NAMESPACE, "module", attributes, delivery.as_slice());
+        node("", "manifest", &[], &[node("", "application", &[], &[]), module])
+    }
+
+    #[test]
+    fn modules_of_bundle() {
+        let stored = CompressionMethod::Stored;
+        let data = zip(&[
+            ("BundleConfig.pb", b"", stored),
+            ("BUNDLE-METADATA/com.android.tools.build.obfuscation/proguard.map", b"", stored),
+            ("META-INF/MANIFEST.MF", b"", stored),
+            ("camera/dex/classes.dex", b"", stored),
+            ("base/manifest/AndroidManifest.xml", b"", stored),
+            ("base/dex/classes.dex", b"", stored),
+            ("textures/assets/a.png", b"", stored),
+        ]);
+        let archive = ZipArchive::new(Cursor::new(data)).unwrap();
+        assert_eq!(modules(&archive), ["base", "camera", "textures"]);
+    }
+
+    #[test]
+    fn apk_paths() {
+        assert_eq!(apk_path("dex/classes2.dex").as_deref(), Some("classes2.dex"));
+        assert_eq!(apk_path("root/META-INF/services/a").as_deref(), Some("META-INF/services/a"));
+        assert_eq!(apk_path("lib/arm64-v8a/libapp.so").as_deref(), Some("lib/arm64-v8a/libapp.so"));
+        assert_eq!(apk_path("assets/a.txt").as_deref(), Some("assets/a.txt"));
+        assert_eq!(apk_path("manifest/AndroidManifest.xml"), None);
+        assert_eq!(apk_path("resources.pb"), None);
+        assert_eq!(apk_path("res/layout/main.xml"), None);
+        assert_eq!(pack_path("assets/level1.bin").as_deref(), Some("assets/level1.bin"));
+        assert_eq!(pack_path("manifest/AndroidManifest.xml"), None);
+    }
+
+    #[test]
+    fn delivery_of_modules() {
+        let stored = CompressionMethod::Stored;
+        let install_time = manifest(&[], Some("install-time"));
+        let on_demand = manifest(&[("title", "@string/camera")], Some("on-demand"));
+        let legacy = manifest(&[("onDemand", "true")], None);
+        let pack = manifest(&[("type", "asset-pack")], Some("install-time"));
+        let fast_follow = manifest(&[("type", "asset-pack")], Some("fast-follow"));
+        let data = zip(&[
+            ("install/manifest/AndroidManifest.xml", install_time.as_slice(), stored),
+            ("ondemand/manifest/AndroidManifest.xml", on_demand.as_slice(), stored),
+            ("legacy/manifest/AndroidManifest.xml", legacy.as_slice(), stored),
+            ("pack/manifest/AndroidManifest.xml", pack.as_slice(), stored),
+            ("fast/manifest/AndroidManifest.xml", fast_follow.as_slice(), stored),
+            ("nodist/manifest/AndroidManifest.xml", node("", "manifest", &[], &[]).as_slice(), stored),
+        ]);
+        let mut archive = ZipArchive::new(Cursor::new(data)).unwrap();
+        let mut delivery = |module: &str| delivery(&mut archive, module).unwrap();
+        assert_eq!(delivery("install"), Delivery { asset_pack: false, install_time: true });
+        assert_eq!(delivery("ondemand"), Delivery { asset_pack: false, install_time: false });
+        assert_eq!(delivery("legacy"), Delivery { asset_pack: false, install_time: false });
+        assert_eq!(delivery("pack"), Delivery { asset_pack: true, install_time: true });
+        assert_eq!(delivery("fast"), Delivery { asset_pack: true, install_time: false });
+        assert_eq!(delivery("nodist"), Delivery { asset_pack: false, install_time: true });
+        assert!(super::delivery(&mut archive, "missing").is_err());
+    }
+
+    #[test]
+    fn merge_base_and_asset_packs() {
+        let dir = TempDir::new("universal-apk-merge");
+        let (stored, deflated) = (CompressionMethod::Stored, CompressionMethod::Deflated);
+        let binary = dir.join("binary.apk");
+        fs::write(binary.as_path(), zip(&[
+            ("AndroidManifest.xml", b"binary manifest", stored),
+            ("resources.arsc", b"arsc", stored),
+            ("res/layout/main.xml", b"layout", deflated),
+        ])).unwrap();
+        let aab = zip(&[
+            ("base/manifest/AndroidManifest.xml", b"proto manifest", deflated),
+            ("base/resources.pb", b"pb", deflated),
+            ("base/res/layout/main.xml", b"proto layout", deflated),
+            ("base/dex/classes.dex", b"dex", deflated),
+            ("base/lib/arm64-v8a/libapp.so", b"so", deflated),
+            ("base/assets/shared.txt", b"base", deflated),
+            ("base/root/resources.arsc", b"root", deflated),
+            ("pack/manifest/AndroidManifest.xml", b"pack manifest", deflated),
+            ("pack/assets/shared.txt", b"pack", deflated),
+            ("pack/assets/level1.bin", b"level1", deflated),
+            ("camera/assets/camera.txt", b"camera", deflated),
+        ]);
+        let mut archive = ZipArchive::new(Cursor::new(aab)).unwrap();
+        let unsigned = dir.join("unsigned.apk");
+        merge(&mut archive, binary.as_path(), &["pack".to_string()], unsigned.as_path()).unwrap();
+
+        let mut apk = ZipArchive::new(File::open(unsigned).unwrap()).unwrap();
+        let mut names: Vec<_> = apk.file_names().map(str::to_string).collect();
+        names.sort();
+        assert_eq!(names, [
+            "AndroidManifest.xml", "assets/level1.bin", "assets/shared.txt", "classes.dex",
+            "lib/arm64-v8a/libapp.so", "res/layout/main.xml", "resources.arsc",
+        ]);
+        let mut read = |name: &str| {
+            let mut content = String::new();
+            apk.by_name(name).unwrap().read_to_string(&mut content).unwrap();
+            content
+        };
+        assert_eq!(read("AndroidManifest.xml"), "binary manifest");
+        assert_eq!(read("resources.arsc"), "arsc");
+        assert_eq!(read("assets/shared.txt"), "base");
+        assert_eq!(read("assets/level1.bin"), "level1");
+        assert_eq!(apk.by_name("lib/arm64-v8a/libapp.so").unwrap().compression(), stored);
+        assert_eq!(apk.by_name("classes.dex").unwrap().compression(), deflated);
+    }
+}
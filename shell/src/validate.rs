@@ -0,0 +1,314 @@
+use std::path::Path;
+
+use crate::apktool::{ApktoolInfo, BASE_PACKAGE_ID};
+use crate::config::{ApksMode, Config, SplitDimension};
+use crate::error::Error;
+use crate::pipeline::Flow;
+use crate::zipalign;
+
+/// Google Play 允许的最大 versionCode.
+const MAX_VERSION_CODE: u32 = 2_100_000_000;
+
+/// 检查配置中的路径、版本号和 `main_activity`, 一次返回全部问题.
+/// `flow` 为 None 时按两个流程都会执行来检查.
+pub(crate) fn check(config: &Config, flow: Option<Flow>) -> Result<(), Error> {
+    report(problems(config, flow))
+}
+
+pub(crate) fn report(problems: Vec<String>) -> Result<(), Error> {
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::ConfigInvalid(format!("{} problem(s) found\n  - {}", problems.len(), problems.join("\n  - "))))
+    }
+}
+
+pub(crate) fn problems(config: &Config, flow: Option<Flow>) -> Vec<String> {
+    let aab = flow != Some(Flow::Apk);
+    let apk = flow != Some(Flow::Aab);
+    // 输出目录已存在时不会重新反编译
+    let decode = aab && !Path::new(config.apk.apk_outdir.as_str()).exists();
+    let mut problems = Vec::new();
+
+    if decode || apk {
+        file(&mut problems, "[jar] apktool_path", config.jar.apktool_path.as_str());
+    }
+    if aab {
+        file(&mut problems, "[jar] bundletool_path", config.jar.bundletool_path.as_str());
+        file(&mut problems, "[jar] android_jar_path", config.jar.android_jar_path.as_str());
+    }
+
+    file(&mut problems, "[sign] keystore", config.sign.keystore.as_str());
+    not_empty(&mut problems, "[sign] keystore_key_alias", config.sign.keystore_key_alias.as_str());
+    if let Some(lineage) = &config.sign.lineage {
+        file(&mut problems, "[sign] lineage", lineage.as_str());
+    }
+    if let Some(previous) = &config.sign.previous {
+        file(&mut problems, "[sign.previous] keystore", previous.keystore.as_str());
+        not_empty(&mut problems, "[sign.previous] keystore_key_alias", previous.keystore_key_alias.as_str());
+    }
+
+    // 目录为 split apk 集合
+    if decode && !Path::new(config.apk.apk_path.as_str()).is_dir() {
+        file(&mut problems, "[apk] apk_path", config.apk.apk_path.as_str());
+    }
+    not_empty(&mut problems, "[apk] apk_outdir", config.apk.apk_outdir.as_str());
+    not_empty(&mut problems, "[apk] app_name", config.apk.app_name.as_str());
+    if aab {
+        let versions = versions(config.apk.min_sdk_version, config.apk.target_sdk_version, config.apk.version_code);
+        problems.extend(versions.into_iter().map(|problem| format!("[apk] {}", problem)));
+        // 已反编译时, apktool.yml 中无法解析的版本号需要在配置中指定
+        if !decode {
+            match ApktoolInfo::read(Path::new(config.apk.apk_outdir.as_str())) {
+                Ok(info) => problems.extend(info.version_problems(config).into_iter().map(|problem| format!("[apk] {}", problem))),
+                Err(e) => problems.push(format!("[apk] apk_outdir: {:#}", e)),
+            }
+        }
+        if let Some(version_name) = &config.apk.version_name {
+            not_empty(&mut problems, "[apk] version_name", version_name.as_str());
+        }
+    }
+
+    if apk {
+        directory(&mut problems, "[build_apk] app_path", config.build_apk.app_path.as_str());
+        if let Err(e) = zipalign::page_bytes(config.build_apk.zipalign_page_size) {
+            problems.push(format!("[build_apk] zipalign_page_size: {}", e));
+        }
+    }
+
+    if aab && !config.config.bundletool_config_path.is_empty() {
+        file(&mut problems, "[config] bundletool_config_path", config.config.bundletool_config_path.as_str());
+    }
+    if aab {
+        let options = &config.build_apks;
+        if let Some(device_spec) = &options.device_spec {
+            file(&mut problems, "[build_apks] device_spec", device_spec.as_str());
+            if options.connected_device {
+                problems.push("[build_apks] device_spec and connected_device can not be used together".to_string());
+            }
+        }
+        if options.mode == ApksMode::Universal && (options.connected_device || options.device_spec.is_some()) {
+            problems.push("[build_apks] universal mode can not target a device".to_string());
+        }
+        // 已反编译的 feature 模块中原 apk 指定的 package id
+        let forced: Vec<Option<u32>> = config.module.iter()
+            .map(|module| ApktoolInfo::read(Path::new(module.outdir(config).as_str())).ok().and_then(|info| info.package_id))
+            .collect();
+        for (i, module) in config.module.iter().enumerate() {
+            let name = module.name.as_str();
+            if let Some(id) = forced[i] {
+                if id == BASE_PACKAGE_ID {
+                    problems.push(format!("[[module]] {} forcedPackageId 0x{:02x} is the package id of base", name, id));
+                } else if let Some(other) = forced[..i].iter().position(|other| *other == Some(id)) {
+                    problems.push(format!("[[module]] {} forcedPackageId 0x{:02x} is also used by {}", name, id, config.module[other].name));
+                }
+            }
+            if !is_module_name(name) {
+                problems.push(format!("[[module]] name `{}` must start with a letter and contain only letters, digits and `_`", name));
+            } else if name == "base" {
+                problems.push("[[module]] name `base` is reserved for the base module".to_string());
+            } else if config.module[..i].iter().any(|other| other.name == name) {
+                problems.push(format!("[[module]] name `{}` is used more than once", name));
+            }
+            if !Path::new(module.outdir(config).as_str()).exists() {
+                file(&mut problems, format!("[[module]] {} apk_path", name).as_str(), module.apk_path.as_str());
+            }
+        }
+        for (i, pack) in config.asset_pack.iter().enumerate() {
+            let name = pack.name.as_str();
+            if !is_module_name(name) {
+                problems.push(format!("[[asset_pack]] name `{}` must start with a letter and contain only letters, digits and `_`", name));
+            } else if name == "base" || config.module.iter().any(|module| module.name == name) || config.asset_pack[..i].iter().any(|other| other.name == name) {
+                problems.push(format!("[[asset_pack]] name `{}` is already used by another module", name));
+            }
+            if pack.directories.is_empty() {
+                problems.push(format!("[[asset_pack]] {} has no directories", name));
+            }
+            for directory in &pack.directories {
+                let path = Path::new(directory);
+                if directory.trim().is_empty() || path.is_absolute() || path.components().any(|c| !matches!(c, std::path::Component::Normal(_))) {
+                    problems.push(format!("[[asset_pack]] {} directory `{}` must be a relative path under assets/", name, directory));
+                } else if config.asset_pack[..i].iter().flat_map(|other| &other.directories).any(|other| overlaps(other, directory)) {
+                    problems.push(format!("[[asset_pack]] {} directory `{}` is already part of another asset pack", name, directory));
+                } else if !decode && !Path::new(config.apk.apk_outdir.as_str()).join("assets").join(directory).is_dir() {
+                    problems.push(format!("[[asset_pack]] {} directory assets/{} does not exist", name, directory));
+                }
+            }
+        }
+        for (i, split) in config.bundle.split.iter().enumerate() {
+            let name = split.dimension.name();
+            if config.bundle.split[..i].iter().any(|other| other.dimension == split.dimension) {
+                problems.push(format!("[[bundle.split]] dimension {} is listed more than once", name));
+            }
+            if split.suffix_stripping && !matches!(split.dimension, SplitDimension::Texture | SplitDimension::DeviceTier) {
+                problems.push(format!("[[bundle.split]] suffix_stripping is only supported for texture and device_tier, not {}", name));
+            }
+        }
+    }
+    if config.config.launch || !config.config.main_activity.is_empty() {
+        if let Err(e) = component(config.config.main_activity.as_str()) {
+            problems.push(format!("[config] main_activity `{}`: {}", config.config.main_activity, e));
+        }
+    }
+    problems
+}
+
+fn not_empty(problems: &mut Vec<String>, name: &str, value: &str) -> bool {
+    if value.trim().is_empty() {
+        problems.push(format!("{} is empty", name));
+        false
+    } else {
+        true
+    }
+}
+
+fn file(problems: &mut Vec<String>, name: &str, path: &str) {
+    if not_empty(problems, name, path) && !Path::new(path).is_file() {
+        problems.push(format!("{} `{}` does not exist or is not a file", name, path));
+    }
+}
+
+fn directory(problems: &mut Vec<String>, name: &str, path: &str) {
+    if not_empty(problems, name, path) && !Path::new(path).is_dir() {
+        problems.push(format!("{} `{}` does not exist or is not a directory", name, path));
+    }
+}
+
+/// 已设置的 SDK 版本和 versionCode 是否在 Android 允许的范围内.
+pub(crate) fn versions(min_sdk: Option<u32>, target_sdk: Option<u32>, version_code: Option<u32>) -> Vec<String> {
+    let mut problems = Vec::new();
+    if let (Some(min_sdk), Some(target_sdk)) = (min_sdk, target_sdk) {
+        if min_sdk > target_sdk {
+            problems.push(format!("min_sdk_version {} is greater than target_sdk_version {}", min_sdk, target_sdk));
+        }
+    }
+    match version_code {
+        Some(0) => problems.push("version_code must be greater than 0".to_string()),
+        Some(code) if code > MAX_VERSION_CODE => problems.push(format!("version_code {} is greater than {}", code, MAX_VERSION_CODE)),
+        _ => {}
+    }
+    problems
+}
+
+/// `adb shell am start -n` 接受的组件名: `包名/Activity`, Activity 可以用 `.` 开头的相对类名.
+fn component(value: &str) -> Result<(), String> {
+    let (package, activity) = value.split_once('/').ok_or("expected `package/Activity`")?;
+    if !is_qualified_name(package) {
+        return Err(format!("invalid package name `{}`", package));
+    }
+    if !is_qualified_name(activity.strip_prefix('.').unwrap_or(activity)) {
+        return Err(format!("invalid activity name `{}`", activity));
+    }
+    Ok(())
+}
+
+/// 两个 `assets/` 下的目录是否相同或互相包含.
+fn overlaps(a: &str, b: &str) -> bool {
+    let (a, b) = (Path::new(a), Path::new(b));
+    a.starts_with(b) || b.starts_with(a)
+}
+
+/// bundletool 接受的模块名, 同时用作 zip 文件名.
+fn is_module_name(name: &str) -> bool {
+    name.chars().next().is_some_and(|c| c.is_ascii_alphabetic()) && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn is_qualified_name(name: &str) -> bool {
+    !name.is_empty() && name.split('.').all(|part| {
+        let mut chars = part.chars();
+        chars.next().is_some_and(|c| c.is_alphabetic() || c == '_' || c == '$')
+            && chars.all(|c| c.is_alphanumeric() || c == '_' || c == '$')
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Apk, AssetPack, FeatureModule};
+    use crate::test_util;
+
+    #[test]
+    fn component_names() {
+        assert_eq!(component("com.example/.MainActivity"), Ok(()));
+        assert_eq!(component("com.example/com.example.ui.Main$Inner"), Ok(()));
+        assert_eq!(component("com.example.MainActivity"), Err("expected `package/Activity`".to_string()));
+        assert_eq!(component("com..example/.Main"), Err("invalid package name `com..example`".to_string()));
+        assert_eq!(component("1com.example/.Main"), Err("invalid package name `1com.example`".to_string()));
+        assert_eq!(component("com.example/.Main-Activity"), Err("invalid activity name `.Main-Activity`".to_string()));
+        assert_eq!(component("com.example/"), Err("invalid activity name ``".to_string()));
+    }
+
+    #[test]
+    fn version_ranges() {
+        assert!(versions(Some(21), Some(34), Some(1)).is_empty());
+        assert!(versions(None, None, None).is_empty());
+        assert!(versions(Some(21), Some(21), Some(MAX_VERSION_CODE)).is_empty());
+        assert_eq!(versions(Some(34), Some(21), None), ["min_sdk_version 34 is greater than target_sdk_version 21"]);
+        assert_eq!(versions(Some(34), None, Some(0)), ["version_code must be greater than 0"]);
+        assert_eq!(versions(None, None, Some(2_100_000_001)), ["version_code 2100000001 is greater than 2100000000"]);
+    }
+
+    #[test]
+    fn module_names() {
+        assert!(is_module_name("camera"));
+        assert!(is_module_name("feature_2"));
+        assert!(!is_module_name("2d"));
+        assert!(!is_module_name("_camera"));
+        assert!(!is_module_name("camera-v2"));
+        assert!(!is_module_name(""));
+    }
+
+    #[test]
+    fn overlapping_directories() {
+        assert!(overlaps("textures", "textures"));
+        assert!(overlaps("textures", "textures/hd"));
+        assert!(overlaps("textures/hd/", "textures"));
+        assert!(!overlaps("textures", "textures_hd"));
+        assert!(!overlaps("audio", "textures"));
+    }
+
+    #[test]
+    fn report_every_problem_at_once() {
+        let mut config = test_util::config()
+            .with_module(FeatureModule::new("camera", "camera.apk"))
+            .with_module(FeatureModule::new("camera", "camera.apk"))
+            .with_module(FeatureModule::new("base", "base.apk"))
+            .with_module(FeatureModule::new("2d", "2d.apk"))
+            .with_asset_pack(AssetPack::new("camera", vec!["textures".to_string()]))
+            .with_asset_pack(AssetPack::new("hd", vec!["textures/hd".to_string(), "../outside".to_string()]))
+            .with_asset_pack(AssetPack::new("empty", Vec::new()));
+        config.apk = Apk::new("app.apk", "out", "").with_sdk_versions(34, 21).with_version(0, "");
+        config.config.main_activity = "MainActivity".to_string();
+
+        let problems = problems(&config, Some(Flow::Aab));
+        let expected = [
+            "[jar] apktool_path `apktool.jar` does not exist or is not a file",
+            "[jar] bundletool_path `bundletool.jar` does not exist or is not a file",
+            "[jar] android_jar_path `android.jar` does not exist or is not a file",
+            "[sign] keystore `abtool.jks` does not exist or is not a file",
+            "[apk] apk_path `app.apk` does not exist or is not a file",
+            "[apk] app_name is empty",
+            "[apk] min_sdk_version 34 is greater than target_sdk_version 21",
+            "[apk] version_code must be greater than 0",
+            "[apk] version_name is empty",
+            "[[module]] camera apk_path `camera.apk` does not exist or is not a file",
+            "[[module]] name `camera` is used more than once",
+            "[[module]] camera apk_path `camera.apk` does not exist or is not a file",
+            "[[module]] name `base` is reserved for the base module",
+            "[[module]] base apk_path `base.apk` does not exist or is not a file",
+            "[[module]] name `2d` must start with a letter and contain only letters, digits and `_`",
+            "[[module]] 2d apk_path `2d.apk` does not exist or is not a file",
+            "[[asset_pack]] name `camera` is already used by another module",
+            "[[asset_pack]] hd directory `textures/hd` is already part of another asset pack",
+            "[[asset_pack]] hd directory `../outside` must be a relative path under assets/",
+            "[[asset_pack]] empty has no directories",
+            "[config] main_activity `MainActivity`: expected `package/Activity`",
+        ];
+        assert_eq!(problems, expected);
+
+        // apk 流程不检查 aab 相关的配置
+        let apk_problems = super::problems(&config, Some(Flow::Apk));
+        assert!(apk_problems.iter().all(|problem| !problem.starts_with("[[")), "{:?}", apk_problems);
+        assert!(apk_problems.contains(&"[build_apk] app_path `app` does not exist or is not a directory".to_string()));
+    }
+}
@@ -0,0 +1,138 @@
+use std::fs::File;
+use std::io::{self, Read, Seek, Write};
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+use tracing::{debug, info};
+use zip::write::FileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+
+/// 未压缩条目的数据起始位置按 4 字节对齐, 与 `zipalign 4` 一致.
+const ALIGNMENT: u64 = 4;
+
+/// 支持的 `.so` 页大小(KiB). zip 的对齐参数是 u16, 无法表示 64 KiB.
+const PAGE_SIZES: [u32; 2] = [4, 16];
+
+/// 对齐 `input` 写入 `output`: 未压缩条目按 4 字节对齐, 未压缩的 `.so` 按页大小对齐,
+/// 压缩条目原样复制. 相当于 `zipalign -p 4` (16 KiB 页时为 `-P 16`).
+pub(crate) fn align(input: &Path, output: &Path, page_size: u32) -> Result<()> {
+    let page = page_bytes(page_size)?;
+    let mut archive = ZipArchive::new(File::open(input)?)?;
+    let mut writer = ZipWriter::new(File::create(output)?);
+    for i in 0..archive.len() {
+        copy_aligned(&mut archive, &mut writer, i, page)?;
+    }
+    writer.finish()?;
+    Ok(())
+}
+
+/// 复制第 `index` 个条目, 未压缩条目重新写入并对齐, 压缩条目原样复制.
+pub(crate) fn copy_aligned<R, W>(archive: &mut ZipArchive<R>, writer: &mut ZipWriter<W>, index: usize, page: u64) -> Result<()>
+where
+    R: Read + Seek,
+    W: Write + Seek,
+{
+    let mut file = archive.by_index(index)?;
+    if file.compression() != CompressionMethod::Stored || file.is_dir() {
+        writer.raw_copy_file(file)?;
+        return Ok(());
+    }
+
+    let alignment = entry_alignment(file.name(), page);
+    let mut options = FileOptions::default()
+        .compression_method(CompressionMethod::Stored)
+        .last_modified_time(file.last_modified())
+        .large_file(file.size() > u32::MAX as u64);
+    if let Some(mode) = file.unix_mode() {
+        options = options.unix_permissions(mode);
+    }
+    let name = file.name().to_string();
+    let alignment = u16::try_from(alignment).map_err(|_| anyhow!("alignment {} of {} is too large", alignment, name))?;
+    let padding = writer.start_file_aligned(name.as_str(), options, alignment)?;
+    debug!("align {} to {} bytes, padding {}", name, alignment, padding);
+    io::copy(&mut file, writer)?;
+    Ok(())
+}
+
+/// 检查 `input` 是否已对齐, 相当于 `zipalign -c -p 4`. 返回未对齐的条目名称.
+pub(crate) fn check(input: &Path, page_size: u32) -> Result<Vec<String>> {
+    let page = page_bytes(page_size)?;
+    let mut archive = ZipArchive::new(File::open(input)?)?;
+    let mut misaligned = Vec::new();
+    for i in 0..archive.len() {
+        let file = archive.by_index_raw(i)?;
+        if file.compression() != CompressionMethod::Stored || file.is_dir() {
+            continue;
+        }
+        let alignment = entry_alignment(file.name(), page);
+        if file.data_start() % alignment != 0 {
+            debug!("{} at {} is not aligned to {} bytes", file.name(), file.data_start(), alignment);
+            misaligned.push(file.name().to_string());
+        }
+    }
+    info!("checked {} entries, {} misaligned", archive.len(), misaligned.len());
+    Ok(misaligned)
+}
+
+fn entry_alignment(name: &str, page: u64) -> u64 {
+    if name.ends_with(".so") {
+        page
+    } else {
+        ALIGNMENT
+    }
+}
+
+pub(crate) fn page_bytes(page_size: u32) -> Result<u64> {
+    if PAGE_SIZES.contains(&page_size) {
+        Ok(page_size as u64 * 1024)
+    } else {
+        Err(anyhow!("unsupported page size {}KiB, expected 4 or 16", page_size))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    /// 写入未对齐的测试 apk: 存储的 `.so` 和资源前各有一个压缩条目, 使数据起始位置错开.
+    fn write_unaligned(path: &Path) {
+        let mut writer = ZipWriter::new(File::create(path).unwrap());
+        let deflated = FileOptions::default().compression_method(CompressionMethod::Deflated);
+        let stored = FileOptions::default().compression_method(CompressionMethod::Stored);
+        writer.start_file("classes.dex", deflated).unwrap();
+        writer.write_all(b"dex\n035").unwrap();
+        writer.start_file("res/raw/a.bin", stored).unwrap();
+        writer.write_all(&[1; 7]).unwrap();
+        writer.start_file("lib/arm64-v8a/libdemo.so", stored).unwrap();
+        writer.write_all(&[0x7f; 4099]).unwrap();
+        writer.finish().unwrap();
+    }
+
+    #[test]
+    fn align_stored_so_for_each_page_size() {
+        let dir = TempDir::new("zipalign");
+        let input = dir.join("unaligned.apk");
+        write_unaligned(input.as_path());
+
+        for page_size in PAGE_SIZES {
+            let output = dir.join(format!("aligned-{}.apk", page_size));
+            align(input.as_path(), output.as_path(), page_size).unwrap();
+            assert!(check(output.as_path(), page_size).unwrap().is_empty(), "page size {}", page_size);
+
+            let mut archive = ZipArchive::new(File::open(output.as_path()).unwrap()).unwrap();
+            let so = archive.by_name("lib/arm64-v8a/libdemo.so").unwrap();
+            assert_eq!(so.data_start() % (page_size as u64 * 1024), 0);
+            assert_eq!(so.size(), 4099);
+        }
+        let misaligned = check(input.as_path(), 16).unwrap();
+        assert!(misaligned.contains(&"lib/arm64-v8a/libdemo.so".to_string()));
+    }
+
+    #[test]
+    fn reject_unsupported_page_size() {
+        assert_eq!(page_bytes(16).unwrap(), 16384);
+        assert!(page_bytes(64).is_err());
+        assert!(page_bytes(8).is_err());
+    }
+}